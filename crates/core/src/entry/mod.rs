@@ -1,23 +1,28 @@
 //! Defines image entries.
 
 use std::borrow::Cow;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::path::PathBuf;
+use std::sync::Arc;
 
-use opencv::core::Mat;
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, Size};
 
-use crate::{util, Result};
+use crate::util::WorkDir;
+use crate::{util, warp, Result};
 
 mod image;
 mod path;
+mod warped;
 pub use image::Image;
 pub use path::Path;
+pub use warped::Warped;
 
 /// An entry represents an image.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Entry {
     Path(Path),
     Image(Image),
+    Warped(Warped),
 }
 
 // Constructors
@@ -37,6 +42,21 @@ impl Entry {
         Ok(Self::Path(Path::new_owned(path)?))
     }
 
+    /// Create a new path-based entry from a file inside a working directory.
+    ///
+    /// The working directory is kept alive for as long as the entry is, and the image is read at
+    /// its own depth.
+    #[inline]
+    pub fn new_path_in_work_dir(path: PathBuf, work_dir: Arc<WorkDir>) -> Result<Self> {
+        Ok(Self::Path(Path::new_in_work_dir(path, work_dir)?))
+    }
+
+    /// Create a new path-based entry, that is read at its own depth instead of as 8-bit.
+    #[inline]
+    pub fn new_path_any_depth(path: PathBuf) -> Result<Self> {
+        Ok(Self::Path(Path::new_owned(path)?.with_any_depth()))
+    }
+
     /// Create a new image-based entry.
     #[inline]
     pub fn new_image<OwnString: ToString>(name: OwnString, image: Mat) -> Result<Self> {
@@ -48,6 +68,12 @@ impl Entry {
     pub fn new_image_owned(name: String, image: Mat) -> Result<Self> {
         Ok(Self::Image(Image::new_owned(name, image)?))
     }
+
+    /// Create a new entry that is transformed when read.
+    #[inline]
+    pub fn new_warped(entry: Entry, transform: Mat, size: Size, opts: warp::Opts) -> Self {
+        Self::Warped(Warped::new(entry, transform, size, opts))
+    }
 }
 
 impl Entry {
@@ -57,6 +83,7 @@ impl Entry {
         match self {
             Self::Path(p) => p.file_name(),
             Self::Image(p) => Cow::Borrowed(p.name()),
+            Self::Warped(p) => p.entry().name(),
         }
     }
 
@@ -64,33 +91,83 @@ impl Entry {
     #[inline]
     pub fn read_image(&self) -> Result<Cow<'_, Mat>> {
         Ok(match self {
-            Self::Path(p) => Cow::Owned(util::read_image(p.path())?),
+            Self::Path(p) => Cow::Owned(p.read_image()?),
             Self::Image(p) => Cow::Borrowed(p.image()),
+            Self::Warped(p) => Cow::Owned(p.read_image()?),
         })
     }
 
+    /// Get the image associated with this entry, reading files at their own depth instead of as
+    /// 8-bit.
+    #[inline]
+    pub fn read_image_any_depth(&self) -> Result<Cow<'_, Mat>> {
+        match self {
+            Self::Path(p) => Ok(Cow::Owned(util::read_image_any_depth(p.path())?)),
+            _ => self.read_image(),
+        }
+    }
+
     /// Get and *own* the image associated with this entry.
     #[inline]
     pub fn read_into_image(&mut self) -> Result<&Mat> {
         match self {
             Self::Path(p) => {
-                *self = Self::new_image(p.file_name().as_ref(), util::read_image(p.path())?)?;
+                *self = Self::new_image(p.file_name().as_ref(), p.read_image()?)?;
+                self.read_into_image()
+            }
+            Self::Warped(p) => {
+                *self = Self::new_image(p.entry().name().as_ref(), p.read_image()?)?;
                 self.read_into_image()
             }
             Self::Image(p) => Ok(p.image()),
         }
     }
 
+    /// Calculate a hash of this entry's content.
+    ///
+    /// The hash is stable across runs, and does not depend on this entry's name.
+    pub fn content_hash(&self) -> Result<u64> {
+        let mut hasher = util::ContentHasher::default();
+        match self {
+            Self::Path(p) => hasher.write(&std::fs::read(p.path())?),
+            Self::Image(p) => hash_mat(&mut hasher, p.image())?,
+            Self::Warped(p) => {
+                hasher.write_u64(p.entry().content_hash()?);
+                hash_mat(&mut hasher, p.transform())?;
+                hasher.write_i32(p.size().width);
+                hasher.write_i32(p.size().height);
+                p.opts().hash(&mut hasher);
+            }
+        }
+        Ok(hasher.finish())
+    }
+
     /// Get the image associated with this entry and consume self.
     #[inline]
     pub fn into_image(self) -> Result<Image> {
         match self {
-            Self::Path(p) => Ok(Image::new(p.file_name(), util::read_image(p.path())?)?),
+            Self::Path(p) => Ok(Image::new(p.file_name(), p.read_image()?)?),
             Self::Image(p) => Ok(p),
+            Self::Warped(p) => Ok(Image::new(p.entry().name(), p.read_image()?)?),
         }
     }
 }
 
+/// Feed the contents of a matrix to a hasher.
+fn hash_mat<H: Hasher>(hasher: &mut H, mat: &Mat) -> Result<()> {
+    let mat = if mat.is_continuous() {
+        Cow::Borrowed(mat)
+    } else {
+        Cow::Owned(mat.try_clone()?)
+    };
+    let size = mat.size()?;
+    hasher.write_i32(size.width);
+    hasher.write_i32(size.height);
+    hasher.write_i32(mat.typ());
+    hasher.write(mat.data_bytes()?);
+    Ok(())
+}
+
 /// A group of entries.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Entries<'entry, EntryIter: Iterator<Item = Cow<'entry, Entry>>> {
@@ -131,4 +208,13 @@ impl OwnedEntries {
             entries: Box::new(self.entries.iter().map(Cow::Borrowed)),
         }
     }
+
+    /// Return a group of entries that owns its entries.
+    #[inline]
+    pub fn into_entries<'entry>(self) -> Entries<'entry, OwnedEntryIter<'entry>> {
+        Entries {
+            reference: Cow::Owned(self.reference),
+            entries: Box::new(self.entries.into_iter().map(Cow::Owned)),
+        }
+    }
 }
@@ -1,15 +1,39 @@
 //! Path based image entry.
 
 use std::borrow::Cow;
+use std::hash::Hash;
 use std::io;
 use std::path::{Path as PathRef, PathBuf};
+use std::sync::Arc;
 
+use opencv::core::Mat;
+
+use crate::util::{self, WorkDir};
 use crate::Result;
 
 /// A path to an image.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone)]
 pub struct Path {
     path: PathBuf,
+    /// Keeps the working directory this image is in alive.
+    work_dir: Option<Arc<WorkDir>>,
+    /// Read the image at its own depth, instead of as 8-bit.
+    any_depth: bool,
+}
+
+impl PartialEq for Path {
+    fn eq(&self, other: &Self) -> bool {
+        self.path.eq(&other.path)
+    }
+}
+
+impl Eq for Path {}
+
+impl Hash for Path {
+    #[inline]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.path.hash(state)
+    }
 }
 
 impl Path {
@@ -28,7 +52,44 @@ impl Path {
             // FIXME: use right errors when `io_error_more` is stabilized
             return Err(io::ErrorKind::NotFound.into());
         }
-        Ok(Self { path })
+        Ok(Self {
+            path,
+            work_dir: None,
+            any_depth: false,
+        })
+    }
+
+    /// Create a new image entry from a path inside a working directory.
+    ///
+    /// The working directory is kept alive for as long as this entry is. Images in a working
+    /// directory are intermediate results, so they are read at their own depth.
+    pub fn new_in_work_dir(path: PathBuf, work_dir: Arc<WorkDir>) -> Result<Self> {
+        Ok(Self {
+            work_dir: Some(work_dir),
+            ..Self::new_owned(path)?.with_any_depth()
+        })
+    }
+
+    /// Read the image at its own depth, instead of as 8-bit.
+    ///
+    /// This is meant for images that were written at full precision, such as intermediate
+    /// results, rather than for input images.
+    #[inline]
+    pub fn with_any_depth(self) -> Self {
+        Self {
+            any_depth: true,
+            ..self
+        }
+    }
+
+    /// Read the image at this path.
+    #[inline]
+    pub fn read_image(&self) -> Result<Mat> {
+        if self.any_depth {
+            util::read_image_any_depth(&self.path)
+        } else {
+            util::read_image(&self.path)
+        }
     }
 
     /// Get the path to this entry.
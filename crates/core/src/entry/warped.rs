@@ -0,0 +1,90 @@
+//! Transform based image entry.
+
+use std::hash::Hash;
+
+use opencv::core::{Mat, Size};
+
+use super::Entry;
+use crate::util::OpaqueMat;
+use crate::{warp, Result};
+
+/// An entry that is resampled by a perspective transform when it is read.
+///
+/// This lets stages pass aligned images around without holding them in memory, or writing
+/// them to disk.
+#[derive(Debug, Clone)]
+pub struct Warped {
+    entry: Box<Entry>,
+    transform: OpaqueMat,
+    size: Size,
+    opts: warp::Opts,
+}
+
+impl PartialEq for Warped {
+    fn eq(&self, other: &Self) -> bool {
+        self.entry.eq(&other.entry)
+    }
+}
+
+impl Eq for Warped {}
+
+impl Hash for Warped {
+    #[inline]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.entry.hash(state)
+    }
+}
+
+impl Warped {
+    /// Create a new entry from an entry and a transform.
+    ///
+    /// # Parameters
+    /// - `entry`: The entry to transform.
+    /// - `transform`: The 3x3 perspective transformation matrix.
+    /// - `size`: The size of the transformed image.
+    /// - `opts`: Options used to resample the image.
+    #[inline]
+    pub fn new(entry: Entry, transform: Mat, size: Size, opts: warp::Opts) -> Self {
+        Self {
+            entry: Box::new(entry),
+            transform: OpaqueMat(transform),
+            size,
+            opts,
+        }
+    }
+
+    /// Get the untransformed entry.
+    #[inline]
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    /// Get the transformation matrix.
+    #[inline]
+    pub const fn transform(&self) -> &Mat {
+        &self.transform.0
+    }
+
+    /// Get the size of the transformed image.
+    #[inline]
+    pub const fn size(&self) -> Size {
+        self.size
+    }
+
+    /// Get the options used to resample the image.
+    #[inline]
+    pub const fn opts(&self) -> &warp::Opts {
+        &self.opts
+    }
+
+    /// Read the underlying entry and transform it.
+    #[inline]
+    pub fn read_image(&self) -> Result<Mat> {
+        warp::warp_perspective(
+            self.entry.read_image()?.as_ref(),
+            self.transform(),
+            self.size,
+            self.opts,
+        )
+    }
+}
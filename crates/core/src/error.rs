@@ -1,6 +1,9 @@
 //! Our error types.
 
 use std::io;
+use std::path::PathBuf;
+
+use opencv::core::Size;
 
 /// A general error that covers all possible errors.
 ///
@@ -11,12 +14,58 @@ pub enum Error {
     Io(#[from] io::Error),
     #[error(transparent)]
     OpenCV(#[from] opencv::Error),
+    /// An image could not be decoded or encoded.
+    #[error("unsupported image format: {}", .0.display())]
+    UnsupportedFormat(PathBuf),
+    /// Alignment did not converge to a usable transform.
+    #[error("alignment diverged: {0}")]
+    AlignmentDiverged(String),
+    /// Too few stars were detected in an image to use it.
+    #[error("insufficient stars: found {found}, need at least {required}")]
+    InsufficientStars { found: usize, required: usize },
+    /// Images that should have the same dimensions do not.
+    #[error(
+        "dimension mismatch: expected {}x{}, found {}x{}",
+        .expected.width,
+        .expected.height,
+        .found.width,
+        .found.height
+    )]
+    DimensionMismatch { expected: Size, found: Size },
+    /// An option is unusable.
+    #[error("invalid option `{key}`: {reason}")]
+    InvalidOption { key: String, reason: String },
+    /// Work was stopped before it finished.
+    #[error("cancelled")]
+    Cancelled,
+    /// Work was stopped by an error policy, such as when too many entries failed.
+    #[error("aborted: {0}")]
+    Aborted(String),
+    /// An error that occurred while processing an entry, or in a pipeline stage.
+    #[error("{}{source}", context_prefix(.entry, .stage))]
+    WithContext {
+        /// Name of the entry being processed.
+        entry: Option<String>,
+        /// Name of the stage the error occurred in.
+        stage: Option<String>,
+        source: Box<Error>,
+    },
     #[error("{0}")]
     Other(String),
     #[error("{0}")]
     OtherStatic(&'static str),
 }
 
+/// Describe where an error occurred.
+fn context_prefix(entry: &Option<String>, stage: &Option<String>) -> String {
+    match (entry, stage) {
+        (Some(e), Some(s)) => format!("stage `{}`, entry `{}`: ", s, e),
+        (Some(e), None) => format!("entry `{}`: ", e),
+        (None, Some(s)) => format!("stage `{}`: ", s),
+        (None, None) => String::new(),
+    }
+}
+
 impl From<io::ErrorKind> for Error {
     #[inline]
     fn from(e: io::ErrorKind) -> Self {
@@ -24,5 +73,92 @@ impl From<io::ErrorKind> for Error {
     }
 }
 
+impl Error {
+    /// Record the entry that this error occurred while processing.
+    ///
+    /// The innermost entry is kept if one is already recorded.
+    pub fn with_entry<S: Into<String>>(self, name: S) -> Self {
+        match self {
+            Self::WithContext {
+                entry: None,
+                stage,
+                source,
+            } => Self::WithContext {
+                entry: Some(name.into()),
+                stage,
+                source,
+            },
+            e @ Self::WithContext { .. } => e,
+            e => Self::WithContext {
+                entry: Some(name.into()),
+                stage: None,
+                source: Box::new(e),
+            },
+        }
+    }
+
+    /// Record the stage that this error occurred in.
+    ///
+    /// The innermost stage is kept if one is already recorded.
+    pub fn with_stage<S: Into<String>>(self, stage: S) -> Self {
+        match self {
+            Self::WithContext {
+                entry,
+                stage: None,
+                source,
+            } => Self::WithContext {
+                entry,
+                stage: Some(stage.into()),
+                source,
+            },
+            e @ Self::WithContext { .. } => e,
+            e => Self::WithContext {
+                entry: None,
+                stage: Some(stage.into()),
+                source: Box::new(e),
+            },
+        }
+    }
+
+    /// Get the name of the entry that this error occurred while processing, if known.
+    #[inline]
+    pub fn entry(&self) -> Option<&str> {
+        match self {
+            Self::WithContext { entry, .. } => entry.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Get the name of the stage that this error occurred in, if known.
+    #[inline]
+    pub fn stage(&self) -> Option<&str> {
+        match self {
+            Self::WithContext { stage, .. } => stage.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Get the error without the context of where it occurred.
+    #[inline]
+    pub fn kind(&self) -> &Self {
+        match self {
+            Self::WithContext { source, .. } => source.kind(),
+            e => e,
+        }
+    }
+
+    /// Check if this error was caused by work being stopped.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self.kind(), Self::Cancelled)
+    }
+
+    /// Check if this error was caused by an error policy stopping work.
+    #[inline]
+    pub fn is_aborted(&self) -> bool {
+        matches!(self.kind(), Self::Aborted(_))
+    }
+}
+
 /// Shorthand result type.
 pub type Result<T> = ::core::result::Result<T, Error>;
@@ -0,0 +1,301 @@
+//! Output image formats and bit depths.
+//!
+//! Images of any depth have black at 0. White is the largest value of integer depths, and 1 for
+//! floating point depths, so that intermediate floating point images are from 0 to 1 whatever
+//! the depth of the images they were made from.
+
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, Vector};
+
+use crate::{util, Error, Result};
+
+/// Bit depth of the values of an image that is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Depth {
+    /// 8-bit unsigned integers.
+    U8,
+    /// 16-bit unsigned integers.
+    U16,
+    /// 32-bit floating point, from 0 to 1.
+    F32,
+}
+
+impl Depth {
+    /// Get a depth by its number of bits.
+    #[inline]
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        match bits {
+            8 => Some(Self::U8),
+            16 => Some(Self::U16),
+            32 => Some(Self::F32),
+            _ => None,
+        }
+    }
+
+    /// Number of bits of each value.
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        match self {
+            Self::U8 => 8,
+            Self::U16 => 16,
+            Self::F32 => 32,
+        }
+    }
+
+    /// The OpenCV depth of this depth.
+    #[inline]
+    const fn cv_depth(self) -> i32 {
+        match self {
+            Self::U8 => opencv::core::CV_8U,
+            Self::U16 => opencv::core::CV_16U,
+            Self::F32 => opencv::core::CV_32F,
+        }
+    }
+}
+
+/// Get the value that white is represented by in an OpenCV depth.
+///
+/// Floating point images are expected to be from 0 to 1.
+pub fn white(cv_depth: i32) -> f64 {
+    match cv_depth {
+        opencv::core::CV_8U => u8::MAX as f64,
+        opencv::core::CV_8S => i8::MAX as f64,
+        opencv::core::CV_16U => u16::MAX as f64,
+        opencv::core::CV_16S => i16::MAX as f64,
+        opencv::core::CV_32S => i32::MAX as f64,
+        _ => 1.0,
+    }
+}
+
+/// Format of an image that is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Format {
+    /// TIFF, 16-bit by default.
+    Tif16,
+    /// TIFF, 32-bit floating point by default.
+    Tif32f,
+    /// FITS, 16-bit by default.
+    Fits,
+    /// PNG, 16-bit by default.
+    Png16,
+    /// JPEG, which is always 8-bit.
+    Jpg,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tif16 => "tif16",
+            Self::Tif32f => "tif32f",
+            Self::Fits => "fits",
+            Self::Png16 => "png16",
+            Self::Jpg => "jpg",
+        })
+    }
+}
+
+impl Format {
+    /// Get the format that a file's extension refers to.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path
+            .as_ref()
+            .extension()?
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "tif" | "tiff" => Some(Self::Tif16),
+            "fits" | "fit" | "fts" => Some(Self::Fits),
+            "png" => Some(Self::Png16),
+            "jpg" | "jpeg" => Some(Self::Jpg),
+            _ => None,
+        }
+    }
+
+    /// Extension of files of this format.
+    #[inline]
+    pub const fn extension(self) -> &'static str {
+        match self {
+            Self::Tif16 | Self::Tif32f => "tif",
+            Self::Fits => "fits",
+            Self::Png16 => "png",
+            Self::Jpg => "jpg",
+        }
+    }
+
+    /// Depth that images are written with if none is given.
+    #[inline]
+    pub const fn default_depth(self) -> Depth {
+        match self {
+            Self::Tif16 | Self::Fits | Self::Png16 => Depth::U16,
+            Self::Tif32f => Depth::F32,
+            Self::Jpg => Depth::U8,
+        }
+    }
+
+    /// Check if images can be written with a depth in this format.
+    #[inline]
+    pub const fn supports(self, depth: Depth) -> bool {
+        match self {
+            Self::Tif16 | Self::Tif32f | Self::Fits => true,
+            Self::Png16 => !matches!(depth, Depth::F32),
+            Self::Jpg => matches!(depth, Depth::U8),
+        }
+    }
+}
+
+/// Convert an image to a depth, scaling its values from black to white.
+#[inline]
+pub fn convert_depth(image: &Mat, depth: Depth) -> Result<Mat> {
+    convert_cv_depth(image, depth.cv_depth())
+}
+
+/// Convert an image to an OpenCV depth, scaling its values from black to white.
+pub fn convert_cv_depth(image: &Mat, cv_depth: i32) -> Result<Mat> {
+    let scale = white(cv_depth) / white(image.depth());
+    let mut out = Mat::default();
+    image.convert_to(&mut out, cv_depth, scale, 0.0)?;
+    Ok(out)
+}
+
+/// Convert an image to 32-bit floating point, from 0 to 1.
+#[inline]
+pub fn to_float(image: &Mat) -> Result<Mat> {
+    convert_cv_depth(image, opencv::core::CV_32F)
+}
+
+/// Write an image in a format.
+///
+/// # Parameters
+/// - `path`: The file to write. Its extension is not checked.
+/// - `image`: The image to write.
+/// - `format`: The format to write the image in.
+/// - `depth`: The depth to write the image with. Defaults to the format's
+///   [default depth](Format::default_depth).
+pub fn write_image<P: AsRef<Path>>(
+    path: P,
+    image: &Mat,
+    format: Format,
+    depth: Option<Depth>,
+) -> Result<()> {
+    let depth = depth.unwrap_or_else(|| format.default_depth());
+    if !format.supports(depth) {
+        return Err(Error::InvalidOption {
+            key: "output_depth".to_owned(),
+            reason: format!("{} bits is not supported by {}", depth.bits(), format),
+        });
+    }
+    let image = convert_depth(image, depth)?;
+    match format {
+        Format::Fits => write_fits(path.as_ref(), &image),
+        _ => util::write_image(path, &image),
+    }
+}
+
+/// Write an image in a format and depth, if they are given.
+///
+/// The format defaults to the path's extension if a depth is given. Without either, the image is
+/// written as it is, in the format of the path's extension.
+pub fn write_image_as<P: AsRef<Path>>(
+    path: P,
+    image: &Mat,
+    format: Option<Format>,
+    depth: Option<Depth>,
+) -> Result<()> {
+    let format = format.or_else(|| depth.and(Format::from_path(&path)));
+    match (format, depth) {
+        (Some(format), depth) => write_image(path, image, format, depth),
+        (None, Some(depth)) => util::write_image(path, &convert_depth(image, depth)?),
+        (None, None) => util::write_image(path, image),
+    }
+}
+
+/// Write an image as FITS.
+///
+/// Images with several channels are written as a cube of RGB planes. Rows are written from the
+/// bottom up, as FITS expects. 8 and 16-bit unsigned images are written as integers, and others
+/// as 32-bit floating point.
+pub(crate) fn write_fits(path: &Path, image: &Mat) -> Result<()> {
+    let (image, bitpix) = match image.depth() {
+        opencv::core::CV_8U => (image.clone(), 8),
+        opencv::core::CV_16U => (image.clone(), 16),
+        _ => {
+            let mut f = Mat::default();
+            image.convert_to(&mut f, opencv::core::CV_32F, 1.0, 0.0)?;
+            (f, -32)
+        }
+    };
+    let channels = image.channels();
+    let (width, height) = (image.cols(), image.rows());
+
+    // Header
+    let mut cards = vec![
+        format!("{:<8}= {:>20}", "SIMPLE", "T"),
+        format!("{:<8}= {:>20}", "BITPIX", bitpix),
+        format!("{:<8}= {:>20}", "NAXIS", if channels > 1 { 3 } else { 2 }),
+        format!("{:<8}= {:>20}", "NAXIS1", width),
+        format!("{:<8}= {:>20}", "NAXIS2", height),
+    ];
+    if channels > 1 {
+        cards.push(format!("{:<8}= {:>20}", "NAXIS3", channels));
+    }
+    if bitpix == 16 {
+        // Unsigned values are stored offset, as signed
+        cards.push(format!("{:<8}= {:>20}", "BZERO", 32768));
+        cards.push(format!("{:<8}= {:>20}", "BSCALE", 1));
+    }
+    cards.push("END".to_owned());
+    let mut header = cards
+        .iter()
+        .map(|c| format!("{:<80}", c))
+        .collect::<String>()
+        .into_bytes();
+    pad(&mut header, b' ');
+
+    // Data, with BGR planes reversed
+    let mut planes = Vector::<Mat>::new();
+    opencv::core::split(&image, &mut planes)?;
+    let mut data = Vec::with_capacity((width * height * channels) as usize * 4);
+    for plane in planes.iter().rev() {
+        for row in (0..height).rev() {
+            match bitpix {
+                8 => data.extend_from_slice(plane.at_row::<u8>(row)?),
+                16 => {
+                    for v in plane.at_row::<u16>(row)? {
+                        data.extend_from_slice(&((*v as i32 - 32768) as i16).to_be_bytes());
+                    }
+                }
+                _ => {
+                    for v in plane.at_row::<f32>(row)? {
+                        data.extend_from_slice(&v.to_be_bytes());
+                    }
+                }
+            }
+        }
+    }
+    pad(&mut data, 0);
+
+    if let Some(p) = path.parent() {
+        if !p.as_os_str().is_empty() && !p.exists() {
+            std::fs::create_dir_all(p)?;
+        }
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(&header)?;
+    file.write_all(&data)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Pad FITS data to a whole number of blocks.
+fn pad(data: &mut Vec<u8>, value: u8) {
+    const BLOCK: usize = 2880;
+    let len = (data.len() + BLOCK - 1) / BLOCK * BLOCK;
+    data.resize(len, value);
+}
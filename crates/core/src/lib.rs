@@ -2,7 +2,9 @@
 
 pub mod entry;
 pub mod error;
+pub mod format;
 pub mod util;
+pub mod warp;
 
 pub use error::*;
 pub use opencv as cv;
@@ -1,11 +1,14 @@
 //! General purpose utilities.
 
+use std::hash::Hasher;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use opencv::core::{Mat, Vector, VectorElement, VectorExtern};
+use opencv::core::{Mat, MatTraitConst, Vector, VectorElement, VectorExtern};
 use opencv::imgcodecs;
 
-use crate::Result;
+use crate::{Error, Result};
 
 lazy_static::lazy_static! {
     /// Static reference to an empty vector.
@@ -36,10 +39,100 @@ unsafe impl Sync for OpaqueMat {}
 /// Get a temporary directory path to work with.
 #[inline]
 pub fn temp_dir() -> PathBuf {
-    PathBuf::from("/tmp/medo")
+    std::env::temp_dir().join("medo")
+}
+
+/// A uniquely named working directory.
+///
+/// The directory and all of its contents are removed when this is dropped.
+#[derive(Debug)]
+pub struct WorkDir {
+    path: PathBuf,
+}
+
+impl WorkDir {
+    /// Create a new working directory inside the [temporary directory](temp_dir).
+    #[inline]
+    pub fn new() -> Result<Self> {
+        Self::new_in(temp_dir())
+    }
+
+    /// Create a new working directory inside `parent`.
+    pub fn new_in<P: AsRef<Path>>(parent: P) -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        let path = parent.as_ref().join(format!(
+            "run-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    /// Get the path to this directory.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Get the directory in which persistent caches should be stored.
+///
+/// This is `$XDG_CACHE_HOME/medo`, falling back to `$HOME/.cache/medo`.
+pub fn cache_dir() -> PathBuf {
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+        .map(|p| p.join("medo"))
+        .unwrap_or_else(|| temp_dir().join("cache"))
+}
+
+/// A [FNV-1a] hasher.
+///
+/// Unlike [`std::collections::hash_map::DefaultHasher`], its output is stable across builds, so
+/// it can be used to identify content that is persisted.
+///
+/// [FNV-1a]: http://www.isthe.com/chongo/tech/comp/fnv/
+#[derive(Debug, Clone, Copy)]
+pub struct ContentHasher(u64);
+
+impl Default for ContentHasher {
+    #[inline]
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for ContentHasher {
+    #[inline]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
 }
 
 /// Convenience method to write an image with default options.
+///
+/// Files with a FITS extension are written as FITS, and others by OpenCV.
 pub fn write_image<P: AsRef<Path>>(path: P, image: &Mat) -> Result<()> {
     let path = path.as_ref();
     // Create parent directory if it doesn't exist
@@ -48,15 +141,38 @@ pub fn write_image<P: AsRef<Path>>(path: P, image: &Mat) -> Result<()> {
             std::fs::create_dir_all(p)?;
         }
     }
-    imgcodecs::imwrite(path.to_string_lossy().as_ref(), &image, &EMPTY_VEC_I32.0)?;
+    if crate::format::Format::from_path(path) == Some(crate::format::Format::Fits) {
+        return crate::format::write_fits(path, image);
+    }
+    if !imgcodecs::imwrite(path.to_string_lossy().as_ref(), &image, &EMPTY_VEC_I32.0)? {
+        return Err(Error::UnsupportedFormat(path.to_owned()));
+    }
     Ok(())
 }
 
 /// Convenience method to read a BGR image.
 #[inline]
 pub fn read_image<P: AsRef<Path>>(path: P) -> Result<Mat> {
-    Ok(imgcodecs::imread(
-        path.as_ref().to_string_lossy().as_ref(),
-        imgcodecs::IMREAD_COLOR,
-    )?)
+    read_image_with_flags(path.as_ref(), imgcodecs::IMREAD_COLOR)
+}
+
+/// Convenience method to read a BGR image, keeping its bit depth.
+///
+/// Images such as calibration masters need more precision than 8 bits.
+#[inline]
+pub fn read_image_any_depth<P: AsRef<Path>>(path: P) -> Result<Mat> {
+    read_image_with_flags(
+        path.as_ref(),
+        imgcodecs::IMREAD_COLOR | imgcodecs::IMREAD_ANYDEPTH,
+    )
+}
+
+fn read_image_with_flags(path: &Path, flags: i32) -> Result<Mat> {
+    let image = imgcodecs::imread(path.to_string_lossy().as_ref(), flags)?;
+    // OpenCV gives an empty image for files it can't read
+    if image.empty() {
+        std::fs::metadata(path)?;
+        return Err(Error::UnsupportedFormat(path.to_owned()));
+    }
+    Ok(image)
 }
@@ -0,0 +1,297 @@
+//! Resampling of images by perspective transforms.
+
+use opencv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar, Size};
+use opencv::imgproc;
+
+use crate::{format, Result};
+
+/// Interpolation method used to resample an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Interpolation {
+    /// Nearest neighbour interpolation.
+    Nearest,
+    /// Bilinear interpolation.
+    Bilinear,
+    /// Bicubic interpolation over a 4x4 neighbourhood.
+    Bicubic,
+    /// Lanczos interpolation over a 6x6 neighbourhood.
+    ///
+    /// OpenCV has no such method, so it is much slower than the others.
+    Lanczos3,
+    /// Lanczos interpolation over an 8x8 neighbourhood.
+    Lanczos4,
+}
+
+impl Default for Interpolation {
+    #[inline]
+    fn default() -> Self {
+        Self::Bilinear
+    }
+}
+
+impl Interpolation {
+    /// The radius of this method's kernel, if it is not provided by OpenCV.
+    #[inline]
+    const fn kernel_radius(self) -> Option<i32> {
+        match self {
+            Self::Lanczos3 => Some(3),
+            Self::Nearest | Self::Bilinear | Self::Bicubic | Self::Lanczos4 => None,
+        }
+    }
+
+    /// Check if this method overshoots around sharp edges, leaving rings.
+    #[inline]
+    const fn rings(self) -> bool {
+        matches!(self, Self::Bicubic | Self::Lanczos3 | Self::Lanczos4)
+    }
+
+    /// Evaluate this method's kernel.
+    fn kernel(self, t: f32) -> f32 {
+        let a = self.kernel_radius().unwrap() as f32;
+        let t = t.abs();
+        if t < f32::EPSILON {
+            1.0
+        } else if t < a {
+            let pt = std::f32::consts::PI * t;
+            a * pt.sin() * (pt / a).sin() / (pt * pt)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Resampling options.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct Opts {
+    /// Interpolation method.
+    pub interpolation: Interpolation,
+    /// Clamp interpolated values to the range of the source pixels around them: the 3x3
+    /// neighbourhood of the nearest source pixel, whatever the interpolation method.
+    ///
+    /// This prevents the dark rings that bicubic and lanczos interpolation leave around stars.
+    /// It has no effect on nearest neighbour and bilinear interpolation.
+    pub clamp: bool,
+    /// Mark pixels that fall outside the source image as invalid (`NaN`), instead of black.
+    ///
+    /// The resampled image is always 32-bit floating point, from 0 to 1, when this is set.
+    pub invalid_border: bool,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            interpolation: Default::default(),
+            clamp: true,
+            invalid_border: false,
+        }
+    }
+}
+
+/// Resample an image by a perspective transform.
+///
+/// # Parameters
+/// - `src`: The image to resample.
+/// - `transform`: The 3x3 transformation matrix, mapping `src` to the resampled image.
+/// - `size`: The size of the resampled image.
+pub fn warp_perspective(src: &Mat, transform: &Mat, size: Size, opts: Opts) -> Result<Mat> {
+    let radius = match opts.interpolation.kernel_radius() {
+        Some(r) => r,
+        None => return warp_perspective_cv(src, transform, size, opts),
+    };
+
+    let channels = src.channels() as usize;
+    let src_size = src.size()?;
+    let (src_width, src_height) = (src_size.width, src_size.height);
+
+    // Resample in floating point, one channel per column
+    let src_f = format::to_float(src)?;
+    let src_f = src_f.reshape(1, 0)?;
+    let src_data = src_f.data_typed::<f32>()?;
+    let at = |x: i32, y: i32, c: usize| -> f32 {
+        let x = x.clamp(0, src_width - 1) as usize;
+        let y = y.clamp(0, src_height - 1) as usize;
+        src_data[(y * src_width as usize + x) * channels + c]
+    };
+
+    // Destination pixels are mapped back to the source
+    let inverse = inverse_transform(transform)?;
+    let border = if opts.invalid_border { f32::NAN } else { 0.0 };
+
+    let mut dst = Mat::new_rows_cols_with_default(
+        size.height,
+        size.width * channels as i32,
+        opencv::core::CV_32F,
+        Scalar::all(0.0),
+    )?;
+    let dst_data = dst.data_typed_mut::<f32>()?;
+    let taps = 2 * radius as usize;
+    let mut weights_x = vec![0.0; taps];
+    let mut weights_y = vec![0.0; taps];
+
+    for y in 0..size.height {
+        for x in 0..size.width {
+            let out = &mut dst_data[(y * size.width + x) as usize * channels..][..channels];
+
+            // Map to source coordinates
+            let (fx, fy) = (x as f64, y as f64);
+            let w = inverse[6] * fx + inverse[7] * fy + inverse[8];
+            let sx = ((inverse[0] * fx + inverse[1] * fy + inverse[2]) / w) as f32;
+            let sy = ((inverse[3] * fx + inverse[4] * fy + inverse[5]) / w) as f32;
+            if !(-0.5..src_width as f32 - 0.5).contains(&sx)
+                || !(-0.5..src_height as f32 - 0.5).contains(&sy)
+            {
+                out.fill(border);
+                continue;
+            }
+
+            // Kernel weights
+            let (x0, y0) = (sx.floor() as i32, sy.floor() as i32);
+            for (i, (wx, wy)) in weights_x.iter_mut().zip(&mut weights_y).enumerate() {
+                let offset = i as i32 - radius + 1;
+                *wx = opts.interpolation.kernel(sx - (x0 + offset) as f32);
+                *wy = opts.interpolation.kernel(sy - (y0 + offset) as f32);
+            }
+            let norm = weights_x.iter().sum::<f32>() * weights_y.iter().sum::<f32>();
+
+            for (c, out) in out.iter_mut().enumerate() {
+                let mut value = 0.0;
+                for (j, wy) in weights_y.iter().enumerate() {
+                    let py = y0 + j as i32 - radius + 1;
+                    for (i, wx) in weights_x.iter().enumerate() {
+                        value += wx * wy * at(x0 + i as i32 - radius + 1, py, c);
+                    }
+                }
+                value /= norm;
+
+                if opts.clamp {
+                    // The same neighbourhood as OpenCV's methods are clamped to
+                    let (nx, ny) = (sx.round() as i32, sy.round() as i32);
+                    let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+                    for py in ny - 1..=ny + 1 {
+                        for px in nx - 1..=nx + 1 {
+                            min = min.min(at(px, py, c));
+                            max = max.max(at(px, py, c));
+                        }
+                    }
+                    // Neighbourhoods of invalid values have no range
+                    if min <= max {
+                        value = value.clamp(min, max);
+                    }
+                }
+                *out = value;
+            }
+        }
+    }
+
+    let dst = dst.reshape(channels as i32, 0)?;
+    if opts.invalid_border {
+        return Ok(dst);
+    }
+    format::convert_cv_depth(&dst, src.depth())
+}
+
+/// Resample an image with one of OpenCV's interpolation methods.
+fn warp_perspective_cv(src: &Mat, transform: &Mat, size: Size, opts: Opts) -> Result<Mat> {
+    let flags = match opts.interpolation {
+        Interpolation::Nearest => imgproc::INTER_NEAREST,
+        Interpolation::Bilinear => imgproc::INTER_LINEAR,
+        Interpolation::Bicubic => imgproc::INTER_CUBIC,
+        Interpolation::Lanczos4 => imgproc::INTER_LANCZOS4,
+        Interpolation::Lanczos3 => unreachable!(),
+    };
+    let border = if opts.invalid_border { f64::NAN } else { 0.0 };
+    let warp = |image: &Mat, flags: i32| -> Result<Mat> {
+        let mut dst = Mat::default();
+        imgproc::warp_perspective(
+            image,
+            &mut dst,
+            transform,
+            size,
+            flags,
+            opencv::core::BORDER_CONSTANT,
+            Scalar::all(border),
+        )?;
+        Ok(dst)
+    };
+
+    let clamp = opts.clamp && opts.interpolation.rings();
+    if !clamp && !opts.invalid_border {
+        return warp(src, flags);
+    }
+
+    // Resample in floating point, so that values can be clamped and the border is invalid
+    let src_f = format::to_float(src)?;
+    let mut dst = warp(&src_f, flags)?;
+    if clamp {
+        // The range of the 3x3 neighbourhood of each source pixel, taken at the nearest source
+        // pixel of each resampled pixel
+        let anchor = opencv::core::Point::new(-1, -1);
+        let border_value = imgproc::morphology_default_border_value()?;
+        let (mut min, mut max) = (Mat::default(), Mat::default());
+        imgproc::erode(
+            &src_f,
+            &mut min,
+            &Mat::default(),
+            anchor,
+            1,
+            opencv::core::BORDER_REPLICATE,
+            border_value,
+        )?;
+        imgproc::dilate(
+            &src_f,
+            &mut max,
+            &Mat::default(),
+            anchor,
+            1,
+            opencv::core::BORDER_REPLICATE,
+            border_value,
+        )?;
+        let min = warp(&min, imgproc::INTER_NEAREST)?.reshape(1, 0)?;
+        let max = warp(&max, imgproc::INTER_NEAREST)?.reshape(1, 0)?;
+
+        let channels = dst.channels();
+        let mut dst_1 = dst.reshape(1, 0)?;
+        let values = dst_1.data_typed_mut::<f32>()?;
+        let bounds = min
+            .data_typed::<f32>()?
+            .iter()
+            .zip(max.data_typed::<f32>()?);
+        for (v, (min, max)) in values.iter_mut().zip(bounds) {
+            // Invalid values stay invalid, as `max` and `min` ignore `NaN` bounds
+            if !v.is_nan() {
+                *v = v.max(*min).min(*max);
+            }
+        }
+        dst = dst_1.reshape(channels, 0)?;
+    }
+
+    if opts.invalid_border {
+        return Ok(dst);
+    }
+    format::convert_cv_depth(&dst, src.depth())
+}
+
+/// Read a 3x3 transformation matrix, in row major order.
+pub fn transform_to_array(transform: &Mat) -> Result<[f64; 9]> {
+    let mut transform_f64 = Mat::default();
+    transform.convert_to(&mut transform_f64, opencv::core::CV_64F, 1.0, 0.0)?;
+
+    let mut out = [0.0; 9];
+    for (i, v) in out.iter_mut().enumerate() {
+        *v = *transform_f64.at_2d::<f64>(i as i32 / 3, i as i32 % 3)?;
+    }
+    Ok(out)
+}
+
+/// Invert a 3x3 transformation matrix, in row major order.
+fn inverse_transform(transform: &Mat) -> Result<[f64; 9]> {
+    let mut transform_f64 = Mat::default();
+    transform.convert_to(&mut transform_f64, opencv::core::CV_64F, 1.0, 0.0)?;
+    let mut inverse = Mat::default();
+    opencv::core::invert(&transform_f64, &mut inverse, opencv::core::DECOMP_LU)?;
+    transform_to_array(&inverse)
+}
@@ -0,0 +1,144 @@
+//! Calibration of images by bias, dark and flat masters.
+//!
+//! A **bias** master is the sensor's offset, and a **dark** master is the offset along with the
+//! thermal signal of an exposure, so only one of them is subtracted from an image. A **flat**
+//! master is the vignetting and dust of the optical train, normalized to a mean of 1, which an
+//! image is divided by.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, Scalar};
+use medo_core::entry::Entry;
+use medo_core::util::{self, OpaqueMat};
+use medo_core::{format, Error, Result};
+use medo_stacker::stacker::average;
+
+/// Kinds of calibration masters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Bias,
+    Dark,
+    Flat,
+}
+
+/// Masters that images are calibrated by.
+#[derive(Debug, Default)]
+pub struct Masters {
+    pub bias: Option<OpaqueMat>,
+    pub dark: Option<OpaqueMat>,
+    pub flat: Option<OpaqueMat>,
+}
+
+impl Masters {
+    /// Read masters from files.
+    pub fn read(bias: Option<&Path>, dark: Option<&Path>, flat: Option<&Path>) -> Result<Self> {
+        let read = |path: Option<&Path>| -> Result<Option<OpaqueMat>> {
+            path.map(|p| {
+                Ok(OpaqueMat(format::to_float(&util::read_image_any_depth(
+                    p,
+                )?)?))
+            })
+            .transpose()
+        };
+        Ok(Self {
+            bias: read(bias)?,
+            dark: read(dark)?,
+            flat: read(flat)?,
+        })
+    }
+
+    /// Check if there are no masters.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bias.is_none() && self.dark.is_none() && self.flat.is_none()
+    }
+
+    /// Calibrate an image, as 32-bit floating point.
+    ///
+    /// The image is scaled from 0 to 1 like the masters, whatever its depth. The dark master is
+    /// subtracted if there is one, otherwise the bias master is. The image is then divided by the
+    /// flat master.
+    pub fn apply(&self, image: &Mat) -> Result<Mat> {
+        let mut out = format::to_float(image)?;
+        if let Some(OpaqueMat(offset)) = self.dark.as_ref().or(self.bias.as_ref()) {
+            check_size(offset, &out)?;
+            let mut subtracted = Mat::default();
+            cv::core::subtract(&out, offset, &mut subtracted, &Mat::default(), -1)?;
+            out = subtracted;
+        }
+        if let Some(OpaqueMat(flat)) = &self.flat {
+            check_size(flat, &out)?;
+            let mut divided = Mat::default();
+            cv::core::divide2(&out, flat, &mut divided, 1.0, -1)?;
+            out = divided;
+        }
+        Ok(out)
+    }
+}
+
+/// Build a master from calibration frames.
+///
+/// Frames are read at their own depth, and averaged as they are read. Flat frames are calibrated
+/// by the given masters before they are averaged, and the master is normalized to a mean of 1.
+/// Masters are 32-bit floating point.
+///
+/// # Parameters
+/// - `entries`: The calibration frames.
+/// - `kind`: The kind of master to build.
+/// - `masters`: Masters that flat frames are calibrated by.
+pub fn build_master(entries: Vec<Entry>, kind: Kind, masters: &Masters) -> Result<Mat> {
+    if entries.is_empty() {
+        return Err(Error::OtherStatic("no frames to build a master from"));
+    }
+    let span = tracing::info_span!("calibration", kind = ?kind);
+    let _enter = span.enter();
+
+    let read = |e: &Entry| -> Result<Mat> {
+        let image = e.read_image_any_depth()?;
+        tracing::debug!(name = %e.name(), "read frame");
+        match kind {
+            Kind::Flat => masters.apply(&image),
+            Kind::Bias | Kind::Dark => format::to_float(&image),
+        }
+    };
+
+    let mut entries = entries.into_iter();
+    let first_entry = entries.next().unwrap();
+    let first = read(&first_entry)
+        .and_then(|i| Entry::new_image(first_entry.name(), i))
+        .map_err(|err| err.with_entry(first_entry.name()))?;
+    let mut stacker = average::Stacker::new(std::iter::once(Cow::Owned(first)))?;
+    for e in entries {
+        read(&e)
+            .and_then(|i| stacker.add(&i))
+            .map_err(|err| err.with_entry(e.name()))?;
+    }
+    let mut master = stacker.leak().read_image()?.into_owned();
+
+    if kind == Kind::Flat {
+        let mean = cv::core::mean(&master, &Mat::default())?;
+        let channels = master.channels() as usize;
+        let mean = mean.0[..channels].iter().sum::<f64>() / channels as f64;
+        if mean <= 0.0 {
+            return Err(Error::OtherStatic("flat master has no signal"));
+        }
+        let mut normalized = Mat::default();
+        cv::core::multiply(&master, &Scalar::all(1.0 / mean), &mut normalized, 1.0, -1)?;
+        master = normalized;
+    }
+    Ok(master)
+}
+
+/// Fail if a master does not have the same dimensions as an image.
+fn check_size(master: &Mat, image: &Mat) -> Result<()> {
+    let (expected, found) = (image.size()?, master.size()?);
+    if expected != found {
+        return Err(Error::DimensionMismatch { expected, found });
+    }
+    Ok(())
+}
@@ -3,7 +3,7 @@
 use medo_core::entry::OwnedEntries;
 use medo_core::Result;
 
-use crate::pipeline::Pipeline;
+use crate::pipeline::{Context, Event, Failure, Pipeline};
 
 /// A group of entries and associated processing options.
 pub struct Group {
@@ -17,19 +17,47 @@ pub struct Group {
     pub entries: OwnedEntries,
     /// The output of running this group's pipeline.
     pub pipeline_output: Option<OwnedEntries>,
+    /// Entries that failed to be processed by the last run of this group's pipeline.
+    pub failures: Vec<Failure>,
 }
 
 impl Group {
     /// Process this group's pipeline.
+    #[inline]
     pub fn process(&mut self) -> Result<&OwnedEntries> {
+        self.process_with(&Context::default())
+    }
+
+    /// Process this group's pipeline in a context.
+    ///
+    /// Fails with [`Error::Cancelled`](medo_core::Error::Cancelled) if the context is cancelled,
+    /// and with [`Error::Aborted`](medo_core::Error::Aborted) if the pipeline's error policy
+    /// aborts the run. Failed entries are recorded in
+    /// [`failures`](Self::failures) either way.
+    pub fn process_with(&mut self, ctx: &Context) -> Result<&OwnedEntries> {
         let span = tracing::info_span!("group", name = %self.name);
         let _enter = span.enter();
 
-        self.pipeline_output = Some(
-            self.pipeline
-                .process(self.entries.to_borrow())?
-                .into_owned(),
-        );
+        let start = std::time::Instant::now();
+        ctx.emit(Event::GroupStarted {
+            name: self.name.clone(),
+            entries: self.entries.entries.len(),
+        });
+
+        let ctx = ctx.for_run();
+        let output = self
+            .pipeline
+            .process_with(self.entries.to_borrow(), &ctx)
+            .map(|o| o.into_owned());
+        self.failures = ctx.failures();
+        ctx.emit(Event::GroupFinished {
+            name: self.name.clone(),
+            time: start.elapsed(),
+            failed: self.failures.len(),
+        });
+        let output = output?;
+        ctx.check_cancelled()?;
+        self.pipeline_output = Some(output);
         Ok(self.pipeline_output.as_ref().unwrap())
     }
 }
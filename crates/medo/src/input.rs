@@ -0,0 +1,212 @@
+//! Selection of the files that entries are read from.
+//!
+//! Inputs can be directories, files, glob patterns, or `@` prefixed lists of inputs. Capture
+//! directories are full of files that are not images, such as sidecars and thumbnails, which are
+//! left out so that they are not stacked, or chosen as the reference.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv::imgcodecs;
+use medo_core::entry::Entry;
+use medo_core::{Error, Result};
+
+use crate::metadata::capture_time;
+
+/// Extensions of the files that are used by default.
+pub const IMAGE_EXTENSIONS: &[&str] = &[
+    "tif", "tiff", "png", "jpg", "jpeg", "jp2", "bmp", "dib", "webp", "ppm", "pgm", "pbm", "pnm",
+    "pfm", "exr", "hdr", "pic", "sr", "ras",
+];
+
+/// Order that entries are given in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    /// By path.
+    Name,
+    /// By capture time, then path. Entries with no known capture time are ordered last.
+    CaptureTime,
+}
+
+impl Default for Order {
+    #[inline]
+    fn default() -> Self {
+        Self::Name
+    }
+}
+
+/// Input selection options.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Whether the subdirectories of input directories are searched.
+    pub recursive: bool,
+    /// Extensions of the files to use, case insensitive.
+    ///
+    /// Defaults to [`IMAGE_EXTENSIONS`].
+    pub include: Vec<String>,
+    /// Extensions of the files to leave out, case insensitive.
+    pub exclude: Vec<String>,
+    /// Order that entries are given in.
+    pub order: Order,
+}
+
+impl Opts {
+    /// Check if a file should be used, by its name.
+    ///
+    /// Hidden files and thumbnails are left out, along with files whose extensions are not
+    /// included, or are excluded.
+    pub fn accepts(&self, path: &Path) -> bool {
+        let stem = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_ascii_lowercase())
+            .unwrap_or_default();
+        if is_hidden(path) || stem.ends_with("thumb") || stem.ends_with("thumbnail") {
+            return false;
+        }
+
+        let included = if self.include.is_empty() {
+            IMAGE_EXTENSIONS.iter().any(|e| has_extension(path, e))
+        } else {
+            self.include.iter().any(|e| has_extension(path, e))
+        };
+        included && !self.excludes(path)
+    }
+
+    /// Check if a file's extension is excluded.
+    #[inline]
+    pub fn excludes(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|e| has_extension(path, e))
+    }
+}
+
+/// Select the files of some inputs, and read them as entries.
+///
+/// Each input is one of:
+/// - A directory, whose files are used.
+/// - A file.
+/// - A glob pattern, such as `lights/*.tif`, whose matching files are used.
+/// - A list of inputs, one per line, prefixed by `@`, such as `@lights.txt`. Relative inputs are
+///   relative to the list, and lines starting with `#` are ignored.
+///
+/// Files are used at most once, and files that can't be decoded are left out.
+pub fn select<S: AsRef<str>>(inputs: &[S], opts: &Opts) -> Result<Vec<Entry>> {
+    let mut paths = BTreeSet::new();
+    for input in inputs {
+        let input = input.as_ref();
+        match input.strip_prefix('@') {
+            Some(list) => {
+                let list = Path::new(list);
+                let base = list.parent().unwrap_or_else(|| Path::new(""));
+                let contents =
+                    std::fs::read_to_string(list).map_err(|e| Error::from(e).with_entry(input))?;
+                for line in contents.lines().map(str::trim) {
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    let line = base.join(line);
+                    add_input(&mut paths, &line.to_string_lossy(), opts)?;
+                }
+            }
+            None => add_input(&mut paths, input, opts)?,
+        }
+    }
+
+    let mut entries = vec![];
+    for path in paths {
+        // Checks the file's signature, without decoding it
+        if !imgcodecs::have_image_reader(&path.to_string_lossy()).unwrap_or(false) {
+            tracing::warn!(path = %path.display(), "unreadable file, leaving it out");
+            continue;
+        }
+        entries.push(Entry::new_path_owned(path)?);
+    }
+    // Entries are already ordered by path, which breaks ties
+    if opts.order == Order::CaptureTime {
+        let mut keyed = entries
+            .into_iter()
+            .enumerate()
+            .map(|(i, e)| {
+                let time = capture_time(&e);
+                ((time.is_none(), time, i), e)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries = keyed.into_iter().map(|(_, e)| e).collect();
+    }
+    tracing::info!(entries = entries.len(), "selected inputs");
+    Ok(entries)
+}
+
+/// List the files of a directory that would be selected, without reading them.
+///
+/// Files are ordered by path.
+pub fn list_dir(dir: &Path, opts: &Opts) -> Result<Vec<PathBuf>> {
+    let mut paths = BTreeSet::new();
+    add_dir(&mut paths, dir, opts).map_err(|e| e.with_entry(dir.to_string_lossy()))?;
+    Ok(paths.into_iter().collect())
+}
+
+/// Add the files of an input that is not a list.
+fn add_input(paths: &mut BTreeSet<PathBuf>, input: &str, opts: &Opts) -> Result<()> {
+    let path = Path::new(input);
+    if path.is_dir() {
+        return add_dir(paths, path, opts).map_err(|e| e.with_entry(input));
+    }
+    if path.is_file() {
+        // Files that were asked for by name are used unless they are excluded
+        if !opts.excludes(path) {
+            paths.insert(path.to_owned());
+        }
+        return Ok(());
+    }
+    if !input.contains(&['*', '?', '['][..]) {
+        return Err(Error::from(std::io::ErrorKind::NotFound).with_entry(input));
+    }
+
+    let matches = glob::glob(input).map_err(|e| Error::InvalidOption {
+        key: "input".to_owned(),
+        reason: format!("invalid glob pattern `{}`: {}", input, e),
+    })?;
+    for path in matches {
+        let path = path.map_err(|e| Error::from(e.into_error()).with_entry(input))?;
+        if path.is_file() && opts.accepts(&path) {
+            paths.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Add the files of a directory.
+fn add_dir(paths: &mut BTreeSet<PathBuf>, dir: &Path, opts: &Opts) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if opts.recursive && !is_hidden(&path) {
+                add_dir(paths, &path, opts)?;
+            }
+        } else if opts.accepts(&path) {
+            paths.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Check if a file or directory is hidden.
+#[inline]
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .map_or(false, |n| n.to_string_lossy().starts_with('.'))
+}
+
+/// Check if a file has an extension, case insensitively.
+#[inline]
+fn has_extension(path: &Path, ext: &str) -> bool {
+    path.extension().map_or(false, |e| {
+        e.to_string_lossy()
+            .eq_ignore_ascii_case(ext.trim_start_matches('.'))
+    })
+}
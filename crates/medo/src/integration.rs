@@ -0,0 +1,221 @@
+//! Integration of many groups' masters into one image.
+//!
+//! A target imaged over several nights is stacked as a group per night, and each night's master
+//! is framed slightly differently. Masters are registered to a common reference by the stars
+//! they share, then averaged, weighting each by its noise so that a hazy night does not spoil a
+//! clear one.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::entry::Entry;
+use medo_core::{warp, Error, Result};
+use medo_stacker::noise;
+use medo_stacker::stacker::average;
+
+use crate::group::Group;
+use crate::pipeline::alignment::{self, Aligner, Measurement};
+
+/// Fraction of the median noise of the masters that the noise of each is taken to be at least,
+/// when weighting them by noise.
+///
+/// A master with almost no noise, such as a clipped or synthetic one, would otherwise outweigh
+/// every other master.
+const MIN_RELATIVE_NOISE: f64 = 0.1;
+
+/// How masters are weighted when they are integrated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weighting {
+    /// Masters are weighted by the inverse of their noise variance.
+    Noise,
+    /// Masters are weighted equally.
+    Equal,
+}
+
+impl Default for Weighting {
+    #[inline]
+    fn default() -> Self {
+        Self::Noise
+    }
+}
+
+/// Integration options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Name of the group whose master the others are registered to.
+    ///
+    /// Defaults to the group whose master has the least noise.
+    pub reference: Option<String>,
+    /// Options used to register masters to the reference.
+    pub alignment: alignment::Opts,
+    /// How masters are weighted.
+    pub weighting: Weighting,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            reference: None,
+            alignment: alignment::Opts {
+                // Masters are only registered once
+                cache: false,
+                // Out-of-frame pixels are left out of the average
+                resampling: warp::Opts {
+                    invalid_border: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            weighting: Default::default(),
+        }
+    }
+}
+
+/// A group's master, as it was integrated.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// Name of the group.
+    pub group: String,
+    /// Estimated standard deviation of the noise in the group's master.
+    pub noise: f64,
+    /// Relative weight of the group's master, such that all weights sum to 1.
+    pub weight: f64,
+    /// How the group's master was registered, or `None` if it is the reference.
+    pub measurement: Option<Measurement>,
+}
+
+/// The outcome of integrating masters.
+#[derive(Debug)]
+pub struct Integration {
+    /// Name of the group whose master the others were registered to.
+    pub reference: String,
+    /// The masters that were integrated.
+    pub sessions: Vec<Session>,
+    /// Names of the groups whose masters could not be integrated, and why.
+    pub failed: Vec<(String, Error)>,
+    /// The integrated image.
+    pub image: Entry,
+}
+
+/// Register the masters of processed groups to a common reference, and integrate them.
+///
+/// Groups that have not been processed are left out. A master that cannot be registered is left
+/// out too, and recorded in [`Integration::failed`].
+pub fn integrate(groups: &[Group], opts: &Opts) -> Result<Integration> {
+    let span = tracing::info_span!("integration");
+    let _enter = span.enter();
+
+    // Read masters, named after their groups
+    let mut masters = vec![];
+    for group in groups {
+        let output = match &group.pipeline_output {
+            Some(o) => o,
+            None => {
+                tracing::warn!(group = %group.name, "group was not processed, leaving it out");
+                continue;
+            }
+        };
+        let image = output
+            .reference
+            .read_image()
+            .map_err(|e| e.with_entry(&group.name))?
+            .into_owned();
+        let noise = noise::estimate(&image).map_err(|e| e.with_entry(&group.name))?;
+        tracing::info!(group = %group.name, noise = %format!("{:.4}", noise), "read master");
+        masters.push((Entry::new_image(&group.name, image)?, noise));
+    }
+    if masters.is_empty() {
+        return Err(Error::OtherStatic("no masters to integrate"));
+    }
+
+    // Choose the reference
+    let reference = match &opts.reference {
+        Some(name) => masters
+            .iter()
+            .position(|(m, _)| m.name() == name.as_str())
+            .ok_or_else(|| Error::InvalidOption {
+                key: "reference".to_owned(),
+                reason: format!("no processed group is named `{}`", name),
+            })?,
+        None => masters
+            .iter()
+            .enumerate()
+            .min_by(|(_, (_, a)), (_, (_, b))| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            .map(|(i, _)| i)
+            .unwrap(),
+    };
+    let (reference, reference_noise) = masters.remove(reference);
+    let reference_name = reference.name().into_owned();
+    tracing::info!(reference = %reference_name, "registering masters");
+
+    // Register the other masters
+    let aligner = Aligner::new(&reference, opts.alignment.clone())?;
+    let mut registered = vec![(reference, reference_noise, None)];
+    let mut failed = vec![];
+    for (index, (master, noise)) in masters.into_iter().enumerate() {
+        let name = master.name().into_owned();
+        match aligner.align(master, index) {
+            Ok((entry, m)) => {
+                tracing::info!(
+                    group = %name,
+                    shift = %format!("({:.2}, {:.2})", m.shift_x, m.shift_y),
+                    rotation = %format!("{:.3}deg", m.rotation),
+                    rms_residual = %format!("{:.3}px", m.rms_residual),
+                    "registered master",
+                );
+                registered.push((entry, noise, Some(m)));
+            }
+            Err(e) => {
+                let e = e.with_entry(&name);
+                tracing::error!(error = %e, "failed to register master, leaving it out");
+                failed.push((name, e));
+            }
+        }
+    }
+
+    // Weigh and average
+    let mut noises = registered
+        .iter()
+        .map(|(_, noise, _)| *noise as f32)
+        .collect::<Vec<_>>();
+    let min_noise = noise::median(&mut noises)
+        .map_or(0.0, |median| median as f64 * MIN_RELATIVE_NOISE)
+        .max(f64::EPSILON);
+    let weights = registered
+        .iter()
+        .map(|(_, noise, _)| match opts.weighting {
+            Weighting::Noise => 1.0 / noise.max(min_noise).powi(2),
+            Weighting::Equal => 1.0,
+        })
+        .collect::<Vec<_>>();
+    let total = weights.iter().sum::<f64>();
+    let images = registered
+        .iter()
+        .map(|(entry, _, _)| entry.read_image())
+        .collect::<Result<Vec<_>>>()?;
+    let image = average::weighted(images.iter().map(|i| &**i).zip(weights.iter().copied()))?;
+
+    let sessions = registered
+        .iter()
+        .zip(&weights)
+        .map(|((entry, noise, measurement), weight)| Session {
+            group: entry.name().into_owned(),
+            noise: *noise,
+            weight: weight / total,
+            measurement: *measurement,
+        })
+        .collect::<Vec<_>>();
+    for s in &sessions {
+        tracing::info!(group = %s.group, weight = %format!("{:.3}", s.weight), "integrated master");
+    }
+
+    Ok(Integration {
+        image: Entry::new_image(&reference_name, image)?,
+        reference: reference_name,
+        sessions,
+        failed,
+    })
+}
@@ -1,5 +1,14 @@
 //! The main medo implementation.
 
+pub mod calibration;
 pub mod group;
+pub mod input;
+pub mod integration;
+pub mod live;
+pub mod metadata;
 pub mod pipeline;
+pub mod preview;
+pub mod project;
+pub mod reference;
 pub use medo_core as core;
+pub use medo_stacker as stacker;
@@ -0,0 +1,347 @@
+//! Live stacking of a directory that frames are captured into.
+//!
+//! The directory is polled for new files, rather than watched through the operating system,
+//! so that network shares and removable drives that capture software writes to are supported.
+//! A file is only read once its size and modification time stop changing between polls, so
+//! that frames are not read while they are being written.
+//!
+//! The first frame, or the frame given, is the reference. Every later frame is calibrated,
+//! aligned to the reference, and added to a running average.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv::core::Mat;
+use medo_core::entry::Entry;
+use medo_core::{warp, Result};
+use medo_stacker::noise;
+use medo_stacker::stacker::average;
+
+use crate::calibration::Masters;
+use crate::input;
+use crate::metadata::format_utc;
+use crate::pipeline::alignment::{self, Aligner};
+use crate::pipeline::{calibration, Context};
+
+/// Live stacking options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Frame that every other frame is aligned to.
+    ///
+    /// Defaults to the first frame found.
+    pub reference: Option<PathBuf>,
+    /// Which files of the directory are frames.
+    pub input: input::Opts,
+    /// Masters that frames are calibrated by.
+    pub calibration: calibration::Opts,
+    /// Options used to align frames to the reference.
+    pub alignment: alignment::Opts,
+    /// Time between polls of the directory.
+    #[serde(with = "secs")]
+    pub interval: Duration,
+    /// Files of the directory that are not frames, such as the stack itself.
+    pub ignore: Vec<PathBuf>,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            reference: None,
+            input: Default::default(),
+            calibration: Default::default(),
+            alignment: alignment::Opts {
+                // Frames are only aligned once
+                cache: false,
+                // Out-of-frame pixels are left out of the average
+                resampling: warp::Opts {
+                    invalid_border: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            interval: Duration::from_secs(2),
+            ignore: vec![],
+        }
+    }
+}
+
+/// (De)serialization of durations as seconds.
+mod secs {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(d.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(d)?;
+        if !(secs.is_finite() && secs >= 0.0) {
+            return Err(serde::de::Error::custom(
+                "must be a non-negative number of seconds",
+            ));
+        }
+        Ok(Duration::from_secs_f64(secs))
+    }
+}
+
+/// A frame that was stacked.
+#[derive(Debug, Clone, Serialize)]
+pub struct Frame {
+    /// Name of the frame.
+    pub name: String,
+    /// Time taken to calibrate, align and stack the frame, in seconds.
+    pub time_secs: f64,
+    /// Measurements of the frame's alignment, by name.
+    pub metrics: BTreeMap<String, f64>,
+}
+
+/// A frame that could not be stacked.
+#[derive(Debug, Clone, Serialize)]
+pub struct Rejection {
+    /// Name of the frame.
+    pub name: String,
+    /// Why the frame could not be stacked.
+    pub reason: String,
+}
+
+/// State of a live stack.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    /// Name of the reference frame.
+    pub reference: String,
+    /// Number of frames in the stack, including the reference.
+    pub stacked: usize,
+    /// Frames that could not be stacked.
+    pub rejected: Vec<Rejection>,
+    /// Estimated standard deviation of the noise in the stack.
+    pub noise: f64,
+    /// The frame that was stacked last.
+    pub last: Option<Frame>,
+    /// When the stack was last updated, in UTC.
+    pub updated: String,
+}
+
+/// Finds the files of a directory that were added since the last poll.
+#[derive(Debug)]
+pub struct Watcher {
+    dir: PathBuf,
+    opts: input::Opts,
+    /// Files that were already returned, or are ignored.
+    seen: HashSet<PathBuf>,
+    /// Size and modification time of files at the last poll, that are not yet returned.
+    pending: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+}
+
+impl Watcher {
+    /// Create a watcher of a directory.
+    ///
+    /// # Parameters
+    /// - `dir`: The directory to watch.
+    /// - `opts`: Which files of the directory are returned.
+    #[inline]
+    pub fn new<P: Into<PathBuf>>(dir: P, opts: input::Opts) -> Self {
+        Self {
+            dir: dir.into(),
+            opts,
+            seen: HashSet::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Never return a file.
+    #[inline]
+    pub fn ignore(&mut self, path: &Path) {
+        self.seen.insert(canonical(path));
+    }
+
+    /// Get the files that are new since the last poll, and were not written to since then.
+    ///
+    /// Files are ordered by path. New files are only returned by the poll after the one they
+    /// were first seen in.
+    pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+        let mut ready = vec![];
+        let mut pending = HashMap::new();
+        for path in input::list_dir(&self.dir, &self.opts)? {
+            let key = canonical(&path);
+            if self.seen.contains(&key) {
+                continue;
+            }
+            // Files may be removed between listing and reading their metadata
+            let metadata = match std::fs::metadata(&path) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            let state = (metadata.len(), metadata.modified().ok());
+            if state.0 > 0 && self.pending.get(&path) == Some(&state) {
+                self.seen.insert(key);
+                ready.push(path);
+            } else {
+                pending.insert(path, state);
+            }
+        }
+        self.pending = pending;
+        Ok(ready)
+    }
+}
+
+/// Resolve a path, so that different spellings of the same file compare equal.
+///
+/// Files that don't exist yet are resolved by their directory.
+fn canonical(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    match (dir.canonicalize(), path.file_name()) {
+        (Ok(dir), Some(name)) => dir.join(name),
+        _ => path.to_owned(),
+    }
+}
+
+/// Read and calibrate a frame, as 32-bit floating point.
+fn calibrate(path: PathBuf, masters: &Masters) -> Result<Entry> {
+    let entry = Entry::new_path_owned(path)?;
+    let image = masters.apply(entry.read_image_any_depth()?.as_ref())?;
+    Entry::new_image(entry.name(), image)
+}
+
+/// Wait for the next files of a directory, until work is requested to stop.
+///
+/// Returns `None` once work is requested to stop.
+fn wait(watcher: &mut Watcher, interval: Duration, ctx: &Context) -> Option<Vec<PathBuf>> {
+    loop {
+        if ctx.is_cancelled() {
+            return None;
+        }
+        match watcher.poll() {
+            Ok(files) if !files.is_empty() => return Some(files),
+            Ok(_) => {}
+            Err(e) => tracing::warn!(error = %e, "failed to list frames"),
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+/// Stack the frames of a directory as they are captured, until work is requested to stop.
+///
+/// Frames that are already in the directory are stacked first.
+///
+/// # Parameters
+/// - `dir`: The directory frames are captured into.
+/// - `opts`: Live stacking options.
+/// - `ctx`: The context to run in. Cancelling it ends the session successfully.
+/// - `on_update`: Called with the stack and its state after every frame, whether or not it
+///   could be stacked. Errors end the session.
+pub fn run<F>(dir: &Path, opts: &Opts, ctx: &Context, mut on_update: F) -> Result<Stats>
+where
+    F: FnMut(&Mat, &Stats) -> Result<()>,
+{
+    let span = tracing::info_span!("live", dir = %dir.display());
+    let _enter = span.enter();
+
+    let masters = opts.calibration.read_masters()?;
+    let mut watcher = Watcher::new(dir, opts.input.clone());
+    for path in &opts.ignore {
+        watcher.ignore(path);
+    }
+
+    // Find reference
+    let mut queue = vec![];
+    let reference = match &opts.reference {
+        Some(path) => {
+            watcher.ignore(path);
+            path.clone()
+        }
+        None => {
+            tracing::info!("waiting for the first frame");
+            queue = match wait(&mut watcher, opts.interval, ctx) {
+                Some(files) => files,
+                None => return Ok(Stats::default()),
+            };
+            queue.remove(0)
+        }
+    };
+    let reference = calibrate(reference.clone(), &masters)
+        .map_err(|e| e.with_entry(reference.to_string_lossy()))?;
+    let aligner = Aligner::new(&reference, opts.alignment.clone())?;
+    let mut stats = Stats {
+        reference: reference.name().into_owned(),
+        stacked: 1,
+        ..Default::default()
+    };
+    tracing::info!(reference = %stats.reference, "selected reference");
+    let mut stacker = average::Stacker::new(std::iter::once(Cow::Owned(reference)))?;
+    stats.noise = noise::estimate(stacker.image())?;
+    stats.updated = format_utc(SystemTime::now());
+    on_update(stacker.image(), &stats)?;
+
+    // Stack frames as they come
+    let mut index = 0;
+    loop {
+        for path in queue.drain(..) {
+            if ctx.is_cancelled() {
+                break;
+            }
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let start = std::time::Instant::now();
+            let result = calibrate(path, &masters)
+                .and_then(|e| aligner.align(e, index))
+                .and_then(|(e, m)| {
+                    stacker.add(e.read_image()?.as_ref())?;
+                    Ok(m)
+                });
+            index += 1;
+
+            match result {
+                Ok(m) => {
+                    stats.stacked += 1;
+                    stats.noise = noise::estimate(stacker.image())?;
+                    stats.last = Some(Frame {
+                        name: name.clone(),
+                        time_secs: start.elapsed().as_secs_f64(),
+                        metrics: m.metrics(),
+                    });
+                    tracing::info!(
+                        %name,
+                        stacked = stats.stacked,
+                        noise = stats.noise,
+                        "stacked frame"
+                    );
+                }
+                Err(e) => {
+                    tracing::error!(%name, error = %e, "failed to stack frame, discarding");
+                    stats.rejected.push(Rejection {
+                        name,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            stats.updated = format_utc(SystemTime::now());
+            on_update(stacker.image(), &stats)?;
+        }
+
+        queue = match wait(&mut watcher, opts.interval, ctx) {
+            Some(files) => files,
+            None => break,
+        };
+    }
+    tracing::info!(
+        stacked = stats.stacked,
+        rejected = stats.rejected.len(),
+        "finished live stack"
+    );
+    Ok(stats)
+}
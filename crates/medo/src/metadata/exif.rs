@@ -0,0 +1,254 @@
+//! Reading of EXIF and TIFF headers.
+//!
+//! Cameras record when each frame was captured, and for how long, in EXIF tags. JPEG files carry
+//! them in an APP1 segment, and TIFF files, which most raw formats are, in their image file
+//! directories. Only the tags that metadata is read from are decoded.
+
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Time the image was last changed, which is usually when it was captured.
+const TAG_DATE_TIME: u16 = 306;
+/// Exposure time, in seconds.
+const TAG_EXPOSURE_TIME: u16 = 33434;
+/// Offset of the EXIF directory.
+const TAG_EXIF_IFD: u16 = 34665;
+/// Time the image was captured.
+const TAG_DATE_TIME_ORIGINAL: u16 = 36867;
+
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+
+/// Longest string that is read from a tag. Dates take 20 bytes.
+const MAX_ASCII_LEN: u32 = 64;
+
+/// Capture metadata read from a header.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Exif {
+    /// Time the image was captured at.
+    ///
+    /// EXIF times have no time zone, and are taken to be in UTC.
+    pub capture_time: Option<SystemTime>,
+    /// Exposure time.
+    pub exposure: Option<Duration>,
+}
+
+/// Read capture metadata from the header of a JPEG or TIFF file.
+///
+/// Files of other formats, and files whose header can't be read, have no metadata.
+pub fn read(path: &Path) -> Exif {
+    read_file(path).unwrap_or_else(|e| {
+        tracing::debug!(path = %path.display(), error = %e, "failed to read EXIF header");
+        Exif::default()
+    })
+}
+
+fn read_file(path: &Path) -> io::Result<Exif> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    match magic {
+        [b'I', b'I', 42, 0] | [b'M', b'M', 0, 42] => Tiff::new(file)?.read(),
+        [0xff, 0xd8, ..] => match jpeg_exif(&mut file)? {
+            Some(segment) => Tiff::new(Cursor::new(segment))?.read(),
+            None => Ok(Exif::default()),
+        },
+        _ => Ok(Exif::default()),
+    }
+}
+
+#[inline]
+fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid EXIF header")
+}
+
+/// Get the TIFF header and directories of a JPEG file's EXIF segment, if it has one.
+fn jpeg_exif<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    // Start of image
+    let mut marker = [0; 2];
+    reader.read_exact(&mut marker)?;
+    loop {
+        reader.read_exact(&mut marker)?;
+        match marker {
+            [0xff, 0xd0..=0xd8 | 0x01] => continue,
+            // Metadata comes before the image data
+            [0xff, 0xd9 | 0xda] => return Ok(None),
+            [0xff, _] => {}
+            _ => return Err(invalid()),
+        }
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        let len = usize::from(u16::from_be_bytes(len))
+            .checked_sub(2)
+            .ok_or_else(invalid)?;
+        let mut segment = vec![0; len];
+        reader.read_exact(&mut segment)?;
+        if marker[1] == 0xe1 && segment.starts_with(b"Exif\0\0") {
+            segment.drain(..6);
+            return Ok(Some(segment));
+        }
+    }
+}
+
+/// An entry of an image file directory.
+#[derive(Debug, Clone, Copy)]
+struct Field {
+    tag: u16,
+    typ: u16,
+    count: u32,
+    /// The value if it fits in 4 bytes, or else its offset.
+    value: [u8; 4],
+}
+
+/// A TIFF header and the directories it points to.
+struct Tiff<R> {
+    reader: R,
+    big_endian: bool,
+}
+
+impl<R: Read + Seek> Tiff<R> {
+    /// Read the header at the start of a reader.
+    fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 4];
+        reader.read_exact(&mut header)?;
+        let big_endian = match header {
+            [b'I', b'I', 42, 0] => false,
+            [b'M', b'M', 0, 42] => true,
+            _ => return Err(invalid()),
+        };
+        Ok(Self { reader, big_endian })
+    }
+
+    #[inline]
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut b = [0; 2];
+        self.reader.read_exact(&mut b)?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        })
+    }
+
+    #[inline]
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        self.reader.read_exact(&mut b)?;
+        Ok(self.decode_u32(b))
+    }
+
+    #[inline]
+    fn decode_u32(&self, b: [u8; 4]) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    /// Read the entries of the directory at an offset from the header.
+    fn directory(&mut self, offset: u32) -> io::Result<Vec<Field>> {
+        self.reader.seek(SeekFrom::Start(offset.into()))?;
+        let count = self.u16()?;
+        (0..count)
+            .map(|_| {
+                let (tag, typ, count) = (self.u16()?, self.u16()?, self.u32()?);
+                let mut value = [0; 4];
+                self.reader.read_exact(&mut value)?;
+                Ok(Field {
+                    tag,
+                    typ,
+                    count,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Read the value of a string field, up to its first null.
+    fn ascii(&mut self, field: &Field) -> io::Result<Option<String>> {
+        if field.typ != TYPE_ASCII || field.count > MAX_ASCII_LEN {
+            return Ok(None);
+        }
+        let mut bytes = vec![0; field.count as usize];
+        if field.count <= 4 {
+            bytes.copy_from_slice(&field.value[..bytes.len()]);
+        } else {
+            let offset = self.decode_u32(field.value);
+            self.reader.seek(SeekFrom::Start(offset.into()))?;
+            self.reader.read_exact(&mut bytes)?;
+        }
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        bytes.truncate(len);
+        Ok(String::from_utf8(bytes).ok())
+    }
+
+    /// Read the first value of a rational field.
+    fn rational(&mut self, field: &Field) -> io::Result<Option<f64>> {
+        if field.typ != TYPE_RATIONAL || field.count == 0 {
+            return Ok(None);
+        }
+        let offset = self.decode_u32(field.value);
+        self.reader.seek(SeekFrom::Start(offset.into()))?;
+        let (numerator, denominator) = (self.u32()?, self.u32()?);
+        Ok((denominator != 0).then(|| f64::from(numerator) / f64::from(denominator)))
+    }
+
+    /// Read capture metadata from the first directory, and the EXIF directory it points to.
+    fn read(mut self) -> io::Result<Exif> {
+        let offset = self.u32()?;
+        let mut date_time = None;
+        let mut exif_offset = None;
+        for field in self.directory(offset)? {
+            match field.tag {
+                TAG_DATE_TIME => date_time = self.ascii(&field)?,
+                TAG_EXIF_IFD if field.typ == TYPE_LONG => {
+                    exif_offset = Some(self.decode_u32(field.value))
+                }
+                _ => {}
+            }
+        }
+
+        let mut exif = Exif::default();
+        let mut date_time_original = None;
+        if let Some(offset) = exif_offset {
+            for field in self.directory(offset)? {
+                match field.tag {
+                    TAG_DATE_TIME_ORIGINAL => date_time_original = self.ascii(&field)?,
+                    TAG_EXPOSURE_TIME => {
+                        exif.exposure = self
+                            .rational(&field)?
+                            .filter(|secs| secs.is_finite() && *secs > 0.0)
+                            .map(Duration::from_secs_f64)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        exif.capture_time = date_time_original
+            .or(date_time)
+            .and_then(|s| parse_date_time(&s));
+        Ok(exif)
+    }
+}
+
+/// Parse an EXIF date and time, `YYYY:MM:DD HH:MM:SS`.
+fn parse_date_time(s: &str) -> Option<SystemTime> {
+    let (date, time) = s.trim().split_once(' ')?;
+    let parse = |s: &str| {
+        s.split(':')
+            .map(|v| v.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()
+    };
+    let (date, time) = (parse(date)?, parse(time)?);
+    match (&date[..], &time[..]) {
+        (&[year, month, day], &[hour, minute, second]) => {
+            super::time_from_civil(year as i64, month, day, hour, minute, second)
+        }
+        _ => None,
+    }
+}
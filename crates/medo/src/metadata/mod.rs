@@ -0,0 +1,301 @@
+//! Acquisition metadata of entries.
+//!
+//! Images are read through OpenCV, which does not expose FITS or EXIF headers, so most metadata
+//! is parsed from file and directory names instead. Capture software names files with tokens such
+//! as `Light_M42_Ha_300.0s_Bin1_20231012-221530_0001.fits`, separated by underscores or spaces.
+//! The capture time and exposure of JPEG and TIFF files are read from their EXIF headers.
+
+use std::fmt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use medo_core::entry::Entry;
+
+mod exif;
+
+/// Filters that are recognized in names, and the names they are normalized to.
+const FILTERS: &[(&[&str], &str)] = &[
+    (&["l", "lum", "luminance", "clear"], "L"),
+    (&["r", "red"], "R"),
+    (&["g", "green"], "G"),
+    (&["b", "blue"], "B"),
+    (&["ha", "halpha", "h-alpha"], "Ha"),
+    (&["oiii", "o3"], "OIII"),
+    (&["sii", "s2"], "SII"),
+];
+
+/// Acquisition metadata of an entry.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    /// Filter the entry was captured through.
+    pub filter: Option<String>,
+    /// Exposure time.
+    pub exposure: Option<Duration>,
+    /// Pixel binning factor.
+    pub binning: Option<u32>,
+    /// The night the entry was captured in, as `YYYY-MM-DD`.
+    ///
+    /// Entries captured after midnight belong to the previous night.
+    pub session: Option<String>,
+    /// Time the entry was captured at.
+    ///
+    /// This is read from the entry's header, then from a date and time in its name, such as
+    /// `20231012-221530`, and is otherwise the modification time of its file.
+    pub capture_time: Option<SystemTime>,
+}
+
+impl Metadata {
+    /// Read the metadata of an entry.
+    ///
+    /// Values read from the entry's header are preferred to those in names.
+    pub fn read(entry: &Entry) -> Self {
+        let path = entry_path(entry);
+        let mut metadata = path.map_or_else(Self::default, |p| {
+            let exif = exif::read(p);
+            Self {
+                exposure: exif.exposure,
+                capture_time: exif.capture_time,
+                ..Default::default()
+            }
+        });
+        metadata.fill(Self::from_name(&entry.name()));
+        if let Some(path) = path {
+            // Directories are often named after the filter, or the night
+            for dir in path.ancestors().skip(1).take(2) {
+                if let Some(name) = dir.file_name() {
+                    metadata.fill(Self::from_name(&name.to_string_lossy()));
+                }
+            }
+        }
+        if metadata.capture_time.is_none() {
+            metadata.capture_time =
+                path.and_then(|p| std::fs::metadata(p).and_then(|m| m.modified()).ok());
+        }
+        if metadata.session.is_none() {
+            metadata.session = metadata.capture_time.map(night_of);
+        }
+        metadata
+    }
+
+    /// Parse metadata from a file or directory name.
+    pub fn from_name(name: &str) -> Self {
+        // Only the extension is removed, since exposures contain dots
+        let stem = Path::new(name)
+            .extension()
+            .filter(|e| e.to_string_lossy().chars().all(char::is_alphabetic))
+            .map_or(name, |e| &name[..name.len() - e.len() - 1]);
+
+        let mut metadata = Self::default();
+        for token in stem.split(|c: char| c == '_' || c.is_whitespace()) {
+            let lower = token.to_ascii_lowercase();
+            let value = lower
+                .strip_prefix("filter")
+                .map(|v| v.trim_start_matches(&['-', '='][..]))
+                .unwrap_or(&lower);
+
+            if let Some((_, filter)) = FILTERS.iter().find(|(names, _)| names.contains(&value)) {
+                metadata.filter.get_or_insert_with(|| filter.to_string());
+            } else if let Some(exposure) = parse_exposure(&lower) {
+                metadata.exposure.get_or_insert(exposure);
+            } else if let Some(binning) = parse_binning(&lower) {
+                metadata.binning.get_or_insert(binning);
+            } else if let Some(date) = parse_date(&lower) {
+                metadata.session.get_or_insert(date);
+                if let Some(time) = parse_date_time(&lower) {
+                    metadata.capture_time.get_or_insert(time);
+                }
+            }
+        }
+        metadata
+    }
+
+    /// Fill unknown values from other metadata.
+    fn fill(&mut self, other: Self) {
+        self.filter = self.filter.take().or(other.filter);
+        self.exposure = self.exposure.or(other.exposure);
+        self.binning = self.binning.or(other.binning);
+        self.session = self.session.take().or(other.session);
+        self.capture_time = self.capture_time.or(other.capture_time);
+    }
+}
+
+impl fmt::Display for Metadata {
+    /// Format the known values, e.g. `Ha_300s_bin1_2023-10-12`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(filter) = &self.filter {
+            parts.push(filter.clone());
+        }
+        if let Some(exposure) = self.exposure {
+            parts.push(format!("{}s", exposure.as_secs_f64()));
+        }
+        if let Some(binning) = self.binning {
+            parts.push(format!("bin{}", binning));
+        }
+        if let Some(session) = &self.session {
+            parts.push(session.clone());
+        }
+        write!(f, "{}", parts.join("_"))
+    }
+}
+
+/// Get the path of the file an entry is read from.
+fn entry_path(entry: &Entry) -> Option<&Path> {
+    match entry {
+        Entry::Path(p) => Some(p.path()),
+        Entry::Image(_) => None,
+        Entry::Warped(w) => entry_path(w.entry()),
+    }
+}
+
+/// Get the time an entry was captured at, if it is known.
+///
+/// See [`Metadata::capture_time`].
+#[inline]
+pub fn capture_time(entry: &Entry) -> Option<SystemTime> {
+    Metadata::read(entry).capture_time
+}
+
+/// Parse an exposure time such as `300s`, `300.0s`, `1.5sec` or `500ms`.
+fn parse_exposure(token: &str) -> Option<Duration> {
+    let (value, scale) = if let Some(v) = token.strip_suffix("ms") {
+        (v, 1e-3)
+    } else if let Some(v) = token
+        .strip_suffix("sec")
+        .or_else(|| token.strip_suffix('s'))
+    {
+        (v, 1.0)
+    } else {
+        return None;
+    };
+    let secs = value.parse::<f64>().ok()? * scale;
+    (secs.is_finite() && secs > 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+/// Parse a binning factor such as `bin2`, `bin-2` or `2x2`.
+fn parse_binning(token: &str) -> Option<u32> {
+    if let Some(v) = token.strip_prefix("bin") {
+        return v.trim_start_matches(&['-', '='][..]).parse().ok();
+    }
+    let (x, y) = token.split_once('x')?;
+    let (x, y) = (x.parse::<u32>().ok()?, y.parse::<u32>().ok()?);
+    (x == y && x > 0).then(|| x)
+}
+
+/// Parse a date such as `20231012`, `2023-10-12` or `20231012-221530` into `YYYY-MM-DD`.
+fn parse_date(token: &str) -> Option<String> {
+    let digits = token
+        .chars()
+        .filter(|c| *c != '-')
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    if digits.len() < 8 {
+        return None;
+    }
+    let (year, month, day) = (&digits[0..4], &digits[4..6], &digits[6..8]);
+    let valid = year.starts_with("19") || year.starts_with("20");
+    let valid = valid && (1..=12).contains(&month.parse::<u32>().ok()?);
+    let valid = valid && (1..=31).contains(&day.parse::<u32>().ok()?);
+    valid.then(|| format!("{}-{}-{}", year, month, day))
+}
+
+/// Parse a date and time such as `20231012-221530` or `20231012221530`.
+fn parse_date_time(token: &str) -> Option<SystemTime> {
+    let digits = token
+        .chars()
+        .filter(|c| *c != '-')
+        .take_while(char::is_ascii_digit)
+        .collect::<String>();
+    if digits.len() < 14 {
+        return None;
+    }
+    let value = |range: std::ops::Range<usize>| digits[range].parse::<u32>().ok();
+    time_from_civil(
+        value(0..4)?.into(),
+        value(4..6)?,
+        value(6..8)?,
+        value(8..10)?,
+        value(10..12)?,
+        value(12..14)?,
+    )
+}
+
+/// Get the night a time falls in, as `YYYY-MM-DD` in UTC.
+///
+/// Nights are shifted by 12 hours, so that a night's session does not change at midnight.
+fn night_of(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        .saturating_sub(12 * 60 * 60);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Format a time as `YYYY-MM-DDTHH:MM:SSZ`, in UTC.
+pub fn format_utc(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let secs = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Convert a calendar date and time, in UTC, to a time.
+///
+/// Returns `None` if the date or time is invalid, or before the unix epoch.
+fn time_from_civil(
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+) -> Option<SystemTime> {
+    let valid = (1..=12).contains(&month) && (1..=31).contains(&day);
+    let valid = valid && hour < 24 && minute < 60 && second < 61;
+    if !valid {
+        return None;
+    }
+    let secs =
+        days_from_civil(year, month, day) * 86_400 + i64::from(hour * 3600 + minute * 60 + second);
+    u64::try_from(secs)
+        .ok()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Convert a calendar date to days since the unix epoch.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Convert days since the unix epoch to a calendar date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
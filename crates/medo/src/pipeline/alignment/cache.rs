@@ -0,0 +1,120 @@
+//! Persistent cache of alignment results.
+//!
+//! An alignment result depends on the content of the aligned image, the content of the
+//! reference and the options that were used to compute it. All three are hashed to identify a
+//! cached transform, so that renamed or moved files are still found.
+
+use std::fmt;
+use std::hash::Hasher;
+use std::io;
+use std::path::PathBuf;
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar};
+use medo_core::entry::Entry;
+use medo_core::util::{self, ContentHasher};
+use medo_core::Result;
+
+use super::Opts;
+
+/// Identifies a cached alignment result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key(u64);
+
+impl fmt::Display for Key {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// A directory of cached transforms.
+#[derive(Debug, Clone)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    /// Create a cache stored in the given directory.
+    #[inline]
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The default location of the cache.
+    #[inline]
+    pub fn default_dir() -> PathBuf {
+        util::cache_dir().join("alignment")
+    }
+
+    /// Compute the key of an entry's alignment result.
+    pub fn key(entry: &Entry, reference_hash: u64, opts: &Opts) -> Result<Key> {
+        let mut hasher = ContentHasher::default();
+        hasher.write_u64(entry.content_hash()?);
+        hasher.write_u64(reference_hash);
+        // Options that affect the calculated transform
+        let detection = &opts.star_detection;
+        hasher.write_u32(detection.star_detection.max_area.to_bits());
+        hasher.write_u32(detection.star_detection.max_eccentricity.to_bits());
+        hasher.write_u32(detection.threshold_brightness.to_bits());
+        hasher.write_u32(detection.max_brightness.to_bits());
+        hasher.write_i32(detection.blur_amount);
+        hasher.write_u64(opts.homography.iterations as u64);
+        hasher.write_u64(opts.homography.epsilon.to_bits());
+        Ok(Key(hasher.finish()))
+    }
+
+    #[inline]
+    fn path(&self, key: Key) -> PathBuf {
+        self.dir.join(format!("{}.txt", key))
+    }
+
+    /// Get a cached transform.
+    pub fn get(&self, key: Key) -> Option<Mat> {
+        let contents = std::fs::read_to_string(self.path(key)).ok()?;
+        let values = contents
+            .split_whitespace()
+            .map(|v| v.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .ok()?;
+        if values.len() != 9 {
+            tracing::warn!(%key, "ignoring malformed cached alignment");
+            return None;
+        }
+
+        let mut transform =
+            Mat::new_rows_cols_with_default(3, 3, cv::core::CV_32F, Scalar::all(0.0)).ok()?;
+        for (i, v) in values.into_iter().enumerate() {
+            *transform
+                .at_2d_mut::<f32>(i as i32 / 3, i as i32 % 3)
+                .ok()? = v;
+        }
+        Some(transform)
+    }
+
+    /// Cache a transform.
+    pub fn insert(&self, key: Key, transform: &Mat) -> Result<()> {
+        let mut transform_f32 = Mat::default();
+        transform.convert_to(&mut transform_f32, cv::core::CV_32F, 1.0, 0.0)?;
+
+        let mut contents = String::new();
+        for i in 0..3 {
+            for j in 0..3 {
+                contents.push_str(&format!("{} ", transform_f32.at_2d::<f32>(i, j)?));
+            }
+            contents.push('\n');
+        }
+
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(key), contents)?;
+        Ok(())
+    }
+
+    /// Remove all cached transforms.
+    pub fn clear(&self) -> Result<()> {
+        match std::fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
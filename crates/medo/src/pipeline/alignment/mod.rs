@@ -0,0 +1,364 @@
+//! Implementation of the alignment stage.
+
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConstManual, Size};
+use medo_core::entry::{Entries, Entry, OwnedEntryIter, Warped};
+use medo_core::util::{self, WorkDir};
+use medo_core::{warp, Error, Result};
+use medo_stacker::homography;
+use medo_stacker::star;
+
+use super::{Context, InvalidOption, Stage};
+
+mod cache;
+pub mod report;
+pub use cache::{Cache, Key};
+pub use report::{Measurement, Record};
+
+/// Fewest stars an image needs to be aligned by.
+const MIN_STARS: usize = 4;
+
+/// Type of the star masks that images are aligned by, whatever their own type.
+const MASK_TYPE: i32 = cv::core::CV_8UC3;
+
+/// Fail if too few stars were detected to align by.
+fn check_stars(stars: &[star::Circle]) -> Result<()> {
+    if stars.len() < MIN_STARS {
+        return Err(Error::InsufficientStars {
+            found: stars.len(),
+            required: MIN_STARS,
+        });
+    }
+    Ok(())
+}
+
+/// How aligned entries are output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    /// Entries carry their transform, and are resampled by whichever stage reads them.
+    Transform,
+    /// Entries are resampled and written to a working directory.
+    ///
+    /// The working directory is unique to each run, and is removed once no entries refer to it.
+    Disk,
+}
+
+impl Default for Output {
+    #[inline]
+    fn default() -> Self {
+        Self::Transform
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Options used to detect the stars that images are aligned by.
+    pub star_detection: star::ContourDetectionOpts,
+    /// Options used to calculate each image's homography.
+    pub homography: homography::CalculateOpts,
+    /// Options used to resample aligned images.
+    pub resampling: warp::Opts,
+    /// Whether alignment results are cached.
+    pub cache: bool,
+    /// Directory in which alignment results are cached.
+    ///
+    /// Defaults to [`Cache::default_dir`].
+    pub cache_dir: Option<PathBuf>,
+    /// How aligned entries are output.
+    pub output: Output,
+    /// Directory in which working directories are created.
+    ///
+    /// Defaults to the system's temporary directory.
+    pub work_dir: Option<PathBuf>,
+    /// File to write an alignment report to, as JSON or CSV depending on its extension.
+    pub report: Option<PathBuf>,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            star_detection: Default::default(),
+            homography: Default::default(),
+            resampling: Default::default(),
+            cache: true,
+            cache_dir: None,
+            output: Default::default(),
+            work_dir: None,
+            report: None,
+        }
+    }
+}
+
+impl Opts {
+    /// Get the alignment cache these options refer to.
+    #[inline]
+    pub fn open_cache(&self) -> Option<Cache> {
+        self.cache
+            .then(|| Cache::new(self.cache_dir.clone().unwrap_or_else(Cache::default_dir)))
+    }
+
+    /// Check that these options are usable.
+    pub fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        let detection = &self.star_detection;
+        if detection.blur_amount < 1 || detection.blur_amount % 2 == 0 {
+            return Err(InvalidOption::new(
+                "star_detection.blur_amount",
+                "must be a positive odd number",
+            ));
+        }
+        if !(0.0..=detection.max_brightness).contains(&detection.threshold_brightness) {
+            return Err(InvalidOption::new(
+                "star_detection.threshold_brightness",
+                "must be between 0 and `max_brightness`",
+            ));
+        }
+        if self.homography.iterations == 0 {
+            return Err(InvalidOption::new(
+                "homography.iterations",
+                "must be greater than 0",
+            ));
+        }
+        if self.homography.epsilon <= 0.0 {
+            return Err(InvalidOption::new(
+                "homography.epsilon",
+                "must be greater than 0",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Aligns entries to a reference.
+pub struct Aligner {
+    opts: Opts,
+    calculator: homography::Calculator,
+    reference_stars: Vec<star::Circle>,
+    reference_size: Size,
+    reference_hash: u64,
+    cache: Option<Cache>,
+    work_dir: Option<Arc<WorkDir>>,
+}
+
+impl Aligner {
+    /// Create an aligner for the given reference.
+    pub fn new(reference: &Entry, opts: Opts) -> Result<Self> {
+        Self::new_inner(reference, opts).map_err(|e| e.with_entry(reference.name()))
+    }
+
+    fn new_inner(reference: &Entry, opts: Opts) -> Result<Self> {
+        // Aligned images are only written to disk if asked to
+        let work_dir = match opts.output {
+            Output::Transform => None,
+            Output::Disk => Some(Arc::new(match &opts.work_dir {
+                Some(p) => WorkDir::new_in(p)?,
+                None => WorkDir::new()?,
+            })),
+        };
+
+        // Create alignment calculator
+        let first = reference.read_image()?;
+        let first_stars = star::find_contours(&first, opts.star_detection)?.collect::<Vec<_>>();
+        check_stars(&first_stars)?;
+        let first_mask = star::create_mask(first.size()?, MASK_TYPE, first_stars.iter().copied())?;
+        let calculator = homography::Calculator::new(&first_mask)?;
+
+        // Previously calculated alignments are looked up by content
+        let cache = opts.open_cache();
+        let reference_hash = match cache {
+            Some(_) => reference.content_hash()?,
+            None => 0,
+        };
+
+        Ok(Self {
+            calculator,
+            reference_stars: first_stars,
+            reference_size: first.size()?,
+            reference_hash,
+            cache,
+            work_dir,
+            opts,
+        })
+    }
+
+    /// Get the options this aligner was created with.
+    #[inline]
+    pub fn opts(&self) -> &Opts {
+        &self.opts
+    }
+
+    /// Calculate the transform from an entry to the reference.
+    ///
+    /// Stars detected in the entry are returned if they were needed to calculate the transform.
+    fn transform(&self, entry: &Entry, name: &str) -> Result<(Mat, Option<Vec<star::Circle>>)> {
+        // Find cached alignment
+        let key = match &self.cache {
+            Some(_) => Some(Cache::key(entry, self.reference_hash, &self.opts)?),
+            None => None,
+        };
+        if let Some(warp) = self.cache.as_ref().zip(key).and_then(|(c, k)| c.get(k)) {
+            tracing::debug!(%name, "using cached alignment");
+            return Ok((warp, None));
+        }
+
+        // Create mask
+        let image = entry.read_image()?;
+        let stars = star::find_contours(&image, self.opts.star_detection)?.collect::<Vec<_>>();
+        check_stars(&stars)?;
+        let mask = star::create_mask(image.size()?, MASK_TYPE, stars.iter().copied())?;
+        // Align
+        let warp = self.calculator.calculate(&mask, self.opts.homography)?;
+        if let Some((cache, key)) = self.cache.as_ref().zip(key) {
+            if let Err(e) = cache.insert(key, &warp) {
+                tracing::warn!(%name, error = %e, "failed to cache alignment");
+            }
+        }
+        Ok((warp, Some(stars)))
+    }
+
+    /// Align an entry.
+    ///
+    /// # Parameters
+    /// - `entry`: The entry to align.
+    /// - `index`: A number that is unique to this entry among the entries being aligned.
+    pub fn align(&self, entry: Entry, index: usize) -> Result<(Entry, Measurement)> {
+        let name = entry.name().into_owned();
+        let (warp, stars) = self.transform(&entry, &name)?;
+
+        // Measure alignment
+        let stars = match stars {
+            Some(s) => s,
+            None => star::find_contours(&entry.read_image()?, self.opts.star_detection)?.collect(),
+        };
+        let measurement = Measurement::new(&warp, &stars, &self.reference_stars)?;
+
+        let warped = Warped::new(entry, warp, self.reference_size, self.opts.resampling);
+        let out = match &self.work_dir {
+            // Resample and write, each entry in its own directory in case names collide
+            Some(work_dir) => {
+                let mut out_path = work_dir.path().to_owned();
+                out_path.push(index.to_string());
+                out_path.push(format!("{}.tif", name));
+                util::write_image(&out_path, &warped.read_image()?)?;
+                Entry::new_path_in_work_dir(out_path, work_dir.clone())?
+            }
+            // Resampled lazily by the consumer
+            None => Entry::Warped(warped),
+        };
+        Ok((out, measurement))
+    }
+}
+
+pub fn process<'scope>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    opts: &Opts,
+    ctx: &Context,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+    let aligner = Aligner::new(&input.reference, opts.clone())?;
+
+    // Align images
+    let results = input
+        .entries
+        .enumerate()
+        .par_bridge()
+        .into_par_iter()
+        .filter_map(|(index, e)| {
+            // Skip remaining entries once cancelled
+            if ctx.is_cancelled() {
+                return None;
+            }
+            let span = tracing::info_span!("stage_alignment");
+            let _enter = span.enter();
+
+            let name = e.name().into_owned();
+            tracing::info!(%name); // alignment takes a long time, so info is useful
+                                   // Start
+            let start = std::time::Instant::now();
+            let result = aligner
+                .align(e.into_owned(), index)
+                .map_err(|e| e.with_entry(&name));
+            let time = start.elapsed();
+            // Done
+            if let Ok((_, m)) = &result {
+                ctx.entry_measured(&name, m.metrics());
+            }
+            ctx.entry_processed(&name, time, result.as_ref().err());
+            match &result {
+                Ok((_, m)) => tracing::info!(
+                    %name,
+                    time = %format!("{}s", time.as_secs()),
+                    shift = %format!("({:.2}, {:.2})", m.shift_x, m.shift_y),
+                    rotation = %format!("{:.3}deg", m.rotation),
+                    matched_stars = %format!("{}/{}", m.matched_stars, m.stars),
+                    rms_residual = %format!("{:.3}px", m.rms_residual),
+                    "finished",
+                ),
+                Err(e) => tracing::error!(%name, error = %e, "failed to align entry, discarding"),
+            }
+            Some((name, time, result))
+        })
+        .collect::<Vec<_>>();
+    // Aligned images written to disk are removed along with the aligner's working directory
+    ctx.check_cancelled()?;
+
+    // Report
+    let mut images = Vec::with_capacity(results.len());
+    let mut records = Vec::with_capacity(results.len());
+    for (name, time, result) in results {
+        let outcome = match result {
+            Ok((entry, measurement)) => {
+                images.push(Cow::Owned(entry));
+                Ok(measurement)
+            }
+            Err(e) => Err(e.kind().to_string()),
+        };
+        records.push(Record {
+            name,
+            time,
+            outcome,
+        });
+    }
+    if let Some(path) = &opts.report {
+        report::write(path, &records)?;
+        tracing::info!(report = %path.display(), "wrote alignment report");
+    }
+
+    Ok(Entries {
+        reference: input.reference,
+        entries: Box::new(images.into_iter()),
+    })
+}
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "alignment"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    #[inline]
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        Opts::validate(self)
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
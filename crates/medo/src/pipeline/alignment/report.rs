@@ -0,0 +1,213 @@
+//! Alignment quality reports.
+//!
+//! Each aligned entry is described by the parameters of its transform, and by how well its
+//! stars land on the reference's stars once transformed. Trends in these values point to
+//! flexure, field rotation or bad frames.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use medo_core::cv::core::Mat;
+use medo_core::{warp, Error, Result};
+use medo_stacker::star::Circle;
+
+/// Smallest residual, in pixels, up to which a transformed star is matched to its nearest
+/// reference star.
+///
+/// Stars are matched if their residual is at most the reference star's radius, or this for
+/// small stars.
+const MIN_MATCH_TOLERANCE: f32 = 2.0;
+
+/// Measurements of a single alignment.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Measurement {
+    /// Horizontal shift, in pixels.
+    pub shift_x: f64,
+    /// Vertical shift, in pixels.
+    pub shift_y: f64,
+    /// Rotation, in degrees.
+    pub rotation: f64,
+    /// Scale factor.
+    pub scale: f64,
+    /// Number of stars detected in the entry.
+    pub stars: usize,
+    /// Number of stars that land on a reference star once transformed.
+    pub matched_stars: usize,
+    /// Root mean square distance between matched stars, in pixels.
+    ///
+    /// This is `NaN` if no stars were matched.
+    pub rms_residual: f64,
+}
+
+impl Measurement {
+    /// Measure an alignment.
+    ///
+    /// # Parameters
+    /// - `transform`: The transform from the entry to the reference.
+    /// - `stars`: Stars detected in the entry.
+    /// - `reference_stars`: Stars detected in the reference.
+    pub fn new(transform: &Mat, stars: &[Circle], reference_stars: &[Circle]) -> Result<Self> {
+        let t = warp::transform_to_array(transform)?;
+
+        // Decompose the affine part of the transform
+        let scale = (t[0] * t[4] - t[1] * t[3]).abs().sqrt() / t[8].abs();
+        let rotation = (t[3] - t[1]).atan2(t[0] + t[4]).to_degrees();
+
+        // Match transformed stars to their nearest reference star
+        let mut matched_stars = 0;
+        let mut squared_error = 0.0;
+        for star in stars {
+            let (x, y) = (star.center.x as f64, star.center.y as f64);
+            let w = t[6] * x + t[7] * y + t[8];
+            let tx = ((t[0] * x + t[1] * y + t[2]) / w) as f32;
+            let ty = ((t[3] * x + t[4] * y + t[5]) / w) as f32;
+
+            let nearest = reference_stars
+                .iter()
+                .map(|r| {
+                    let d2 = (r.center.x - tx).powi(2) + (r.center.y - ty).powi(2);
+                    (d2, r.radius.max(MIN_MATCH_TOLERANCE))
+                })
+                // Degenerate transforms put stars nowhere
+                .filter(|(d2, _)| d2.is_finite())
+                .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+            if let Some((d2, tolerance)) = nearest {
+                if d2 <= tolerance.powi(2) {
+                    matched_stars += 1;
+                    squared_error += d2 as f64;
+                }
+            }
+        }
+
+        Ok(Self {
+            shift_x: t[2] / t[8],
+            shift_y: t[5] / t[8],
+            rotation,
+            scale,
+            stars: stars.len(),
+            matched_stars,
+            rms_residual: (squared_error / matched_stars as f64).sqrt(),
+        })
+    }
+
+    /// Get the measurements by name.
+    pub fn metrics(&self) -> BTreeMap<String, f64> {
+        [
+            ("shift_x", self.shift_x),
+            ("shift_y", self.shift_y),
+            ("rotation", self.rotation),
+            ("scale", self.scale),
+            ("stars", self.stars as f64),
+            ("matched_stars", self.matched_stars as f64),
+            ("rms_residual", self.rms_residual),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect()
+    }
+}
+
+/// Alignment result of an entry.
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// Name of the entry.
+    pub name: String,
+    /// Time taken to align the entry.
+    pub time: Duration,
+    /// Measurements of the alignment, or the reason it failed.
+    pub outcome: std::result::Result<Measurement, String>,
+}
+
+/// A record as it is written, flattened to a row.
+#[derive(Serialize)]
+struct Row<'a> {
+    name: &'a str,
+    time_secs: f64,
+    #[serde(flatten)]
+    measurement: Option<&'a Measurement>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl<'a> From<&'a Record> for Row<'a> {
+    fn from(r: &'a Record) -> Self {
+        Self {
+            name: &r.name,
+            time_secs: r.time.as_secs_f64(),
+            measurement: r.outcome.as_ref().ok(),
+            error: r.outcome.as_ref().err().map(String::as_str),
+        }
+    }
+}
+
+/// Columns of CSV reports, in order.
+const CSV_COLUMNS: &[&str] = &[
+    "name",
+    "time_secs",
+    "shift_x",
+    "shift_y",
+    "rotation",
+    "scale",
+    "stars",
+    "matched_stars",
+    "rms_residual",
+    "error",
+];
+
+/// Format a field of a row for CSV, where strings are quoted and missing values are empty.
+fn csv_field(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(s)) => format!("\"{}\"", s.replace('"', "\"\"")),
+        Some(v) => v.to_string(),
+    }
+}
+
+/// Format records as CSV.
+pub fn to_csv(records: &[Record]) -> Result<String> {
+    let mut out = CSV_COLUMNS.join(",");
+    out.push('\n');
+    for r in records {
+        let row = serde_json::to_value(Row::from(r)).map_err(|e| Error::Other(e.to_string()))?;
+        let fields = CSV_COLUMNS
+            .iter()
+            .map(|c| csv_field(row.get(c)))
+            .collect::<Vec<_>>();
+        out.push_str(&fields.join(","));
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// Format records as a JSON array.
+///
+/// Non-finite measurements, such as the residual of an alignment without matched stars, are
+/// `null`.
+pub fn to_json(records: &[Record]) -> Result<String> {
+    let rows = records.iter().map(Row::from).collect::<Vec<_>>();
+    let mut out = serde_json::to_string_pretty(&rows).map_err(|e| Error::Other(e.to_string()))?;
+    out.push('\n');
+    Ok(out)
+}
+
+/// Write records to a file.
+///
+/// Records are written as JSON if the file has a `json` extension, and as CSV otherwise.
+pub fn write<P: AsRef<Path>>(path: P, records: &[Record]) -> Result<()> {
+    let path = path.as_ref();
+    let contents = match path.extension().and_then(|e| e.to_str()) {
+        Some(e) if e.eq_ignore_ascii_case("json") => to_json(records)?,
+        _ => to_csv(records)?,
+    };
+    if let Some(p) = path.parent() {
+        if !p.as_os_str().is_empty() {
+            std::fs::create_dir_all(p)?;
+        }
+    }
+    std::fs::write(path, contents)?;
+    Ok(())
+}
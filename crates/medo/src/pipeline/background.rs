@@ -0,0 +1,218 @@
+//! Implementation of the background extraction stage.
+//!
+//! Gradients from light pollution and moonlight are modelled from the background between stars
+//! and removed, leaving the background flat. The stage belongs after stacking, and before
+//! [stretching](super::stretch).
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv::core::{MatTraitConst, MatTraitConstManual};
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::{format, util, Result};
+use medo_stacker::background;
+use medo_stacker::star;
+
+use super::{Context, InvalidOption, Stage};
+
+/// Surfaces that the background can be modelled by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Model {
+    /// Polynomial of `degree`, for simple gradients.
+    Polynomial,
+    /// Thin plate spline smoothed by `smoothing`, for complex gradients.
+    Rbf,
+}
+
+impl Default for Model {
+    #[inline]
+    fn default() -> Self {
+        Self::Polynomial
+    }
+}
+
+/// Ways the background model is removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Correction {
+    /// Subtract the model, which removes light added by the sky.
+    Subtract,
+    /// Divide by the model, which removes vignetting that flats didn't.
+    Divide,
+}
+
+impl Default for Correction {
+    #[inline]
+    fn default() -> Self {
+        Self::Subtract
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Number of samples along each side of the image.
+    pub grid_size: u32,
+    /// Length of the side of the square each sample is taken from, in pixels.
+    pub sample_size: u32,
+    /// How far above the background samples are rejected as structures, in standard
+    /// deviations.
+    pub tolerance: f32,
+    /// Options used to detect the stars that are left out of samples.
+    ///
+    /// Stars are detected in an automatically stretched copy of the entry.
+    pub star_detection: star::ContourDetectionOpts,
+    /// Scale of the radius of stars that are left out, so that their halos are left out too.
+    pub star_margin: f32,
+    /// Surface that the background is modelled by.
+    pub model: Model,
+    /// Degree of the polynomial model, from 1 to 4.
+    pub degree: u32,
+    /// Smoothing of the spline model. 0 passes through every sample.
+    pub smoothing: f64,
+    /// How the model is removed.
+    pub correction: Correction,
+    /// Directory that the model of each entry is written to, for inspection.
+    ///
+    /// Models are TIFF files named after their entry, e.g. `light_1.tif` for `light_1.fits`.
+    pub model_dir: Option<PathBuf>,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            grid_size: 16,
+            sample_size: 25,
+            tolerance: 2.0,
+            star_detection: Default::default(),
+            star_margin: 2.0,
+            model: Default::default(),
+            degree: 2,
+            smoothing: 0.01,
+            correction: Default::default(),
+            model_dir: None,
+        }
+    }
+}
+
+/// Remove the background and return an owned entry, along with the number of samples the
+/// background was modelled from.
+///
+/// Entries whose background can't be modelled are returned unchanged, modelled from no samples.
+fn extract(image: &Entry, opts: &Opts) -> Result<(Entry, usize)> {
+    let image_mat = image.read_image()?;
+    let depth = image_mat.as_ref().depth();
+    let image_f = format::to_float(image_mat.as_ref())?;
+
+    let mask = background::star_mask(&image_f, opts.star_detection, opts.star_margin)?;
+    let samples = background::sample(&image_f, &mask, opts.grid_size, opts.sample_size)?;
+    let model = match opts.model {
+        Model::Polynomial => background::Model::Polynomial {
+            degree: opts.degree,
+        },
+        Model::Rbf => background::Model::Rbf {
+            smoothing: opts.smoothing,
+        },
+    };
+    let (model, used) = match background::fit(&samples, image_f.size()?, model, opts.tolerance) {
+        Ok(fit) => fit,
+        // An entry whose background can't be modelled, such as a stack filled by a nebula, is
+        // still worth keeping
+        Err(e) => {
+            tracing::warn!(
+                name = %image.name(),
+                error = %e,
+                "failed to model background, keeping entry unchanged"
+            );
+            return Ok((
+                Entry::new_image(image.name(), image_mat.as_ref().clone())?,
+                0,
+            ));
+        }
+    };
+    tracing::info!(
+        name = %image.name(),
+        samples = samples.len(),
+        used,
+        "modelled background"
+    );
+
+    if let Some(dir) = &opts.model_dir {
+        let model_out = format::convert_cv_depth(&model, depth)?;
+        let name = image.name();
+        let stem = Path::new(name.as_ref()).file_stem().unwrap_or_default();
+        util::write_image(
+            dir.join(format!("{}.tif", stem.to_string_lossy())),
+            &model_out,
+        )?;
+    }
+
+    let corrected = match opts.correction {
+        Correction::Subtract => background::subtract(&image_f, &model)?,
+        Correction::Divide => background::divide(&image_f, &model)?,
+    };
+    let out = format::convert_cv_depth(&corrected, depth)?;
+    Ok((Entry::new_image(image.name(), out)?, used))
+}
+
+pub fn process<'scope>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    opts: &Opts,
+    ctx: &Context,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+    let opts = opts.clone();
+    super::map_entries(input, "background", ctx, move |e| {
+        let (entry, used) = extract(e, &opts)?;
+        let metrics = BTreeMap::from([("background_samples".to_owned(), used as f64)]);
+        Ok((entry, metrics))
+    })
+}
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "background"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        if self.grid_size < 2 {
+            return Err(InvalidOption::new("grid_size", "must be at least 2"));
+        }
+        if self.sample_size == 0 {
+            return Err(InvalidOption::new("sample_size", "must be positive"));
+        }
+        if !(self.tolerance > 0.0 && self.tolerance.is_finite()) {
+            return Err(InvalidOption::new("tolerance", "must be greater than 0"));
+        }
+        if !(self.star_margin >= 1.0 && self.star_margin.is_finite()) {
+            return Err(InvalidOption::new("star_margin", "must be at least 1"));
+        }
+        if !(1..=4).contains(&self.degree) {
+            return Err(InvalidOption::new("degree", "must be from 1 to 4"));
+        }
+        if !(self.smoothing >= 0.0 && self.smoothing.is_finite()) {
+            return Err(InvalidOption::new(
+                "smoothing",
+                "must be a non-negative number",
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
@@ -0,0 +1,74 @@
+//! Implementation of the calibration stage.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::Result;
+
+use super::{Context, Stage};
+use crate::calibration::Masters;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Bias master, subtracted if there is no dark master.
+    pub bias: Option<PathBuf>,
+    /// Dark master, subtracted from each entry.
+    pub dark: Option<PathBuf>,
+    /// Flat master, that each entry is divided by.
+    pub flat: Option<PathBuf>,
+}
+
+impl Opts {
+    /// Read the masters these options refer to.
+    #[inline]
+    pub fn read_masters(&self) -> Result<Masters> {
+        Masters::read(
+            self.bias.as_deref(),
+            self.dark.as_deref(),
+            self.flat.as_deref(),
+        )
+    }
+}
+
+/// Calibrate and return an owned entry, as 32-bit floating point.
+///
+/// Entries are read at their own depth, so that the precision of 16-bit lights is kept.
+fn calibrate(entry: &Entry, masters: &Masters) -> Result<Entry> {
+    let image = entry.read_image_any_depth()?;
+    Entry::new_image(entry.name(), masters.apply(image.as_ref())?)
+}
+
+pub fn process<'scope>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    opts: &Opts,
+    ctx: &Context,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+    let masters = opts.read_masters()?;
+    super::map_entries(input, "calibration", ctx, move |e| {
+        Ok((calibrate(e, &masters)?, Default::default()))
+    })
+}
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "calibration"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
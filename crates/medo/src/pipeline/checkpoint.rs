@@ -0,0 +1,217 @@
+//! Checkpointing of pipeline stage outputs.
+//!
+//! After each stage, its output entries are written to a directory inside the checkpoint
+//! directory, along with a manifest identifying the input and options that produced them. A
+//! later run over the same input, with the same options, resumes after the last stage whose
+//! output was saved.
+
+use std::borrow::Cow;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::entry::{Entries, Entry, OwnedEntries, OwnedEntryIter};
+use medo_core::util::{self, ContentHasher};
+use medo_core::{Error, Result};
+
+use super::{Context, Stage};
+
+/// Name of the manifest file in a stage's checkpoint directory.
+const MANIFEST: &str = "checkpoint.json";
+
+/// Description of a saved stage output.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    /// Key of the input and options that produced the output.
+    key: String,
+    /// Path to the reference entry, relative to the stage's directory.
+    reference: PathBuf,
+    /// Paths to the other entries, relative to the stage's directory.
+    entries: Vec<PathBuf>,
+}
+
+/// Identifies the input and options that produced a stage's output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key(u64);
+
+impl std::fmt::Display for Key {
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl Key {
+    /// Calculate the key of a pipeline's input.
+    pub fn input<'a, I: IntoIterator<Item = &'a Entry>>(entries: I) -> Result<Self> {
+        let mut hasher = ContentHasher::default();
+        for e in entries {
+            hasher.write_u64(e.content_hash()?);
+        }
+        Ok(Self(hasher.finish()))
+    }
+
+    /// Calculate the key of a stage's output, given the key of its input.
+    pub fn stage(self, stage: &dyn Stage) -> Result<Self> {
+        let mut hasher = ContentHasher::default();
+        hasher.write_u64(self.0);
+        hasher.write(stage.name().as_bytes());
+        hasher.write(stage.options()?.to_string().as_bytes());
+        Ok(Self(hasher.finish()))
+    }
+}
+
+/// A directory of saved stage outputs.
+#[derive(Debug, Clone)]
+pub struct Checkpoints {
+    dir: PathBuf,
+}
+
+impl Checkpoints {
+    #[inline]
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Get the path to this directory.
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Get the directory in which a stage's output is saved.
+    fn stage_dir(&self, index: usize, stage: &dyn Stage) -> PathBuf {
+        self.dir.join(format!("{}-{}", index, stage.name()))
+    }
+
+    /// Load a stage's saved output, if it was produced by the same input and options.
+    pub fn load(&self, index: usize, stage: &dyn Stage, key: Key) -> Result<Option<OwnedEntries>> {
+        let dir = self.stage_dir(index, stage);
+        let manifest = match std::fs::read(dir.join(MANIFEST)) {
+            Ok(m) => m,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let manifest: Manifest =
+            serde_json::from_slice(&manifest).map_err(|e| Error::Other(e.to_string()))?;
+        if manifest.key != key.to_string() {
+            return Ok(None);
+        }
+
+        // A checkpoint with missing entries can't be resumed from
+        let paths = std::iter::once(&manifest.reference).chain(&manifest.entries);
+        if paths.clone().any(|p| !dir.join(p).is_file()) {
+            tracing::warn!(dir = %dir.display(), "incomplete checkpoint, ignoring");
+            return Ok(None);
+        }
+        let mut entries = paths
+            .map(|p| Entry::new_path_any_depth(dir.join(p)))
+            .collect::<Result<Vec<_>>>()?;
+        let reference = entries.remove(0);
+        Ok(Some(OwnedEntries { reference, entries }))
+    }
+
+    /// Save a stage's output as it is read.
+    ///
+    /// Entries are written at their own depth as they pass through, and the returned entries
+    /// are the stage's own. The checkpoint is only complete once all entries are read. Entries
+    /// that fail to be saved are discarded, and reported as failures of the stage. If the
+    /// context is cancelled, the partially saved output is removed.
+    ///
+    /// # Parameters
+    /// - `ctx`: The context given to the stage.
+    pub fn save<'scope>(
+        &self,
+        index: usize,
+        stage: &dyn Stage,
+        key: Key,
+        output: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        let dir = self.stage_dir(index, stage);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+
+        let reference = write_entry(&dir, Path::new("reference"), &output.reference)
+            .map_err(|e| e.with_entry(output.reference.name()))?;
+        let saved = Arc::new(Mutex::new(vec![]));
+
+        let (stage_name, ctx) = (Arc::<str>::from(stage.name()), ctx.clone());
+        let (write_dir, write_saved, write_stage, write_ctx) =
+            (dir.clone(), saved.clone(), stage_name.clone(), ctx.clone());
+        let entries = output
+            .entries
+            .enumerate()
+            .take_while(move |_| !write_ctx.is_cancelled())
+            .filter_map(move |(i, e)| {
+                let span = tracing::info_span!("checkpoint", stage = %write_stage);
+                let _enter = span.enter();
+
+                match write_entry(&write_dir, Path::new(&i.to_string()), &e) {
+                    Ok(p) => {
+                        write_saved.lock().unwrap().push(p);
+                        Some(e)
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            name = %e.name(),
+                            error = %err,
+                            "failed to save entry, discarding"
+                        );
+                        write_ctx.entry_failed(&e.name(), &err);
+                        None
+                    }
+                }
+            });
+
+        // The manifest is written last, so that it only exists for complete checkpoints
+        let finish = std::iter::once_with(move || {
+            let span = tracing::info_span!("checkpoint", stage = %stage_name);
+            let _enter = span.enter();
+
+            let result = if ctx.is_cancelled() {
+                std::fs::remove_dir_all(&dir).map_err(Error::from)
+            } else {
+                let manifest = Manifest {
+                    key: key.to_string(),
+                    reference,
+                    entries: std::mem::take(&mut *saved.lock().unwrap()),
+                };
+                write_manifest(&dir, &manifest)
+            };
+            if let Err(e) = result {
+                tracing::error!(dir = %dir.display(), error = %e, "failed to finish checkpoint");
+            }
+            None::<Cow<'scope, Entry>>
+        })
+        .flatten();
+
+        Ok(Entries {
+            reference: output.reference,
+            entries: Box::new(entries.chain(finish)),
+        })
+    }
+}
+
+/// Write a stage's manifest.
+fn write_manifest(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let manifest = serde_json::to_vec_pretty(manifest).map_err(|e| Error::Other(e.to_string()))?;
+    std::fs::write(dir.join(MANIFEST), manifest)?;
+    Ok(())
+}
+
+/// Write an entry to `<dir>/<sub_dir>/<name>.tif` at its own depth, returning its path relative
+/// to `dir`.
+fn write_entry(dir: &Path, sub_dir: &Path, entry: &Entry) -> Result<PathBuf> {
+    let name = entry.name();
+    let stem = Path::new(name.as_ref())
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or(Cow::Borrowed("entry"));
+    let path = sub_dir.join(format!("{}.tif", stem));
+    util::write_image(dir.join(&path), entry.read_image()?.as_ref())?;
+    Ok(path)
+}
@@ -0,0 +1,286 @@
+//! Implementation of the colour calibration stage.
+//!
+//! Stacks of one-shot colour cameras come out tinted by the sensor and by light pollution. The
+//! background is neutralized to grey, then the stack is white balanced by the average colour of
+//! its stars, or of a region chosen as white. The stage belongs after stacking and background
+//! extraction, and before [stretching](super::stretch). Entries that are not colour are left
+//! unchanged.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, Rect};
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::{format, Error, Result};
+use medo_stacker::{color, star};
+
+use super::{Context, InvalidOption, Stage};
+
+/// A rectangular region of an image, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Region {
+    /// Column of the region's left edge.
+    pub x: u32,
+    /// Row of the region's top edge.
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    /// Get the region as a rectangle, checking that it is within an image.
+    fn rect(&self, image: &Mat) -> Result<Rect> {
+        let within = |start: u32, len: u32, size: i32| {
+            start
+                .checked_add(len)
+                .map_or(false, |end| i64::from(end) <= i64::from(size))
+        };
+        if !(within(self.x, self.width, image.cols()) && within(self.y, self.height, image.rows()))
+        {
+            return Err(Error::Other(format!(
+                "region {} is outside the {}x{} image",
+                self,
+                image.cols(),
+                image.rows()
+            )));
+        }
+        // Within the image, every bound fits
+        Ok(Rect::new(
+            self.x as i32,
+            self.y as i32,
+            self.width as i32,
+            self.height as i32,
+        ))
+    }
+}
+
+impl std::fmt::Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    /// Parse a region as `x,y,width,height`.
+    fn from_str(s: &str) -> Result<Self> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| Error::Other(format!("invalid region '{}': {}", s, e)))?;
+        match values[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Ok(Self {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => Err(Error::Other(format!(
+                "invalid region '{}': expected x,y,width,height with a positive size",
+                s
+            ))),
+        }
+    }
+}
+
+/// References that entries are white balanced by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhiteReference {
+    /// Entries are not white balanced.
+    None,
+    /// The average colour of unsaturated stars is white.
+    ///
+    /// Entries without unsaturated stars are only neutralized.
+    Stars,
+    /// The mean colour of `white_region` is white.
+    Region,
+}
+
+impl Default for WhiteReference {
+    #[inline]
+    fn default() -> Self {
+        Self::Stars
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Whether the background is neutralized to grey.
+    pub neutralize: bool,
+    /// Region of background that the background level is measured in.
+    ///
+    /// Defaults to the median of the whole entry.
+    pub background_region: Option<Region>,
+    /// Reference that entries are white balanced by.
+    pub white_reference: WhiteReference,
+    /// Region whose colour is white, when white balancing by a region.
+    pub white_region: Option<Region>,
+    /// Options used to detect the stars that entries are white balanced by.
+    ///
+    /// Stars are detected in an automatically stretched copy of the entry.
+    pub star_detection: star::ContourDetectionOpts,
+    /// Fraction of the entry's brightest value at which stars are saturated, and left out of
+    /// the white balance.
+    pub saturation: f32,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            neutralize: true,
+            background_region: None,
+            white_reference: Default::default(),
+            white_region: None,
+            star_detection: Default::default(),
+            saturation: 0.95,
+        }
+    }
+}
+
+/// Names of the channels of colour images, which are BGR.
+const CHANNELS: [&str; 3] = ["blue", "green", "red"];
+
+/// Get the brightest valid value of a 32-bit floating point image.
+fn max_value(image: &Mat) -> Result<f64> {
+    let mut patched = image.try_clone()?;
+    cv::core::patch_na_ns(&mut patched, 0.0)?;
+    let mut max = 0.0;
+    cv::core::min_max_loc(
+        &patched.reshape(1, 0)?,
+        None,
+        Some(&mut max),
+        None,
+        None,
+        &cv::core::no_array(),
+    )?;
+    Ok(max)
+}
+
+/// Calibrate the colour of an entry and return an owned entry, along with measurements of it.
+fn calibrate(image: &Entry, opts: &Opts) -> Result<(Entry, BTreeMap<String, f64>)> {
+    let image_mat = image.read_image()?;
+    let mut metrics = BTreeMap::new();
+    if image_mat.as_ref().channels() == 1 {
+        return Ok((
+            Entry::new_image(image.name(), image_mat.as_ref().clone())?,
+            metrics,
+        ));
+    }
+    let depth = image_mat.as_ref().depth();
+    let mut image_f = format::to_float(image_mat.as_ref())?;
+
+    let background_region = opts
+        .background_region
+        .map(|r| r.rect(&image_f))
+        .transpose()?;
+    let mut levels = color::background_levels(&image_f, background_region)?;
+    if opts.neutralize {
+        image_f = color::neutralize(&image_f, &levels)?;
+        let mean = levels.iter().sum::<f32>() / levels.len() as f32;
+        levels.iter_mut().for_each(|l| *l = mean);
+    }
+
+    let white = match opts.white_reference {
+        WhiteReference::None => None,
+        WhiteReference::Stars => {
+            let stars = star::find_stretched(&image_f, opts.star_detection)?;
+            let saturation = (opts.saturation as f64 * max_value(&image_f)?) as f32;
+            let white = color::star_color(&image_f, &stars, &levels, saturation)?;
+            if white.is_none() {
+                tracing::warn!(
+                    name = %image.name(),
+                    "no unsaturated stars to white balance by, only neutralizing"
+                );
+            }
+            metrics.insert("white_balance_stars".to_owned(), stars.len() as f64);
+            white
+        }
+        WhiteReference::Region => {
+            let region = opts
+                .white_region
+                .ok_or(Error::OtherStatic("no white region to white balance by"))?
+                .rect(&image_f)?;
+            Some(color::region_color(&image_f, region, &levels)?)
+        }
+    };
+    if let Some(white) = white {
+        image_f = color::white_balance(&image_f, &white, &levels)?;
+        for (c, w) in white.iter().enumerate() {
+            let channel = CHANNELS
+                .get(c)
+                .map_or_else(|| c.to_string(), |c| c.to_string());
+            metrics.insert(format!("white_{}", channel), *w as f64);
+        }
+    }
+    tracing::info!(name = %image.name(), ?levels, "calibrated colour");
+
+    let out = format::convert_cv_depth(&image_f, depth)?;
+    Ok((Entry::new_image(image.name(), out)?, metrics))
+}
+
+pub fn process<'scope>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    opts: &Opts,
+    ctx: &Context,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+    let opts = opts.clone();
+    super::map_entries(input, "color_calibration", ctx, move |e| {
+        calibrate(e, &opts)
+    })
+}
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "color_calibration"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        let regions = [
+            ("background_region", self.background_region),
+            ("white_region", self.white_region),
+        ];
+        for (key, region) in regions {
+            if let Some(r) = region {
+                if r.width == 0 || r.height == 0 {
+                    return Err(InvalidOption::new(key, "must have a positive size"));
+                }
+            }
+        }
+        if self.white_reference == WhiteReference::Region && self.white_region.is_none() {
+            return Err(InvalidOption::new(
+                "white_region",
+                "must be set to white balance by a region",
+            ));
+        }
+        if !(self.saturation > 0.0 && self.saturation <= 1.0) {
+            return Err(InvalidOption::new(
+                "saturation",
+                "must be greater than 0 and at most 1",
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
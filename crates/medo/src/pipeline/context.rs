@@ -0,0 +1,433 @@
+//! State shared by all stages of a running pipeline.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::{Error, Result};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Serialize a duration as a number of seconds.
+fn serialize_secs<S: Serializer>(
+    time: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(time.as_secs_f64())
+}
+
+/// Progress of a running pipeline.
+///
+/// Events are serialized as objects tagged by their `event` name, with times in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A group started being processed.
+    GroupStarted {
+        /// Name of the group.
+        name: String,
+        /// Number of entries in the group, besides its reference.
+        entries: usize,
+    },
+    /// A stage started processing entries.
+    StageStarted {
+        /// Position of the stage in the pipeline.
+        index: usize,
+        /// Name of the stage.
+        stage: String,
+    },
+    /// A stage processed an entry.
+    EntryProcessed {
+        /// Position of the stage in the pipeline.
+        index: usize,
+        /// Name of the stage.
+        stage: String,
+        /// Name of the entry.
+        name: String,
+        /// Time taken to process the entry.
+        #[serde(rename = "time_secs", serialize_with = "serialize_secs")]
+        time: Duration,
+        /// Why the entry failed to be processed, if it did.
+        error: Option<String>,
+    },
+    /// A stage measured an entry, such as how well it was aligned.
+    EntryMeasured {
+        /// Position of the stage in the pipeline.
+        index: usize,
+        /// Name of the stage.
+        stage: String,
+        /// Name of the entry.
+        name: String,
+        /// Measurements, by name. Measurements that are not a number are `null`.
+        metrics: BTreeMap<String, f64>,
+    },
+    /// A stage processed all of its entries.
+    StageFinished {
+        /// Position of the stage in the pipeline.
+        index: usize,
+        /// Name of the stage.
+        stage: String,
+        /// Time taken to process all entries.
+        #[serde(rename = "time_secs", serialize_with = "serialize_secs")]
+        time: Duration,
+    },
+    /// All stages processed all of their entries.
+    PipelineFinished {
+        /// Time taken to run the pipeline.
+        #[serde(rename = "time_secs", serialize_with = "serialize_secs")]
+        time: Duration,
+    },
+    /// A group finished being processed, successfully or not.
+    GroupFinished {
+        /// Name of the group.
+        name: String,
+        /// Time taken to process the group.
+        #[serde(rename = "time_secs", serialize_with = "serialize_secs")]
+        time: Duration,
+        /// Number of entries that failed to be processed.
+        failed: usize,
+    },
+}
+
+/// Receives progress events.
+///
+/// Listeners are called from whichever thread an event happens on.
+pub type Listener = dyn Fn(&Event) + Send + Sync;
+
+/// Requests a running pipeline to stop.
+///
+/// Clones of a token share their state, so a token can be cancelled from another thread, or a
+/// signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request work to stop.
+    #[inline]
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Check if work was requested to stop.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do when a stage fails to process an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPolicy {
+    /// Leave the entry out, and carry on.
+    Discard,
+    /// Stop the whole run.
+    Abort,
+    /// Stop the whole run if more than this percentage of a stage's entries fail.
+    ///
+    /// This is checked once the stage has processed all of its entries.
+    AbortAbove(f32),
+}
+
+impl Default for ErrorPolicy {
+    #[inline]
+    fn default() -> Self {
+        Self::Discard
+    }
+}
+
+/// An entry that a stage failed to process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// Position of the stage in the pipeline.
+    pub index: usize,
+    /// Name of the stage.
+    pub stage: String,
+    /// Name of the entry.
+    pub name: String,
+    /// Why the entry failed to be processed.
+    pub error: String,
+}
+
+/// Why a run was aborted by an error policy.
+#[derive(Debug, Clone)]
+struct AbortReason {
+    stage: String,
+    entry: Option<String>,
+    error: String,
+}
+
+/// Failures of a single run.
+#[derive(Debug, Default)]
+struct Failures {
+    failures: Vec<Failure>,
+    /// Number of entries processed by each stage, by position in the pipeline.
+    processed: Vec<usize>,
+    /// Why the run was aborted.
+    abort_reason: Option<AbortReason>,
+}
+
+/// State shared by all stages of a running pipeline.
+#[derive(Clone, Default)]
+pub struct Context {
+    listener: Option<Arc<Listener>>,
+    cancellation: CancellationToken,
+    /// Set when the error policy stops the run.
+    aborted: CancellationToken,
+    failures: Arc<Mutex<Failures>>,
+    /// Position, name and error policy of the stage this context is given to.
+    stage: Option<(usize, Arc<str>, ErrorPolicy)>,
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("listener", &self.listener.is_some())
+            .field("cancelled", &self.is_cancelled())
+            .field("stage", &self.stage)
+            .finish()
+    }
+}
+
+impl Context {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send progress events to a listener.
+    #[inline]
+    pub fn with_listener<F: Fn(&Event) + Send + Sync + 'static>(mut self, listener: F) -> Self {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Stop work when a token is cancelled.
+    #[inline]
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = token;
+        self
+    }
+
+    /// Check if work was requested to stop, or was aborted by an error policy.
+    ///
+    /// Stages should check this between entries, and stop processing entries once it is set.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled() || self.aborted.is_cancelled()
+    }
+
+    /// Fail if work was requested to stop, or was aborted by an error policy.
+    ///
+    /// The error is [`Error::Cancelled`] if work was requested to stop, and [`Error::Aborted`] if
+    /// an error policy stopped it.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.aborted.is_cancelled() {
+            let failures = self.failures.lock().unwrap();
+            Err(match &failures.abort_reason {
+                Some(r) => {
+                    let e = Error::Aborted(r.error.clone());
+                    match &r.entry {
+                        Some(entry) => e.with_entry(entry.as_str()),
+                        None => e,
+                    }
+                    .with_stage(r.stage.as_str())
+                }
+                None => Error::Aborted("too many entries failed".to_owned()),
+            })
+        } else if self.cancellation.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Get a context for a new run, which shares this context's listener and cancellation
+    /// token, but not its failures.
+    #[inline]
+    pub(crate) fn for_run(&self) -> Self {
+        Self {
+            listener: self.listener.clone(),
+            cancellation: self.cancellation.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Get the context given to a stage.
+    #[inline]
+    pub(crate) fn for_stage(&self, index: usize, stage: &str, on_error: ErrorPolicy) -> Self {
+        Self {
+            stage: Some((index, Arc::from(stage), on_error)),
+            ..self.clone()
+        }
+    }
+
+    /// Get the entries that failed to be processed so far.
+    #[inline]
+    pub fn failures(&self) -> Vec<Failure> {
+        self.failures.lock().unwrap().failures.clone()
+    }
+
+    /// Stop the run.
+    fn abort(&self, reason: AbortReason) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.abort_reason.is_none() {
+            tracing::error!(stage = %reason.stage, error = %reason.error, "aborting");
+            failures.abort_reason = Some(reason);
+        }
+        self.aborted.cancel();
+    }
+
+    /// Apply the current stage's error policy to the failure rate of its entries.
+    pub(crate) fn finish_stage(&self) {
+        let (index, stage, on_error) = match &self.stage {
+            Some(s) => s,
+            None => return,
+        };
+        let percent = match on_error {
+            ErrorPolicy::AbortAbove(p) => *p,
+            _ => return,
+        };
+
+        let failures = self.failures.lock().unwrap();
+        // Stages may appear more than once in a pipeline
+        let failed = failures
+            .failures
+            .iter()
+            .filter(|f| f.index == *index)
+            .count();
+        let processed = failures.processed.get(*index).copied().unwrap_or(0);
+        drop(failures);
+        if processed > 0 && failed as f32 * 100.0 / processed as f32 > percent {
+            self.abort(AbortReason {
+                stage: stage.to_string(),
+                entry: None,
+                error: format!(
+                    "{} of {} entries failed, above {}%",
+                    failed, processed, percent
+                ),
+            });
+        }
+    }
+
+    /// Send an event to the listener.
+    #[inline]
+    pub fn emit(&self, event: Event) {
+        if let Some(listener) = &self.listener {
+            listener(&event);
+        }
+    }
+
+    /// Report that the current stage processed an entry.
+    ///
+    /// Failures are recorded, and handled by the stage's error policy.
+    pub fn entry_processed<E: fmt::Display>(&self, name: &str, time: Duration, error: Option<E>) {
+        let (index, stage, on_error) = match &self.stage {
+            Some(s) => s,
+            None => return,
+        };
+        let error = error.map(|e| e.to_string());
+
+        {
+            let mut failures = self.failures.lock().unwrap();
+            if failures.processed.len() <= *index {
+                failures.processed.resize(index + 1, 0);
+            }
+            failures.processed[*index] += 1;
+        }
+        if let Some(e) = &error {
+            self.record_failure(*index, stage, *on_error, name, e);
+        }
+
+        self.emit(Event::EntryProcessed {
+            index: *index,
+            stage: stage.to_string(),
+            name: name.to_owned(),
+            time,
+            error,
+        });
+    }
+
+    /// Report that an entry the current stage processed was lost afterwards, such as when its
+    /// output failed to be saved.
+    ///
+    /// The failure is handled by the stage's error policy, like one reported by
+    /// [`entry_processed`](Self::entry_processed).
+    pub fn entry_failed<E: fmt::Display>(&self, name: &str, error: E) {
+        if let Some((index, stage, on_error)) = &self.stage {
+            self.record_failure(*index, stage, *on_error, name, &error.to_string());
+        }
+    }
+
+    /// Record a failure, and apply the error policy to it.
+    fn record_failure(
+        &self,
+        index: usize,
+        stage: &str,
+        on_error: ErrorPolicy,
+        name: &str,
+        error: &str,
+    ) {
+        self.failures.lock().unwrap().failures.push(Failure {
+            index,
+            stage: stage.to_owned(),
+            name: name.to_owned(),
+            error: error.to_owned(),
+        });
+        if on_error == ErrorPolicy::Abort {
+            self.abort(AbortReason {
+                stage: stage.to_owned(),
+                entry: Some(name.to_owned()),
+                error: error.to_owned(),
+            });
+        }
+    }
+
+    /// Report measurements of an entry by the current stage.
+    ///
+    /// Measurements should be reported before the entry is reported as processed.
+    pub fn entry_measured(&self, name: &str, metrics: BTreeMap<String, f64>) {
+        let (index, stage, _) = match &self.stage {
+            Some(s) => s,
+            None => return,
+        };
+        self.emit(Event::EntryMeasured {
+            index: *index,
+            stage: stage.to_string(),
+            name: name.to_owned(),
+            metrics,
+        });
+    }
+
+    /// Emit an event once all of a group's entries are consumed.
+    ///
+    /// Stages may process entries lazily, as the next stage reads them, so a stage has only
+    /// finished once its output is consumed.
+    pub(crate) fn on_finished<'scope, F>(
+        &self,
+        entries: Entries<'scope, OwnedEntryIter<'scope>>,
+        event: F,
+    ) -> Entries<'scope, OwnedEntryIter<'scope>>
+    where
+        F: FnOnce() -> Event + Send + Sync + 'scope,
+    {
+        let ctx = self.clone();
+        let finished = std::iter::once_with(move || {
+            ctx.emit(event());
+            None::<Cow<'scope, Entry>>
+        })
+        .flatten();
+        Entries {
+            reference: entries.reference,
+            entries: Box::new(entries.entries.chain(finished)),
+        }
+    }
+}
@@ -1,57 +1,495 @@
 //! Defines an entry group's processing pipeline.
 
-use medo_core::entry::{Entries, OwnedEntryIter};
-use medo_core::Result;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::{Error, Result};
 
 pub mod alignment;
+pub mod background;
+pub mod calibration;
+pub mod checkpoint;
+pub mod color;
+mod context;
+pub mod scnr;
 pub mod sharpen;
 pub mod stacking;
+pub mod stretch;
+
+pub use context::{CancellationToken, Context, ErrorPolicy, Event, Failure, Listener};
 
 /// A stage in the processing pipeline of a group of entries.
+///
+/// Stages are created from their options by a [`Registry`], which lets configuration files refer
+/// to them by name.
+pub trait Stage: fmt::Debug + Send + Sync {
+    /// Get the name this stage is registered by.
+    fn name(&self) -> &str;
+
+    /// Get this stage's options, as they would appear in a configuration file.
+    fn options(&self) -> Result<serde_json::Value>;
+
+    /// Check that this stage's options are usable.
+    #[inline]
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        Ok(())
+    }
+
+    /// Process a group of entries.
+    ///
+    /// Each processed entry should be reported to the context.
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>>;
+}
+
+/// An unusable stage option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidOption {
+    /// Path to the option, relative to its stage.
+    pub key: &'static str,
+    /// Why the option is unusable.
+    pub reason: &'static str,
+}
+
+impl InvalidOption {
+    #[inline]
+    pub const fn new(key: &'static str, reason: &'static str) -> Self {
+        Self { key, reason }
+    }
+}
+
+impl From<InvalidOption> for Error {
+    #[inline]
+    fn from(e: InvalidOption) -> Self {
+        Error::InvalidOption {
+            key: e.key.to_owned(),
+            reason: e.reason.to_owned(),
+        }
+    }
+}
+
+/// Prefix the key of an invalid option error.
+fn prefix_key(e: Error, prefix: &str) -> Error {
+    match e {
+        Error::InvalidOption { key, reason } => Error::InvalidOption {
+            key: format!("{}.{}", prefix, key),
+            reason,
+        },
+        e => e,
+    }
+}
+
+impl fmt::Display for InvalidOption {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.reason)
+    }
+}
+
+/// Deserialize a stage's options.
+///
+/// Errors point to the offending option, e.g. `homography.iterations`.
+pub fn deserialize_options<T: DeserializeOwned>(options: serde_json::Value) -> Result<T> {
+    serde_path_to_error::deserialize(options).map_err(|e| Error::InvalidOption {
+        key: e.path().to_string(),
+        reason: e.into_inner().to_string(),
+    })
+}
+
+/// Serialize a stage's options.
+#[inline]
+pub fn serialize_options<T: Serialize>(options: &T) -> Result<serde_json::Value> {
+    serde_json::to_value(options).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Process each entry of a group on its own, as the next stage reads it.
+///
+/// The reference is processed first, and the stage fails if it can't be. Other entries stop
+/// being read once work is cancelled, and those that fail are discarded. Every entry is reported
+/// to the context, along with its measurements.
+///
+/// # Parameters
+/// - `input`: The entries to process.
+/// - `stage`: Name of the stage, which entries are processed in the span of.
+/// - `ctx`: The context entries are reported to.
+/// - `f`: Processes an entry into an owned entry, along with measurements of it.
+pub(crate) fn map_entries<'scope, F>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    stage: &'static str,
+    ctx: &Context,
+    f: F,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>>
+where
+    F: Fn(&Entry) -> Result<(Entry, BTreeMap<String, f64>)> + Send + Sync + 'scope,
+{
+    let (reference, metrics) =
+        f(input.reference.as_ref()).map_err(|e| e.with_entry(input.reference.name()))?;
+    if !metrics.is_empty() {
+        ctx.entry_measured(&reference.name(), metrics);
+    }
+    let (ctx, cancel_ctx) = (ctx.clone(), ctx.clone());
+    Ok(Entries {
+        reference: Cow::Owned(reference),
+        entries: Box::new(
+            input
+                .entries
+                // Stop reading entries once cancelled
+                .take_while(move |_| !cancel_ctx.is_cancelled())
+                .filter_map(move |e| {
+                    let span = tracing::info_span!("stage", stage);
+                    let _enter = span.enter();
+
+                    let name = e.name();
+                    let start = std::time::Instant::now();
+                    let result = f(e.as_ref()).map_err(|e| e.with_entry(name.as_ref()));
+                    let time = start.elapsed();
+                    if let Ok((_, metrics)) = &result {
+                        if !metrics.is_empty() {
+                            ctx.entry_measured(&name, metrics.clone());
+                        }
+                    }
+                    ctx.entry_processed(&name, time, result.as_ref().err());
+                    match result {
+                        Err(e) => {
+                            tracing::error!(
+                                name = %name,
+                                error = %e,
+                                "failed to process entry, discarding"
+                            );
+                            None
+                        }
+                        Ok((e, _)) => Some(Cow::Owned(e)),
+                    }
+                }),
+        ),
+    })
+}
+
+/// Creates a stage from its options.
+pub type StageFactory = Box<dyn Fn(serde_json::Value) -> Result<Arc<dyn Stage>> + Send + Sync>;
+
+/// A set of stages that can be referred to by name.
+pub struct Registry {
+    factories: HashMap<String, StageFactory>,
+}
+
+impl Default for Registry {
+    /// A registry of all built-in stages.
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register_deserialize::<alignment::Opts>("alignment");
+        registry.register_deserialize::<background::Opts>("background");
+        registry.register_deserialize::<calibration::Opts>("calibration");
+        registry.register_deserialize::<color::Opts>("color_calibration");
+        registry.register_deserialize::<scnr::Opts>("scnr");
+        registry.register_deserialize::<sharpen::Opts>("sharpen");
+        registry.register_deserialize::<stacking::Opts>("stacking");
+        registry.register_deserialize::<stretch::Opts>("stretch");
+        registry
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.factories.keys()).finish()
+    }
+}
+
+impl Registry {
+    /// Create a registry with no stages.
+    #[inline]
+    pub fn empty() -> Self {
+        Self {
+            factories: HashMap::new(),
+        }
+    }
+
+    /// Register a stage, replacing any stage of the same name.
+    pub fn register<F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn(serde_json::Value) -> Result<Arc<dyn Stage>> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.into(), Box::new(factory));
+    }
+
+    /// Register a stage that is deserialized from its options.
+    pub fn register_deserialize<S>(&mut self, name: impl Into<String>)
+    where
+        S: Stage + DeserializeOwned + 'static,
+    {
+        self.register(name, |options| {
+            Ok(Arc::new(deserialize_options::<S>(options)?) as Arc<dyn Stage>)
+        });
+    }
+
+    /// Get the names of all registered stages.
+    #[inline]
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Create a stage by name.
+    pub fn create(&self, name: &str, options: serde_json::Value) -> Result<Arc<dyn Stage>> {
+        let factory = self
+            .factories
+            .get(name)
+            .ok_or_else(|| Error::InvalidOption {
+                key: "stage".to_owned(),
+                reason: format!("unknown stage `{}`", name),
+            })?;
+        factory(options)
+    }
+}
+
+/// Serializable description of a stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageConfig {
+    /// Name the stage is registered by.
+    pub stage: String,
+    /// What to do when this stage fails to process an entry.
+    ///
+    /// Defaults to the pipeline's error policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_error: Option<ErrorPolicy>,
+    /// The stage's options.
+    #[serde(flatten)]
+    pub options: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Serializable description of a pipeline.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// What to do when a stage fails to process an entry.
+    #[serde(default)]
+    pub on_error: ErrorPolicy,
+    pub stages: Vec<StageConfig>,
+}
+
+/// A stage of a pipeline.
 #[derive(Debug, Clone)]
-pub enum Stage {
-    Alignment(alignment::Opts),
-    Stacking(stacking::Opts),
-    Sharpen(sharpen::Opts),
+pub struct PipelineStage {
+    pub stage: Arc<dyn Stage>,
+    /// What to do when this stage fails to process an entry.
+    ///
+    /// Defaults to the pipeline's error policy.
+    pub on_error: Option<ErrorPolicy>,
 }
 
-impl Stage {
-    /// Get a constant name describing this stage of the pipeline.
+impl<S: Stage + 'static> From<S> for PipelineStage {
     #[inline]
-    pub const fn name(&self) -> &'static str {
-        match self {
-            Self::Alignment(_) => "alignment",
-            Self::Stacking(_) => "stacking",
-            Self::Sharpen(_) => "sharpen",
+    fn from(stage: S) -> Self {
+        Self {
+            stage: Arc::new(stage),
+            on_error: None,
         }
     }
 }
 
 /// Represents a pipeline of operations on a group of entries.
+#[derive(Debug, Clone)]
 pub struct Pipeline {
-    pub stages: Vec<Stage>,
+    pub stages: Vec<PipelineStage>,
+    /// What to do when a stage fails to process an entry.
+    pub on_error: ErrorPolicy,
+    /// Save the output of every stage, and resume from previously saved outputs.
+    pub checkpoints: Option<checkpoint::Checkpoints>,
+}
+
+impl Default for Pipeline {
+    /// Align, sharpen and stack entries.
+    fn default() -> Self {
+        Self {
+            stages: vec![
+                alignment::Opts::default().into(),
+                sharpen::Opts::default().into(),
+                stacking::Opts::default().into(),
+            ],
+            on_error: Default::default(),
+            checkpoints: None,
+        }
+    }
 }
 
 impl Pipeline {
+    /// Create a pipeline from its description.
+    ///
+    /// Errors point to the offending stage and option, e.g. `stages[0].homography.iterations`.
+    pub fn from_config(config: &Config, registry: &Registry) -> Result<Self> {
+        let stages = config
+            .stages
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let stage = registry
+                    .create(&s.stage, serde_json::Value::Object(s.options.clone()))
+                    .map_err(|e| prefix_key(e, &format!("stages[{}]", i)))?;
+                Ok(PipelineStage {
+                    stage,
+                    on_error: s.on_error,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let pipeline = Self {
+            stages,
+            on_error: config.on_error,
+            checkpoints: None,
+        };
+        pipeline.validate()?;
+        Ok(pipeline)
+    }
+
+    /// Describe this pipeline.
+    pub fn to_config(&self) -> Result<Config> {
+        let stages = self
+            .stages
+            .iter()
+            .map(|s| {
+                let options = match s.stage.options()? {
+                    serde_json::Value::Object(o) => o,
+                    serde_json::Value::Null => Default::default(),
+                    _ => {
+                        return Err(Error::Other(format!(
+                            "options of stage `{}` are not a map",
+                            s.stage.name()
+                        )))
+                    }
+                };
+                Ok(StageConfig {
+                    stage: s.stage.name().to_owned(),
+                    on_error: s.on_error,
+                    options,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Config {
+            on_error: self.on_error,
+            stages,
+        })
+    }
+
+    /// Check that the options of every stage are usable.
+    ///
+    /// The error points to the offending option, e.g. `stages[0].homography.iterations`.
+    pub fn validate(&self) -> Result<()> {
+        validate_policy(&self.on_error)?;
+        for (i, stage) in self.stages.iter().enumerate() {
+            stage
+                .on_error
+                .as_ref()
+                .map_or(Ok(()), validate_policy)
+                .and_then(|_| stage.stage.validate())
+                .map_err(|e| prefix_key(e.into(), &format!("stages[{}]", i)))?;
+        }
+        Ok(())
+    }
+
     /// Run this pipeline.
+    #[inline]
     pub fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        self.process_with(input, &Context::default())
+    }
+
+    /// Run this pipeline in a context.
+    ///
+    /// If [checkpoints](Self::checkpoints) are set, this resumes after the last stage whose
+    /// output was saved from the same input and options, and saves the output of every stage
+    /// that is run as the next stage reads it.
+    ///
+    /// Stages may process entries lazily, so the output must be consumed for the pipeline to
+    /// finish. If the context is cancelled, stages stop yielding entries, so the consumer should
+    /// [check](Context::check_cancelled) the context once it is done.
+    pub fn process_with<'scope>(
         &self,
         mut input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
     ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
         let span = tracing::info_span!("pipeline");
         let _enter = span.enter();
+        let pipeline_start = std::time::Instant::now();
+
+        let mut start = 0;
+        let mut keys = vec![];
+        if let Some(checkpoints) = &self.checkpoints {
+            let entries = input.entries.collect::<Vec<_>>();
+            let mut key = checkpoint::Key::input(
+                std::iter::once(input.reference.as_ref()).chain(entries.iter().map(|e| e.as_ref())),
+            )?;
+            input.entries = Box::new(entries.into_iter());
+            for stage in &self.stages {
+                key = key.stage(stage.stage.as_ref())?;
+                keys.push(key);
+            }
+
+            // Resume from the last saved stage
+            for (i, stage) in self.stages.iter().map(|s| &s.stage).enumerate().rev() {
+                if let Some(output) = checkpoints.load(i, stage.as_ref(), keys[i])? {
+                    tracing::info!(stage = %stage.name(), "resuming from checkpoint");
+                    input = output.into_entries();
+                    start = i + 1;
+                    break;
+                }
+            }
+        }
 
         // A pipeline stage needs to output an `Entry`. We let each stage
         // decide if holding the image in memory, or writing it to disk
         // is more efficient.
-        for stage in &self.stages {
+        for (i, PipelineStage { stage, on_error }) in self.stages.iter().enumerate().skip(start) {
             tracing::info!(stage = %stage.name());
-            input = match stage {
-                Stage::Alignment(o) => alignment::process(input, o)?,
-                Stage::Stacking(o) => stacking::process(input, o)?,
-                Stage::Sharpen(o) => sharpen::process(input, o)?,
+            let stage_start = std::time::Instant::now();
+            ctx.emit(Event::StageStarted {
+                index: i,
+                stage: stage.name().to_owned(),
+            });
+
+            ctx.check_cancelled()?;
+            let stage_ctx = ctx.for_stage(i, stage.name(), on_error.unwrap_or(self.on_error));
+            input = stage
+                .process(input, &stage_ctx)
+                .map_err(|e| e.with_stage(stage.name()))?;
+            let name = stage.name().to_owned();
+            let finish_ctx = stage_ctx.clone();
+            input = ctx.on_finished(input, move || {
+                finish_ctx.finish_stage();
+                Event::StageFinished {
+                    index: i,
+                    stage: name,
+                    time: stage_start.elapsed(),
+                }
+            });
+
+            if let Some(checkpoints) = &self.checkpoints {
+                input = checkpoints
+                    .save(i, stage.as_ref(), keys[i], input, &stage_ctx)
+                    .map_err(|e| e.with_stage(stage.name()))?;
             }
         }
-        Ok(input)
+        Ok(ctx.on_finished(input, move || Event::PipelineFinished {
+            time: pipeline_start.elapsed(),
+        }))
+    }
+}
+
+/// Check that an error policy is usable.
+fn validate_policy(policy: &ErrorPolicy) -> std::result::Result<(), InvalidOption> {
+    match policy {
+        ErrorPolicy::AbortAbove(p) if !(0.0..=100.0).contains(p) => Err(InvalidOption::new(
+            "on_error.abort_above",
+            "must be a percentage between 0 and 100",
+        )),
+        _ => Ok(()),
     }
 }
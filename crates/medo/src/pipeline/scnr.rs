@@ -0,0 +1,113 @@
+//! Implementation of the subtractive chromatic noise reduction stage.
+//!
+//! Colour stacks often keep a green cast in their noise, from the Bayer matrix having twice as
+//! many green pixels, that colour calibration can't remove. Few deep sky objects are green, so
+//! green is limited to a neutral level of red and blue. Entries that are not colour are left
+//! unchanged.
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv::core::MatTraitConst;
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::{format, Result};
+use medo_stacker::color;
+
+use super::{Context, InvalidOption, Stage};
+
+/// Neutral levels that green is limited to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// The mean of red and blue, which removes green strongly.
+    AverageNeutral,
+    /// The brighter of red and blue, which removes green gently.
+    MaximumNeutral,
+}
+
+impl Default for Mode {
+    #[inline]
+    fn default() -> Self {
+        Self::AverageNeutral
+    }
+}
+
+impl From<Mode> for color::Neutral {
+    #[inline]
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::AverageNeutral => Self::Average,
+            Mode::MaximumNeutral => Self::Maximum,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Neutral level that green is limited to.
+    pub mode: Mode,
+    /// How much green is removed, from 0 for none to 1 for all of it.
+    pub amount: f32,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            amount: 1.0,
+        }
+    }
+}
+
+/// Remove green noise from an entry and return an owned entry.
+fn reduce(image: &Entry, opts: &Opts) -> Result<Entry> {
+    let image_mat = image.read_image()?;
+    if image_mat.as_ref().channels() < 3 {
+        return Entry::new_image(image.name(), image_mat.as_ref().clone());
+    }
+    let depth = image_mat.as_ref().depth();
+    let image_f = format::to_float(image_mat.as_ref())?;
+
+    let reduced = color::scnr(&image_f, opts.mode.into(), opts.amount)?;
+    let out = format::convert_cv_depth(&reduced, depth)?;
+    Entry::new_image(image.name(), out)
+}
+
+pub fn process<'scope>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    opts: &Opts,
+    ctx: &Context,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+    let opts = opts.clone();
+    super::map_entries(input, "scnr", ctx, move |e| {
+        Ok((reduce(e, &opts)?, Default::default()))
+    })
+}
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "scnr"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        if !(0.0..=1.0).contains(&self.amount) {
+            return Err(InvalidOption::new("amount", "must be from 0 to 1"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
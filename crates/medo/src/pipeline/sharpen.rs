@@ -1,6 +1,6 @@
 //! Implementation of the sharpening stage.
 
-use std::borrow::Cow;
+use serde::{Deserialize, Serialize};
 
 use medo_core::cv;
 use medo_core::cv::core::{Mat, Size};
@@ -8,27 +8,47 @@ use medo_core::cv::imgproc;
 use medo_core::entry::{Entries, Entry, OwnedEntryIter};
 use medo_core::Result;
 
-#[derive(Debug, Clone, Default)]
-pub struct Opts {}
+use super::{Context, InvalidOption, Stage};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Strength of the sharpening. 0 leaves entries unchanged.
+    pub amount: f64,
+    /// Standard deviation of the blur that details are found with, in pixels.
+    pub sigma: f64,
+}
+
+impl Default for Opts {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            amount: 0.5,
+            sigma: 3.0,
+        }
+    }
+}
 
 /// Sharpen and return an owned entry.
-fn sharpen(image: &Entry) -> Result<Entry> {
+///
+/// This is an unsharp mask: the blurred entry is subtracted from the entry, scaled by the amount.
+fn sharpen(image: &Entry, opts: &Opts) -> Result<Entry> {
     let image_mat = image.read_image()?;
     let mut result_1 = Mat::default();
     imgproc::gaussian_blur(
         image_mat.as_ref(),
         &mut result_1,
         Size::new(0, 0),
-        3.0,
+        opts.sigma,
         0.0,
         cv::core::BORDER_DEFAULT,
     )?;
     let mut result_2 = Mat::default();
     cv::core::add_weighted(
         image_mat.as_ref(),
-        1.5,
+        1.0 + opts.amount,
         &result_1,
-        -0.5,
+        -opts.amount,
         0.0,
         &mut result_2,
         -1,
@@ -39,24 +59,45 @@ fn sharpen(image: &Entry) -> Result<Entry> {
 
 pub fn process<'scope>(
     input: Entries<'scope, OwnedEntryIter<'scope>>,
-    _opts: &Opts,
+    opts: &Opts,
+    ctx: &Context,
 ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
-    Ok(Entries {
-        reference: Cow::Owned(sharpen(input.reference.as_ref())?),
-        entries: Box::new(input.entries.filter_map(|e| {
-            let span = tracing::info_span!("stage_sharpen");
-            let _enter = span.enter();
-
-            let name = e.name();
-            match sharpen(e.as_ref()) {
-                Err(e) => {
-                    tracing::error!(name = %name, error = %e, "failed to sharpen entry, discarding");
-                    None
-                }
-                Ok(e) => {
-                    Some(Cow::Owned(e))
-                }
-            }
-        })),
+    let opts = opts.clone();
+    super::map_entries(input, "sharpen", ctx, move |e| {
+        Ok((sharpen(e, &opts)?, Default::default()))
     })
 }
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "sharpen"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        if !(self.amount >= 0.0 && self.amount.is_finite()) {
+            return Err(InvalidOption::new(
+                "amount",
+                "must be a non-negative number",
+            ));
+        }
+        if !(self.sigma > 0.0 && self.sigma.is_finite()) {
+            return Err(InvalidOption::new("sigma", "must be greater than 0"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
@@ -1,33 +1,149 @@
 //! Implementation of the stacking stage.
 
 use std::borrow::Cow;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
 
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv::core::Mat;
 use medo_core::entry::{Entries, OwnedEntryIter};
 use medo_core::Result;
 use medo_stacker::stacker::Stacker;
 
-#[derive(Debug, Clone, Default)]
-pub struct Opts {}
+use super::{Context, InvalidOption, Stage};
+use crate::preview;
+
+/// Methods of combining entries into a stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    /// Per-pixel average.
+    Average,
+    /// Per-pixel median, which rejects outliers but keeps every entry in memory.
+    Median,
+}
+
+impl Default for Method {
+    #[inline]
+    fn default() -> Self {
+        Self::Average
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Method of combining entries.
+    pub method: Method,
+    /// File that a stretched preview of the stack is written to, as PNG or JPEG.
+    ///
+    /// The preview is updated after every entry is stacked, except for medians, which are only
+    /// previewed once done.
+    pub preview: Option<PathBuf>,
+    /// Length of the preview's longest side, in pixels.
+    pub preview_size: u32,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            method: Default::default(),
+            preview: None,
+            preview_size: preview::DEFAULT_SIZE,
+        }
+    }
+}
+
+/// Write a preview of a stack, warning instead of failing.
+fn write_preview(path: &Path, image: &Mat, opts: &Opts) {
+    if let Err(e) = preview::write(path, image, opts.preview_size) {
+        tracing::warn!(path = %path.display(), error = %e, "failed to write preview");
+    }
+}
 
 pub fn process<'scope>(
     input: Entries<'scope, OwnedEntryIter<'scope>>,
-    _opts: &Opts,
+    opts: &Opts,
+    ctx: &Context,
 ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
     let span = tracing::info_span!("stage_stacking");
     let _enter = span.enter();
 
-    let iter = [input.reference].into_iter().chain(input.entries);
+    // Name of the entry being stacked
+    let current = RefCell::new(String::new());
+    let iter = [input.reference]
+        .into_iter()
+        .chain(input.entries)
+        .inspect(|e| *current.borrow_mut() = e.name().into_owned());
 
-    let mut stacker = Stacker::average(iter)?;
-    for (n, r) in stacker.by_ref().enumerate() {
-        // FIXME: identify image that failed to stack
-        if let Err(e) = r {
-            tracing::error!(index = n, error = %e, "failed to stack entry, discarding")
+    let stacker = match opts.method {
+        Method::Average => Stacker::average(iter),
+        Method::Median => Stacker::median(iter),
+    };
+    let mut stacker = stacker.map_err(|e| e.with_entry(current.borrow().as_str()))?;
+    let preview = match opts.method {
+        Method::Average => opts.preview.as_deref(),
+        // The median is only known once every entry is stacked
+        Method::Median => None,
+    };
+    if let Some(path) = preview {
+        write_preview(path, stacker.image(), opts);
+    }
+    loop {
+        ctx.check_cancelled()?;
+        let start = std::time::Instant::now();
+        let r = match stacker.next() {
+            Some(r) => r,
+            None => break,
+        };
+        let name = current.borrow();
+        ctx.entry_processed(&name, start.elapsed(), r.as_ref().err().map(|e| e.kind()));
+        match r {
+            Ok(()) => {
+                if let Some(path) = preview {
+                    write_preview(path, stacker.image(), opts);
+                }
+            }
+            Err(e) => {
+                tracing::error!(name = %name, error = %e, "failed to stack entry, discarding")
+            }
         }
     }
+    if let (Some(path), Method::Median) = (&opts.preview, opts.method) {
+        write_preview(path, stacker.image(), opts);
+    }
 
     Ok(Entries {
         reference: Cow::Owned(stacker.leak()),
         entries: Box::new(std::iter::empty()),
     })
 }
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "stacking"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        if self.preview_size == 0 {
+            return Err(InvalidOption::new("preview_size", "must be positive"));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
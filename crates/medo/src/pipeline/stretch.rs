@@ -0,0 +1,363 @@
+//! Implementation of the stretching stage.
+//!
+//! Stacks are linear, and look black in most viewers. This stage stretches them for display,
+//! so it belongs at the end of a pipeline: every other stage models the sky, its gradients or
+//! its colour, and expects linear data. Stretched entries are 32-bit floating point, from 0 to 1,
+//! so that no precision is lost before they are written.
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::cv::core::{Mat, MatTraitConst};
+use medo_core::entry::{Entries, Entry, OwnedEntryIter};
+use medo_core::Result;
+use medo_stacker::stretch::{self, Ghs, Mtf};
+
+use super::{Context, InvalidOption, Stage};
+
+/// Functions that entries can be stretched by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Function {
+    /// Midtones transfer function, with shadows and midtones found from the background.
+    Mtf,
+    /// Inverse hyperbolic sine, which keeps stars from saturating.
+    Arcsinh,
+    /// Generalized hyperbolic stretch, which adds contrast around a chosen brightness.
+    Ghs,
+}
+
+impl Default for Function {
+    #[inline]
+    fn default() -> Self {
+        Self::Mtf
+    }
+}
+
+/// Options of the midtones transfer function.
+///
+/// Values are normalized, from 0 to 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MtfOpts {
+    /// Value that is mapped to black.
+    ///
+    /// Defaults to `shadows_clip` standard deviations from the median.
+    pub shadows: Option<f32>,
+    /// Value, after clipping, that is mapped to middle grey.
+    ///
+    /// Defaults to the value that stretches the median to `target_background`.
+    pub midtones: Option<f32>,
+    /// Value that is mapped to white.
+    pub highlights: f32,
+    /// Where shadows are clipped, in standard deviations from the median.
+    pub shadows_clip: f32,
+    /// Brightness that the median is stretched to.
+    pub target_background: f32,
+}
+
+impl Default for MtfOpts {
+    fn default() -> Self {
+        Self {
+            shadows: None,
+            midtones: None,
+            highlights: 1.0,
+            shadows_clip: stretch::SHADOWS_CLIP,
+            target_background: stretch::TARGET_BACKGROUND,
+        }
+    }
+}
+
+/// Options of the inverse hyperbolic sine stretch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArcsinhOpts {
+    /// Strength of the stretch. 0 leaves entries unchanged.
+    pub factor: f32,
+    /// Normalized value that is mapped to black, before stretching.
+    pub black_point: f32,
+}
+
+impl Default for ArcsinhOpts {
+    fn default() -> Self {
+        Self {
+            factor: 100.0,
+            black_point: 0.0,
+        }
+    }
+}
+
+/// Options of the generalized hyperbolic stretch.
+///
+/// Values are normalized, from 0 to 1.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GhsOpts {
+    /// Strength of the stretch, as `ln(D + 1)`. 0 leaves entries unchanged.
+    pub stretch: f32,
+    /// Shape of the stretch, from -5 to 15. Higher values focus contrast around the symmetry
+    /// point.
+    pub local_intensity: f32,
+    /// Value around which contrast is added the most.
+    ///
+    /// Defaults to the median, which is usually the background.
+    pub symmetry_point: Option<f32>,
+    /// Value below which the stretch is linear.
+    pub shadow_protection: f32,
+    /// Value above which the stretch is linear.
+    pub highlight_protection: f32,
+}
+
+impl Default for GhsOpts {
+    fn default() -> Self {
+        Self {
+            stretch: 3.0,
+            local_intensity: 0.0,
+            symmetry_point: None,
+            shadow_protection: 0.0,
+            highlight_protection: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Opts {
+    /// Function entries are stretched by.
+    pub function: Function,
+    /// Whether colour channels are stretched together, keeping the colour balance.
+    ///
+    /// Unlinked channels are stretched by their own statistics, which neutralizes a colour
+    /// cast but may shift colours.
+    pub linked: bool,
+    /// Options used when the function is `mtf`.
+    pub mtf: MtfOpts,
+    /// Options used when the function is `arcsinh`.
+    pub arcsinh: ArcsinhOpts,
+    /// Options used when the function is `ghs`.
+    pub ghs: GhsOpts,
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Self {
+            function: Default::default(),
+            linked: true,
+            mtf: Default::default(),
+            arcsinh: Default::default(),
+            ghs: Default::default(),
+        }
+    }
+}
+
+/// Find the midtones transfer of each channel of a normalized image.
+fn mtf_params(image: &Mat, opts: &Opts) -> Result<Vec<Mtf>> {
+    let mtf = &opts.mtf;
+    Ok(stretch::median_mad(image, opts.linked)?
+        .into_iter()
+        .map(|stats| {
+            let (median, mad) = match stats {
+                Some(s) => s,
+                None => return Mtf::default(),
+            };
+            let auto = Mtf::auto(median, mad, mtf.shadows_clip, mtf.target_background);
+            let shadows = mtf.shadows.unwrap_or(auto.shadows);
+            let midtones = mtf.midtones.unwrap_or_else(|| {
+                // Stretch the median to the target once shadows and highlights are clipped
+                let background = (median - shadows) / (mtf.highlights - shadows);
+                if background > 0.0 && background < 1.0 {
+                    stretch::mtf(mtf.target_background, background)
+                } else {
+                    0.5
+                }
+            });
+            Mtf {
+                shadows,
+                midtones,
+                highlights: mtf.highlights,
+            }
+        })
+        .collect())
+}
+
+/// Get the generalized hyperbolic stretch of each channel of a normalized image.
+fn ghs_params(image: &Mat, opts: &Opts) -> Result<Vec<Ghs>> {
+    let ghs = &opts.ghs;
+    let params = |symmetry_point| Ghs {
+        stretch: ghs.stretch,
+        local_intensity: ghs.local_intensity,
+        symmetry_point,
+        shadow_protection: ghs.shadow_protection,
+        highlight_protection: ghs.highlight_protection,
+    };
+    Ok(match ghs.symmetry_point {
+        Some(sp) => vec![params(sp); image.channels() as usize],
+        None => stretch::median_mad(image, opts.linked)?
+            .into_iter()
+            .map(|stats| params(stats.map_or(0.0, |(median, _)| median)))
+            .collect(),
+    })
+}
+
+/// Stretch and return an owned entry.
+fn stretch(image: &Entry, opts: &Opts) -> Result<Entry> {
+    let image_mat = image.read_image()?;
+    let normalized = stretch::normalize(image_mat.as_ref())?;
+
+    let stretched = match opts.function {
+        Function::Mtf => stretch::apply_mtf(&normalized, &mtf_params(&normalized, opts)?)?,
+        Function::Arcsinh => {
+            let ArcsinhOpts {
+                factor,
+                black_point,
+            } = opts.arcsinh;
+            let f = |x: f32| {
+                let x = ((x - black_point) / (1.0 - black_point)).clamp(0.0, 1.0);
+                stretch::arcsinh(factor, x)
+            };
+            if opts.linked {
+                stretch::map_luminance(&normalized, f)?
+            } else {
+                stretch::map_channels(&normalized, |_, x| f(x))?
+            }
+        }
+        Function::Ghs => {
+            let curves = ghs_params(&normalized, opts)?
+                .iter()
+                .map(Ghs::curve)
+                .collect::<Vec<_>>();
+            if opts.linked {
+                stretch::map_luminance(&normalized, &curves[0])?
+            } else {
+                stretch::map_channels(&normalized, |c, x| curves[c](x))?
+            }
+        }
+    };
+
+    Entry::new_image(image.name(), stretched)
+}
+
+pub fn process<'scope>(
+    input: Entries<'scope, OwnedEntryIter<'scope>>,
+    opts: &Opts,
+    ctx: &Context,
+) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+    let opts = opts.clone();
+    super::map_entries(input, "stretch", ctx, move |e| {
+        Ok((stretch(e, &opts)?, Default::default()))
+    })
+}
+
+/// Check that a value is from 0 to 1.
+#[inline]
+fn is_normalized(x: f32) -> bool {
+    (0.0..=1.0).contains(&x)
+}
+
+impl Stage for Opts {
+    #[inline]
+    fn name(&self) -> &str {
+        "stretch"
+    }
+
+    #[inline]
+    fn options(&self) -> Result<serde_json::Value> {
+        super::serialize_options(self)
+    }
+
+    fn validate(&self) -> std::result::Result<(), InvalidOption> {
+        let mtf = &self.mtf;
+        if !(mtf.highlights > 0.0 && mtf.highlights <= 1.0) {
+            return Err(InvalidOption::new(
+                "mtf.highlights",
+                "must be greater than 0 and at most 1",
+            ));
+        }
+        if let Some(shadows) = mtf.shadows {
+            if !(shadows >= 0.0 && shadows < mtf.highlights) {
+                return Err(InvalidOption::new(
+                    "mtf.shadows",
+                    "must be at least 0 and less than the highlights",
+                ));
+            }
+        }
+        if let Some(midtones) = mtf.midtones {
+            if !(midtones > 0.0 && midtones < 1.0) {
+                return Err(InvalidOption::new(
+                    "mtf.midtones",
+                    "must be between 0 and 1",
+                ));
+            }
+        }
+        if !mtf.shadows_clip.is_finite() {
+            return Err(InvalidOption::new("mtf.shadows_clip", "must be a number"));
+        }
+        if !(mtf.target_background > 0.0 && mtf.target_background < 1.0) {
+            return Err(InvalidOption::new(
+                "mtf.target_background",
+                "must be between 0 and 1",
+            ));
+        }
+
+        let arcsinh = &self.arcsinh;
+        if !(arcsinh.factor >= 0.0 && arcsinh.factor.is_finite()) {
+            return Err(InvalidOption::new(
+                "arcsinh.factor",
+                "must be a non-negative number",
+            ));
+        }
+        if !(arcsinh.black_point >= 0.0 && arcsinh.black_point < 1.0) {
+            return Err(InvalidOption::new(
+                "arcsinh.black_point",
+                "must be at least 0 and less than 1",
+            ));
+        }
+
+        let ghs = &self.ghs;
+        if !(ghs.stretch >= 0.0 && ghs.stretch.is_finite()) {
+            return Err(InvalidOption::new(
+                "ghs.stretch",
+                "must be a non-negative number",
+            ));
+        }
+        if !(-5.0..=15.0).contains(&ghs.local_intensity) {
+            return Err(InvalidOption::new(
+                "ghs.local_intensity",
+                "must be from -5 to 15",
+            ));
+        }
+        if !ghs.symmetry_point.map_or(true, is_normalized) {
+            return Err(InvalidOption::new(
+                "ghs.symmetry_point",
+                "must be from 0 to 1",
+            ));
+        }
+        if !is_normalized(ghs.shadow_protection) {
+            return Err(InvalidOption::new(
+                "ghs.shadow_protection",
+                "must be from 0 to 1",
+            ));
+        }
+        if !is_normalized(ghs.highlight_protection) {
+            return Err(InvalidOption::new(
+                "ghs.highlight_protection",
+                "must be from 0 to 1",
+            ));
+        }
+        if ghs.shadow_protection > ghs.highlight_protection {
+            return Err(InvalidOption::new(
+                "ghs.shadow_protection",
+                "must be at most the highlight protection",
+            ));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        process(input, self, ctx)
+    }
+}
@@ -0,0 +1,73 @@
+//! Small previews of stacks, for watching a stack's progress.
+//!
+//! Previews are scaled down, automatically stretched and written as 8-bit PNG or JPEG, so that
+//! they can be opened by any image viewer. Colour channels are stretched together, keeping the
+//! stack's colour balance.
+
+use std::path::Path;
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, Size};
+use medo_core::cv::imgproc;
+use medo_core::{util, Error, Result};
+use medo_stacker::stretch;
+
+/// Default length of a preview's longest side, in pixels.
+pub const DEFAULT_SIZE: u32 = 1024;
+
+/// Render a preview of an image, as 8-bit.
+///
+/// # Parameters
+/// - `image`: The image to preview.
+/// - `max_size`: Length of the preview's longest side, in pixels. Images that are smaller are
+///   not scaled up.
+pub fn render(image: &Mat, max_size: u32) -> Result<Mat> {
+    let size = image.size()?;
+    let scale = max_size as f64 / size.width.max(size.height) as f64;
+    let image = if scale < 1.0 {
+        let preview_size = Size::new(
+            ((size.width as f64 * scale).round() as i32).max(1),
+            ((size.height as f64 * scale).round() as i32).max(1),
+        );
+        let mut small = Mat::default();
+        imgproc::resize(
+            image,
+            &mut small,
+            preview_size,
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        small
+    } else {
+        image.clone()
+    };
+
+    let mut stretched = stretch::auto_stretch(&image, true)?;
+    // Invalid pixels are shown as black
+    cv::core::patch_na_ns(&mut stretched, 0.0)?;
+    let mut out = Mat::default();
+    stretched.convert_to(&mut out, cv::core::CV_8U, u8::MAX as f64, 0.0)?;
+    Ok(out)
+}
+
+/// Render and write a preview of an image.
+///
+/// The format is chosen by the file's extension, which must be PNG or JPEG. The preview is
+/// written to a hidden file next to `path`, then moved over it, so that viewers never read a
+/// preview that is half written.
+pub fn write(path: &Path, image: &Mat, max_size: u32) -> Result<()> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    if !matches!(extension.as_deref(), Some("png" | "jpg" | "jpeg")) {
+        return Err(Error::UnsupportedFormat(path.to_owned()));
+    }
+
+    let preview = render(image, max_size)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let hidden = path.with_file_name(format!(".{}", name));
+    util::write_image(&hidden, &preview)?;
+    std::fs::rename(hidden, path)?;
+    Ok(())
+}
@@ -0,0 +1,305 @@
+//! Project files.
+//!
+//! A project file records how a project's groups were made: the files each group was given,
+//! its pipeline, the reference that was chosen and the master it produced. Paths are relative to
+//! the project file, so a project can be moved along with its images. A group is only processed
+//! again if its files or pipeline changed since its master was produced.
+
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::entry::{Entry, OwnedEntries};
+use medo_core::util::ContentHasher;
+use medo_core::{format, Error, Result};
+
+use super::{Project, Summary};
+use crate::group::Group;
+use crate::pipeline::checkpoint::{Checkpoints, Key};
+use crate::pipeline::{self, Context, Pipeline, Registry};
+use crate::reference::{self, Selection};
+
+/// A group, as recorded in a project file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupFile {
+    /// Name of the group.
+    pub name: String,
+    /// Files the group is made of.
+    pub inputs: Vec<PathBuf>,
+    /// The file every other file is aligned to.
+    ///
+    /// Chosen by quality, and recorded, if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<PathBuf>,
+    /// File the group's master is written to.
+    pub output: PathBuf,
+    /// Format the group's master is written in.
+    ///
+    /// Defaults to the format of the output's extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_format: Option<format::Format>,
+    /// Bit depth the group's master is written with.
+    ///
+    /// Defaults to the format's, or to the master's own depth.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_depth: Option<format::Depth>,
+    /// Identifies the files and pipeline that produced the group's master.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// The group's pipeline.
+    pub pipeline: pipeline::Config,
+}
+
+/// A project, as recorded in a project file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectFile {
+    /// Directory in which the outputs of every group's stages are saved.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub groups: Vec<GroupFile>,
+}
+
+impl ProjectFile {
+    /// Describe a project whose groups have been processed.
+    ///
+    /// # Parameters
+    /// - `project`: The project to describe. Entries must be files.
+    /// - `output`: Gives the file each group's master was written to, by name.
+    /// - `output_format`: The format masters were written in.
+    /// - `output_depth`: The bit depth masters were written with.
+    pub fn from_project<F: Fn(&str) -> PathBuf>(
+        project: &Project,
+        output: F,
+        output_format: Option<format::Format>,
+        output_depth: Option<format::Depth>,
+    ) -> Result<Self> {
+        let checkpoint_dir = project
+            .groups
+            .first()
+            .and_then(|g| g.pipeline.checkpoints.as_ref())
+            .and_then(|c| c.dir().parent())
+            .map(Path::to_owned);
+        let groups = project
+            .groups
+            .iter()
+            .map(|group| {
+                let path = |e: &Entry| match e {
+                    Entry::Path(p) => Ok(p.path().to_owned()),
+                    _ => Err(Error::Other(format!(
+                        "entry `{}` of group `{}` is not a file",
+                        e.name(),
+                        group.name
+                    ))),
+                };
+                let config = group.pipeline.to_config()?;
+                Ok(GroupFile {
+                    name: group.name.clone(),
+                    inputs: std::iter::once(&group.entries.reference)
+                        .chain(&group.entries.entries)
+                        .map(path)
+                        .collect::<Result<_>>()?,
+                    reference: Some(path(&group.entries.reference)?),
+                    output: output(&group.name),
+                    output_format,
+                    output_depth,
+                    fingerprint: match group.pipeline_output {
+                        Some(_) => Some(fingerprint(&group.entries, &config)?),
+                        None => None,
+                    },
+                    pipeline: config,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            checkpoint_dir,
+            groups,
+        })
+    }
+
+    /// Read a project file.
+    ///
+    /// Paths are resolved relative to the file.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let mut file: Self =
+            serde_path_to_error::deserialize(&mut toml::Deserializer::new(&contents))
+                .map_err(|e| Error::Other(format!("{}: {}", path.display(), e)))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        file.map_paths(|p| base.join(p));
+        Ok(file)
+    }
+
+    /// Write this to a project file.
+    ///
+    /// Paths inside the file's directory are written relative to it.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let base = absolute(path.parent().unwrap_or_else(|| Path::new("")))?;
+        let mut file = self.clone();
+        let mut result = Ok(());
+        file.map_paths(|p| match absolute(p) {
+            Ok(abs) => abs.strip_prefix(&base).map_or(abs.clone(), Path::to_owned),
+            Err(e) => {
+                result = Err(e);
+                p.to_owned()
+            }
+        });
+        result?;
+
+        // TOML has no null, and no newtype variants, so the file is written through JSON
+        let invalid =
+            |e: &dyn std::fmt::Display| Error::Other(format!("{}: {}", path.display(), e));
+        let mut value = serde_json::to_value(&file).map_err(|e| invalid(&e))?;
+        remove_nulls(&mut value);
+        // Tables are written after plain values, as TOML requires
+        let contents = toml::Value::try_from(value)
+            .and_then(|v| toml::to_string_pretty(&v))
+            .map_err(|e| invalid(&e))?;
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Replace every path in this file.
+    fn map_paths<F: FnMut(&Path) -> PathBuf>(&mut self, mut f: F) {
+        if let Some(dir) = &mut self.checkpoint_dir {
+            *dir = f(dir);
+        }
+        for group in &mut self.groups {
+            for input in &mut group.inputs {
+                *input = f(input);
+            }
+            if let Some(reference) = &mut group.reference {
+                *reference = f(reference);
+            }
+            group.output = f(&group.output);
+        }
+    }
+
+    /// Process every group whose files or pipeline changed since its master was produced.
+    ///
+    /// Masters are written to their groups' outputs, and the groups' fingerprints and references
+    /// are updated, so this should be written back to its file afterwards. As with
+    /// [`Project::process_with`], a group that fails to be processed does not stop the others.
+    ///
+    /// # Parameters
+    /// - `registry`: The stages that pipelines can be made of.
+    /// - `force`: Process every group, even if it did not change.
+    /// - `ctx`: The context groups are processed in.
+    pub fn run(&mut self, registry: &Registry, force: bool, ctx: &Context) -> Result<Summary> {
+        let mut summary = Summary::default();
+        for record in &mut self.groups {
+            let result = run_group(record, self.checkpoint_dir.as_deref(), registry, force, ctx);
+            match result {
+                Ok(true) => summary.processed.push(record.name.clone()),
+                Ok(false) => summary.skipped.push(record.name.clone()),
+                Err(e) if e.is_cancelled() => return Err(e),
+                Err(e) => {
+                    tracing::error!(group = %record.name, error = %e, "failed to process group");
+                    summary.failed.push((record.name.clone(), e));
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Process a group if it changed, returning whether it was processed.
+fn run_group(
+    record: &mut GroupFile,
+    checkpoint_dir: Option<&Path>,
+    registry: &Registry,
+    force: bool,
+    ctx: &Context,
+) -> Result<bool> {
+    let mut pipeline = Pipeline::from_config(&record.pipeline, registry)?;
+    pipeline.checkpoints = checkpoint_dir.map(|d| Checkpoints::new(d.join(&record.name)));
+
+    // The recorded reference is kept, so that it is not chosen again
+    let entries = record
+        .inputs
+        .iter()
+        .map(|p| Entry::new_path_owned(p.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let selection = match &record.reference {
+        Some(r) => {
+            let index = record.inputs.iter().position(|p| p == r).ok_or_else(|| {
+                Error::Other(format!("reference `{}` is not an input", r.display()))
+            })?;
+            Selection::Named(entries[index].name().into_owned())
+        }
+        None => Selection::Quality,
+    };
+    let entries = reference::select(entries, &selection)?;
+
+    let fingerprint = fingerprint(&entries, &record.pipeline)?;
+    if !force
+        && record.fingerprint.as_deref() == Some(fingerprint.as_str())
+        && record.output.is_file()
+    {
+        tracing::info!(group = %record.name, "group is up to date, skipping");
+        return Ok(false);
+    }
+
+    let mut group = Group {
+        name: record.name.clone(),
+        pipeline,
+        entries,
+        pipeline_output: None,
+        failures: vec![],
+    };
+    let output = group.process_with(ctx)?;
+    format::write_image_as(
+        &record.output,
+        output.reference.read_image()?.as_ref(),
+        record.output_format,
+        record.output_depth,
+    )?;
+    tracing::info!(group = %record.name, output = %record.output.display(), "wrote master");
+
+    if let Entry::Path(p) = &group.entries.reference {
+        record.reference = Some(p.path().to_owned());
+    }
+    record.fingerprint = Some(fingerprint);
+    Ok(true)
+}
+
+/// Identify a group's files and pipeline.
+///
+/// The pipeline is identified as it is written to a project file, without null values, so that
+/// a pipeline is identified alike before and after it is written.
+fn fingerprint(entries: &OwnedEntries, config: &pipeline::Config) -> Result<String> {
+    let mut hasher = ContentHasher::default();
+    let input = Key::input(std::iter::once(&entries.reference).chain(&entries.entries))?;
+    hasher.write(input.to_string().as_bytes());
+    let mut config = serde_json::to_value(config).map_err(|e| Error::Other(e.to_string()))?;
+    remove_nulls(&mut config);
+    hasher.write(config.to_string().as_bytes());
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+/// Remove null values from maps, recursively.
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, v| !v.is_null());
+            map.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+/// Make a path absolute, without resolving links.
+fn absolute(path: &Path) -> Result<PathBuf> {
+    Ok(if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir()?.join(path)
+    })
+}
@@ -0,0 +1,190 @@
+//! Projects of many groups.
+//!
+//! A night of imaging usually produces entries through several filters, and a target is often
+//! imaged over several nights. Each of these is stacked as its own group, with its own pipeline,
+//! producing its own master.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use medo_core::entry::Entry;
+use medo_core::{Error, Result};
+
+use crate::group::Group;
+use crate::integration::{self, Integration};
+use crate::metadata::Metadata;
+use crate::pipeline::checkpoint::Checkpoints;
+use crate::pipeline::{Context, Pipeline};
+use crate::reference::{self, Selection};
+
+pub mod file;
+pub use file::ProjectFile;
+
+/// Name of the group entries belong to when they are not grouped.
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Metadata that entries are grouped by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupBy {
+    /// Group entries by filter.
+    pub filter: bool,
+    /// Group entries by exposure time.
+    pub exposure: bool,
+    /// Group entries by binning factor.
+    pub binning: bool,
+    /// Group entries by the night they were captured in.
+    pub session: bool,
+}
+
+impl GroupBy {
+    /// Group entries by the settings that frames of a single stack share: filter, exposure time
+    /// and binning.
+    ///
+    /// Entries are only split by the settings that are known, so entries whose metadata can't
+    /// be read still belong to a single group.
+    pub const SETTINGS: Self = Self {
+        filter: true,
+        exposure: true,
+        binning: true,
+        session: false,
+    };
+
+    /// Get the name of the group that entries with some metadata belong to.
+    ///
+    /// Unknown values are left out of the name, so entries with no known metadata belong to
+    /// the [default group](DEFAULT_GROUP).
+    pub fn group_name(&self, metadata: &Metadata) -> String {
+        let key = Metadata {
+            filter: metadata.filter.clone().filter(|_| self.filter),
+            exposure: metadata.exposure.filter(|_| self.exposure),
+            binning: metadata.binning.filter(|_| self.binning),
+            session: metadata.session.clone().filter(|_| self.session),
+            capture_time: None,
+        }
+        .to_string();
+        if key.is_empty() {
+            DEFAULT_GROUP.to_owned()
+        } else {
+            key
+        }
+    }
+
+    /// Split entries into groups, ordered by name.
+    ///
+    /// Entries keep their relative order within each group.
+    pub fn split(&self, entries: Vec<Entry>) -> BTreeMap<String, Vec<Entry>> {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for entry in entries {
+            let name = self.group_name(&Metadata::read(&entry));
+            groups.entry(name).or_default().push(entry);
+        }
+        groups
+    }
+}
+
+/// Outcome of processing a project.
+#[derive(Debug, Default)]
+pub struct Summary {
+    /// Names of the groups that were processed.
+    pub processed: Vec<String>,
+    /// Names of the groups that were up to date, and were not processed again.
+    pub skipped: Vec<String>,
+    /// Names of the groups that failed to be processed, and why.
+    pub failed: Vec<(String, Error)>,
+}
+
+/// A collection of groups.
+pub struct Project {
+    pub groups: Vec<Group>,
+}
+
+impl Project {
+    /// Create a project by grouping entries by their metadata.
+    ///
+    /// # Parameters
+    /// - `entries`: The entries to group.
+    /// - `by`: The metadata to group entries by.
+    /// - `pipeline`: The pipeline each group is given. Its checkpoints are saved in a directory
+    ///   named after each group.
+    /// - `selection`: How each group's reference is chosen. A reference given by name or file is
+    ///   only used by the group it belongs to, and the other groups choose theirs by the
+    ///   [default strategy](Selection::default).
+    pub fn group(
+        entries: Vec<Entry>,
+        by: GroupBy,
+        pipeline: &Pipeline,
+        selection: &Selection,
+    ) -> Result<Self> {
+        let groups = by.split(entries);
+        // Without any group to use it, the reference is reported as missing
+        let found = groups
+            .values()
+            .any(|entries| reference::find(entries, selection).is_some());
+        let fallback = Selection::default();
+        let groups = groups
+            .into_iter()
+            .map(|(name, entries)| {
+                tracing::info!(group = %name, entries = entries.len(), "created group");
+                let selection = if found && reference::find(&entries, selection).is_none() {
+                    &fallback
+                } else {
+                    selection
+                };
+                // Groups save their stages' outputs separately
+                let mut pipeline = pipeline.clone();
+                pipeline.checkpoints = pipeline
+                    .checkpoints
+                    .map(|c| Checkpoints::new(c.dir().join(&name)));
+                Ok(Group {
+                    entries: reference::select(entries, selection)?,
+                    name,
+                    pipeline,
+                    pipeline_output: None,
+                    failures: vec![],
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { groups })
+    }
+
+    /// Get a group by name.
+    #[inline]
+    pub fn group_by_name(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|g| g.name == name)
+    }
+
+    /// Process every group.
+    #[inline]
+    pub fn process(&mut self) -> Result<Summary> {
+        self.process_with(&Context::default())
+    }
+
+    /// Process every group in a context.
+    ///
+    /// A group that fails to be processed does not stop the others. This only fails if the
+    /// context is cancelled.
+    pub fn process_with(&mut self, ctx: &Context) -> Result<Summary> {
+        let mut summary = Summary::default();
+        for group in &mut self.groups {
+            match group.process_with(ctx) {
+                Ok(_) => summary.processed.push(group.name.clone()),
+                Err(e) if e.is_cancelled() => return Err(e),
+                Err(e) => {
+                    tracing::error!(group = %group.name, error = %e, "failed to process group");
+                    summary.failed.push((group.name.clone(), e));
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Register the masters of processed groups to a common reference, and integrate them.
+    ///
+    /// See [`integration::integrate`].
+    #[inline]
+    pub fn integrate(&self, opts: &integration::Opts) -> Result<Integration> {
+        integration::integrate(&self.groups, opts)
+    }
+}
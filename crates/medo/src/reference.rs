@@ -0,0 +1,132 @@
+//! Selection of a group's reference entry.
+
+use std::path::{Path, PathBuf};
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use medo_core::entry::{Entry, OwnedEntries};
+use medo_core::{Error, Result};
+use medo_stacker::star;
+
+use crate::metadata::capture_time;
+
+/// Strategy used to choose the reference among a group of entries.
+///
+/// Every other entry is aligned to the reference, so a blurry or cloudy reference will ruin the
+/// alignment of the whole group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Selection {
+    /// The first entry, in the order they were given.
+    First,
+    /// The entry with the best quality score, i.e. the most and tightest stars.
+    Quality,
+    /// The chronologically middle entry.
+    Middle,
+    /// The entry with the given name.
+    Named(String),
+    /// The entry read from the given file.
+    File(PathBuf),
+}
+
+impl Default for Selection {
+    #[inline]
+    fn default() -> Self {
+        Self::Quality
+    }
+}
+
+/// Find the index of the entry with the best quality score.
+fn best_quality(entries: &[Entry]) -> usize {
+    let scores = entries
+        .par_iter()
+        .map(|e| {
+            let span = tracing::info_span!("reference_selection");
+            let _enter = span.enter();
+
+            let name = e.name();
+            let metrics = e
+                .read_image()
+                .and_then(|i| star::measure(&i, Default::default()));
+            match metrics {
+                Ok(m) => {
+                    tracing::debug!(%name, stars = m.stars, fwhm = m.fwhm, "measured entry");
+                    m.score()
+                }
+                Err(e) => {
+                    tracing::warn!(%name, error = %e, "failed to measure entry");
+                    f32::NEG_INFINITY
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Ties are resolved in favour of the earliest entry
+    scores
+        .iter()
+        .enumerate()
+        .fold(0, |best, (i, s)| if *s > scores[best] { i } else { best })
+}
+
+/// Find the index of the chronologically middle entry.
+///
+/// Entries with no known capture time are ordered after the others, by name.
+fn middle(entries: &[Entry]) -> usize {
+    let mut order = entries
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            let time = capture_time(e);
+            (time.is_none(), time, e.name(), i)
+        })
+        .collect::<Vec<_>>();
+    order.sort();
+    order[order.len() / 2].3
+}
+
+/// Find the entry that a [`Named`](Selection::Named) or [`File`](Selection::File) selection
+/// refers to.
+///
+/// Returns `None` for other selections, or if no entry matches.
+pub fn find(entries: &[Entry], selection: &Selection) -> Option<usize> {
+    match selection {
+        Selection::Named(name) => entries.iter().position(|e| e.name() == name.as_str()),
+        Selection::File(path) => {
+            let path = canonical(path);
+            entries
+                .iter()
+                .position(|e| matches!(e, Entry::Path(p) if canonical(p.path()) == path))
+        }
+        _ => None,
+    }
+}
+
+/// Choose the reference among some entries.
+pub fn select(mut entries: Vec<Entry>, selection: &Selection) -> Result<OwnedEntries> {
+    if entries.is_empty() {
+        return Err(Error::OtherStatic("no entries to choose a reference from"));
+    }
+
+    let index = match selection {
+        Selection::First => 0,
+        Selection::Quality => best_quality(&entries),
+        Selection::Middle => middle(&entries),
+        Selection::Named(name) => find(&entries, selection)
+            .ok_or_else(|| Error::Other(format!("reference entry `{}` not found", name)))?,
+        Selection::File(path) => find(&entries, selection).ok_or_else(|| {
+            Error::Other(format!(
+                "reference file `{}` is not among the inputs",
+                canonical(path).display()
+            ))
+        })?,
+    };
+
+    let reference = entries.remove(index);
+    tracing::info!(reference = %reference.name(), "selected reference");
+    Ok(OwnedEntries { reference, entries })
+}
+
+/// Resolve a path, so that different spellings of the same file compare equal.
+#[inline]
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_owned())
+}
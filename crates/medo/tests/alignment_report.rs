@@ -0,0 +1,58 @@
+use std::time::Duration;
+
+use medo::pipeline::alignment::report::{self, Measurement, Record};
+
+fn records() -> Vec<Record> {
+    vec![
+        Record {
+            name: "light_1.tif".to_owned(),
+            time: Duration::from_secs(1),
+            outcome: Ok(Measurement {
+                shift_x: 1.5,
+                shift_y: -2.0,
+                rotation: 0.0,
+                scale: 1.0,
+                stars: 12,
+                matched_stars: 0,
+                rms_residual: f64::NAN,
+            }),
+        },
+        Record {
+            name: "light \"2\".tif".to_owned(),
+            time: Duration::from_secs(2),
+            outcome: Err("too few stars,\nneed 4".to_owned()),
+        },
+    ]
+}
+
+#[test]
+fn alignment_report_json() {
+    let json: serde_json::Value =
+        serde_json::from_str(&report::to_json(&records()).unwrap()).unwrap();
+    assert_eq!(json[0]["name"], "light_1.tif");
+    assert_eq!(json[0]["shift_x"], 1.5);
+    assert_eq!(json[0]["stars"], 12);
+    assert!(json[0]["rms_residual"].is_null());
+    assert!(json[0].get("error").is_none());
+    assert_eq!(json[1]["name"], "light \"2\".tif");
+    assert_eq!(json[1]["error"], "too few stars,\nneed 4");
+    assert!(json[1].get("shift_x").is_none());
+}
+
+#[test]
+fn alignment_report_csv() {
+    let csv = report::to_csv(&records()).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "name,time_secs,shift_x,shift_y,rotation,scale,stars,matched_stars,rms_residual,error"
+    );
+    assert_eq!(
+        lines.next().unwrap(),
+        "\"light_1.tif\",1.0,1.5,-2.0,0.0,1.0,12,0,,"
+    );
+    assert_eq!(
+        csv.lines().skip(2).collect::<Vec<_>>().join("\n"),
+        "\"light \"\"2\"\".tif\",2.0,,,,,,,,\"too few stars,\nneed 4\""
+    );
+}
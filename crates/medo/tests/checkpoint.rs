@@ -0,0 +1,128 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use medo::core::cv;
+use medo::core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar};
+use medo::core::entry::{Entries, Entry, OwnedEntries, OwnedEntryIter};
+use medo::core::util::WorkDir;
+use medo::core::Result;
+use medo::pipeline::checkpoint::{Checkpoints, Key};
+use medo::pipeline::{Context, Pipeline, Stage};
+
+/// A stage that passes entries through, counting how many times it is run.
+#[derive(Debug, Default)]
+struct Passthrough {
+    runs: AtomicUsize,
+}
+
+impl Stage for Passthrough {
+    fn name(&self) -> &str {
+        "passthrough"
+    }
+
+    fn options(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::Value::Null)
+    }
+
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        _ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        Ok(input)
+    }
+}
+
+/// A floating point image, from 0 to 1, with an invalid first row.
+fn float_image(value: f64) -> Mat {
+    let image =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(value)).unwrap();
+    let mut values = image.reshape(1, 0).unwrap();
+    values.at_row_mut::<f32>(0).unwrap().fill(f32::NAN);
+    image
+}
+
+fn input() -> OwnedEntries {
+    OwnedEntries {
+        reference: Entry::new_image("reference.tif", float_image(0.25)).unwrap(),
+        entries: vec![Entry::new_image("light.tif", float_image(0.75)).unwrap()],
+    }
+}
+
+/// Check that an image is a copy of [`float_image`].
+fn assert_float_image(image: &Mat, value: f32) {
+    assert_eq!(image.typ(), cv::core::CV_32FC3);
+    assert_eq!(image.size().unwrap(), cv::core::Size::new(4, 4));
+    let image = image.reshape(1, 0).unwrap();
+    assert!(image.at_row::<f32>(0).unwrap().iter().all(|v| v.is_nan()));
+    assert!(image.at_row::<f32>(3).unwrap().iter().all(|v| *v == value));
+}
+
+#[test]
+fn checkpoint_round_trip_keeps_depth() {
+    let dir = WorkDir::new().unwrap();
+    let checkpoints = Checkpoints::new(dir.path());
+    let stage = Passthrough::default();
+    let input = input();
+    let key = Key::input(std::iter::once(&input.reference).chain(&input.entries)).unwrap();
+
+    // The saved entries are the stage's own
+    let saved = checkpoints
+        .save(0, &stage, key, input.into_entries(), &Context::new())
+        .unwrap()
+        .into_owned();
+    assert!(matches!(saved.reference, Entry::Image(_)));
+    assert_eq!(saved.entries.len(), 1);
+
+    let loaded = checkpoints.load(0, &stage, key).unwrap().unwrap();
+    assert_float_image(&loaded.reference.read_image().unwrap(), 0.25);
+    assert_eq!(loaded.entries.len(), 1);
+    assert_float_image(&loaded.entries[0].read_image().unwrap(), 0.75);
+
+    // Other inputs or options don't match the checkpoint
+    assert!(checkpoints
+        .load(0, &stage, Key::input([]).unwrap())
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn checkpoint_is_incomplete_until_read() {
+    let dir = WorkDir::new().unwrap();
+    let checkpoints = Checkpoints::new(dir.path());
+    let stage = Passthrough::default();
+    let key = Key::input([]).unwrap();
+
+    let saved = checkpoints
+        .save(0, &stage, key, input().into_entries(), &Context::new())
+        .unwrap();
+    assert!(checkpoints.load(0, &stage, key).unwrap().is_none());
+    saved.into_owned();
+    assert!(checkpoints.load(0, &stage, key).unwrap().is_some());
+}
+
+#[test]
+fn pipeline_resumes_from_checkpoint() {
+    let dir = WorkDir::new().unwrap();
+    let stage = Arc::new(Passthrough::default());
+    let pipeline = Pipeline {
+        stages: vec![medo::pipeline::PipelineStage {
+            stage: stage.clone(),
+            on_error: None,
+        }],
+        on_error: Default::default(),
+        checkpoints: Some(Checkpoints::new(dir.path())),
+    };
+
+    for _ in 0..2 {
+        let output = pipeline
+            .process(input().into_entries())
+            .unwrap()
+            .into_owned();
+        assert_float_image(&output.reference.read_image().unwrap(), 0.25);
+        assert_eq!(output.entries.len(), 1);
+        assert_float_image(&output.entries[0].read_image().unwrap(), 0.75);
+    }
+    assert_eq!(stage.runs.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,100 @@
+use std::sync::Arc;
+
+use medo::core::cv;
+use medo::core::cv::core::{Mat, Scalar};
+use medo::core::entry::{Entries, Entry, OwnedEntries, OwnedEntryIter};
+use medo::core::{Error, Result};
+use medo::pipeline::{Context, ErrorPolicy, Pipeline, PipelineStage, Stage};
+
+/// A stage that fails the entries whose names start with a prefix.
+#[derive(Debug)]
+struct Reject(&'static str);
+
+impl Stage for Reject {
+    fn name(&self) -> &str {
+        "reject"
+    }
+
+    fn options(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::Value::Null)
+    }
+
+    fn process<'scope>(
+        &self,
+        input: Entries<'scope, OwnedEntryIter<'scope>>,
+        ctx: &Context,
+    ) -> Result<Entries<'scope, OwnedEntryIter<'scope>>> {
+        let prefix = self.0;
+        let ctx = ctx.clone();
+        Ok(Entries {
+            reference: input.reference,
+            entries: Box::new(input.entries.filter(move |e| {
+                let name = e.name();
+                let error = name
+                    .starts_with(prefix)
+                    .then(|| Error::OtherStatic("rejected"));
+                ctx.entry_processed(&name, Default::default(), error.as_ref());
+                error.is_none()
+            })),
+        })
+    }
+}
+
+fn stage(stage: Reject, on_error: Option<ErrorPolicy>) -> PipelineStage {
+    PipelineStage {
+        stage: Arc::new(stage),
+        on_error,
+    }
+}
+
+fn input() -> OwnedEntries {
+    let image =
+        || Mat::new_rows_cols_with_default(4, 4, cv::core::CV_8UC3, Scalar::all(0.0)).unwrap();
+    OwnedEntries {
+        reference: Entry::new_image("reference", image()).unwrap(),
+        entries: ["bad_1", "bad_2", "good"]
+            .into_iter()
+            .map(|name| Entry::new_image(name, image()).unwrap())
+            .collect(),
+    }
+}
+
+#[test]
+fn failure_rate_is_counted_by_stage_position() {
+    // Both stages are named alike, but only the first fails entries
+    let pipeline = Pipeline {
+        stages: vec![
+            stage(Reject("bad"), Some(ErrorPolicy::Discard)),
+            stage(Reject("none"), Some(ErrorPolicy::AbortAbove(50.0))),
+        ],
+        ..Default::default()
+    };
+    let ctx = Context::new();
+    let output = pipeline
+        .process_with(input().into_entries(), &ctx)
+        .unwrap()
+        .into_owned();
+    assert_eq!(output.entries.len(), 1);
+    assert!(ctx.check_cancelled().is_ok());
+
+    let failures = ctx.failures();
+    assert_eq!(failures.len(), 2);
+    assert!(failures.iter().all(|f| f.index == 0 && f.stage == "reject"));
+}
+
+#[test]
+fn error_policy_aborts_with_reason() {
+    let pipeline = Pipeline {
+        stages: vec![stage(Reject("bad"), Some(ErrorPolicy::AbortAbove(50.0)))],
+        ..Default::default()
+    };
+    let ctx = Context::new();
+    // Entries are processed as they are read
+    let _ = pipeline
+        .process_with(input().into_entries(), &ctx)
+        .map(|o| o.into_owned());
+    let e = ctx.check_cancelled().unwrap_err();
+    assert!(e.is_aborted());
+    assert!(!e.is_cancelled());
+    assert_eq!(e.stage(), Some("reject"));
+}
@@ -0,0 +1,153 @@
+use std::path::{Path, PathBuf};
+
+use medo::core::cv;
+use medo::core::cv::core::{Mat, Scalar};
+use medo::core::entry::Entry;
+use medo::core::util::{self, WorkDir};
+use medo::input::{self, Opts, Order};
+
+/// Create a capture directory of lights, with the files that capture software leaves next to
+/// them.
+fn capture_dir() -> WorkDir {
+    let dir = WorkDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("sub/deeper")).unwrap();
+    let image = Mat::new_rows_cols_with_default(4, 4, cv::core::CV_8UC3, Scalar::all(1.0)).unwrap();
+    for name in [
+        "b_20231012-230000.tif",
+        "a_20231012-233000.tif",
+        "a_20231012-233000_thumb.jpg",
+        ".hidden.tif",
+        "sub/c_20231012-220000.tif",
+        "sub/deeper/d_20231013-010000.png",
+    ] {
+        util::write_image(dir.path().join(name), &image).unwrap();
+    }
+    std::fs::write(dir.path().join("a_20231012-233000.xmp"), "<x:xmpmeta/>").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "clear skies").unwrap();
+    std::fs::write(
+        dir.path().join("list.txt"),
+        "# lights\n\nsub/c_20231012-220000.tif\n  b_20231012-230000.tif\nsub/c_20231012-220000.tif\n",
+    )
+    .unwrap();
+    dir
+}
+
+/// Get the paths of entries, relative to a directory.
+fn relative(dir: &WorkDir, entries: &[Entry]) -> Vec<PathBuf> {
+    entries
+        .iter()
+        .map(|e| match e {
+            Entry::Path(p) => p.path().strip_prefix(dir.path()).unwrap().to_owned(),
+            _ => panic!("entry is not read from a file"),
+        })
+        .collect()
+}
+
+fn select<S: AsRef<str>>(dir: &WorkDir, inputs: &[S], opts: &Opts) -> Vec<PathBuf> {
+    relative(dir, &input::select(inputs, opts).unwrap())
+}
+
+fn path(dir: &WorkDir, name: &str) -> String {
+    dir.path().join(name).to_string_lossy().into_owned()
+}
+
+fn paths(names: &[&str]) -> Vec<PathBuf> {
+    names.iter().map(|n| Path::new(n).to_owned()).collect()
+}
+
+#[test]
+fn directories_leave_out_sidecars_and_thumbnails() {
+    let dir = capture_dir();
+    let input = path(&dir, "");
+    assert_eq!(
+        select(&dir, &[&input], &Opts::default()),
+        paths(&["a_20231012-233000.tif", "b_20231012-230000.tif"])
+    );
+
+    let opts = Opts {
+        recursive: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        select(&dir, &[&input], &opts),
+        paths(&[
+            "a_20231012-233000.tif",
+            "b_20231012-230000.tif",
+            "sub/c_20231012-220000.tif",
+            "sub/deeper/d_20231013-010000.png",
+        ])
+    );
+}
+
+#[test]
+fn entries_are_ordered_by_capture_time() {
+    let dir = capture_dir();
+    let opts = Opts {
+        recursive: true,
+        order: Order::CaptureTime,
+        ..Default::default()
+    };
+    assert_eq!(
+        select(&dir, &[&path(&dir, "")], &opts),
+        paths(&[
+            "sub/c_20231012-220000.tif",
+            "b_20231012-230000.tif",
+            "a_20231012-233000.tif",
+            "sub/deeper/d_20231013-010000.png",
+        ])
+    );
+}
+
+#[test]
+fn lists_and_globs_are_expanded() {
+    let dir = capture_dir();
+    let list = format!("@{}", path(&dir, "list.txt"));
+    let glob = path(&dir, "*.tif");
+    let unmatched = path(&dir, "darks/*.fits");
+
+    // Files are used once, in order of their paths
+    assert_eq!(
+        select(&dir, &[&list, &glob, &unmatched], &Opts::default()),
+        paths(&[
+            "a_20231012-233000.tif",
+            "b_20231012-230000.tif",
+            "sub/c_20231012-220000.tif",
+        ])
+    );
+    assert!(input::select(&[&path(&dir, "missing.tif")], &Opts::default()).is_err());
+}
+
+#[test]
+fn extensions_are_included_and_excluded() {
+    let dir = capture_dir();
+    let input = path(&dir, "");
+    let opts = Opts {
+        recursive: true,
+        include: vec!["PNG".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(
+        select(&dir, &[&input], &opts),
+        paths(&["sub/deeper/d_20231013-010000.png"])
+    );
+
+    let opts = Opts {
+        recursive: true,
+        exclude: vec![".tif".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(
+        select(&dir, &[&input], &opts),
+        paths(&["sub/deeper/d_20231013-010000.png"])
+    );
+
+    // Files that are asked for by name are only left out if they are excluded
+    let opts = Opts {
+        include: vec!["png".to_owned()],
+        ..Default::default()
+    };
+    assert_eq!(
+        select(&dir, &[&path(&dir, "b_20231012-230000.tif")], &opts),
+        paths(&["b_20231012-230000.tif"])
+    );
+}
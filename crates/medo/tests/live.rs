@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use medo::core::util::WorkDir;
+use medo::live::Watcher;
+
+fn write(dir: &WorkDir, name: &str, contents: &[u8]) -> PathBuf {
+    let path = dir.path().join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn watcher_returns_files_once_stable() {
+    let dir = WorkDir::new().unwrap();
+    let mut watcher = Watcher::new(dir.path(), Default::default());
+    assert!(watcher.poll().unwrap().is_empty());
+
+    // New files are only returned by the next poll
+    let first = write(&dir, "light_1.tif", b"1");
+    write(&dir, "notes.txt", b"not a frame");
+    assert!(watcher.poll().unwrap().is_empty());
+    assert_eq!(watcher.poll().unwrap(), vec![first]);
+
+    // Files are returned once
+    assert!(watcher.poll().unwrap().is_empty());
+}
+
+#[test]
+fn watcher_waits_for_files_being_written() {
+    let dir = WorkDir::new().unwrap();
+    let mut watcher = Watcher::new(dir.path(), Default::default());
+
+    // Empty files are still being created
+    let path = write(&dir, "light_1.tif", b"");
+    assert!(watcher.poll().unwrap().is_empty());
+    assert!(watcher.poll().unwrap().is_empty());
+
+    // Files that grew since the last poll are still being written
+    write(&dir, "light_1.tif", b"1");
+    assert!(watcher.poll().unwrap().is_empty());
+    write(&dir, "light_1.tif", b"12");
+    assert!(watcher.poll().unwrap().is_empty());
+    assert_eq!(watcher.poll().unwrap(), vec![path]);
+}
+
+#[test]
+fn watcher_never_returns_ignored_files() {
+    let dir = WorkDir::new().unwrap();
+    let mut watcher = Watcher::new(dir.path(), Default::default());
+
+    // Ignored files need not exist yet
+    watcher.ignore(&dir.path().join("live.tif"));
+    write(&dir, "live.tif", b"stack");
+    let light = write(&dir, "light_1.tif", b"1");
+    watcher.poll().unwrap();
+    assert_eq!(watcher.poll().unwrap(), vec![light]);
+    assert!(watcher.poll().unwrap().is_empty());
+}
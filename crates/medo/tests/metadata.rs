@@ -0,0 +1,108 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use medo::core::entry::Entry;
+use medo::core::util::WorkDir;
+use medo::metadata::{self, Metadata};
+
+/// Build a little endian TIFF header whose EXIF directory records a capture time and exposure.
+fn exif_tiff(date_time: &str, exposure: (u32, u32)) -> Vec<u8> {
+    let mut tiff = b"II*\0".to_vec();
+    let u16 = |tiff: &mut Vec<u8>, v: u16| tiff.extend_from_slice(&v.to_le_bytes());
+    let u32 = |tiff: &mut Vec<u8>, v: u32| tiff.extend_from_slice(&v.to_le_bytes());
+    // First directory, pointing to the EXIF directory
+    u32(&mut tiff, 8);
+    u16(&mut tiff, 1);
+    for v in [34665, 4] {
+        u16(&mut tiff, v);
+    }
+    u32(&mut tiff, 1);
+    u32(&mut tiff, 26);
+    u32(&mut tiff, 0);
+    // EXIF directory, whose values follow it
+    u16(&mut tiff, 2);
+    for v in [36867, 2] {
+        u16(&mut tiff, v);
+    }
+    u32(&mut tiff, 20);
+    u32(&mut tiff, 56);
+    for v in [33434, 5] {
+        u16(&mut tiff, v);
+    }
+    u32(&mut tiff, 1);
+    u32(&mut tiff, 76);
+    u32(&mut tiff, 0);
+    tiff.extend_from_slice(date_time.as_bytes());
+    tiff.push(0);
+    u32(&mut tiff, exposure.0);
+    u32(&mut tiff, exposure.1);
+    tiff
+}
+
+/// Wrap a TIFF header in the EXIF segment of a JPEG file.
+fn exif_jpeg(tiff: &[u8]) -> Vec<u8> {
+    let mut jpeg = vec![0xff, 0xd8];
+    // A segment before the EXIF one
+    jpeg.extend_from_slice(&[0xff, 0xe0, 0, 4, 0, 0]);
+    jpeg.extend_from_slice(&[0xff, 0xe1]);
+    jpeg.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+    jpeg.extend_from_slice(b"Exif\0\0");
+    jpeg.extend_from_slice(tiff);
+    jpeg.extend_from_slice(&[0xff, 0xd9]);
+    jpeg
+}
+
+fn entry(dir: &WorkDir, name: &str, contents: &[u8]) -> Entry {
+    let path = dir.path().join(name);
+    std::fs::write(&path, contents).unwrap();
+    Entry::new_path_owned(path).unwrap()
+}
+
+#[test]
+fn metadata_reads_exif_headers() {
+    let dir = WorkDir::new().unwrap();
+    let tiff = exif_tiff("2023:10:12 22:15:30", (1, 4));
+    // 2023-10-12T22:15:30Z
+    let time = UNIX_EPOCH + Duration::from_secs(1_697_148_930);
+
+    for entry in [
+        entry(&dir, "light.tif", &tiff),
+        entry(&dir, "light.jpg", &exif_jpeg(&tiff)),
+    ] {
+        let metadata = Metadata::read(&entry);
+        assert_eq!(metadata.capture_time, Some(time));
+        assert_eq!(metadata.exposure, Some(Duration::from_millis(250)));
+        assert_eq!(metadata.session.as_deref(), Some("2023-10-12"));
+        assert_eq!(metadata::capture_time(&entry), Some(time));
+    }
+}
+
+#[test]
+fn metadata_prefers_headers_to_names() {
+    let dir = WorkDir::new().unwrap();
+    let tiff = exif_tiff("2023:10:12 22:15:30", (300, 1));
+    let entry = entry(&dir, "Light_Ha_60s_20220101-000000.tif", &tiff);
+    let metadata = Metadata::read(&entry);
+    assert_eq!(metadata.exposure, Some(Duration::from_secs(300)));
+    assert_eq!(
+        metadata.capture_time,
+        Some(UNIX_EPOCH + Duration::from_secs(1_697_148_930))
+    );
+    assert_eq!(metadata.filter.as_deref(), Some("Ha"));
+}
+
+#[test]
+fn metadata_reads_capture_time_from_names() {
+    let dir = WorkDir::new().unwrap();
+    // 2023-10-12T22:15:30Z
+    let time = UNIX_EPOCH + Duration::from_secs(1_697_148_930);
+    let metadata = Metadata::from_name("Light_M42_Ha_300.0s_Bin1_20231012-221530_0001.fits");
+    assert_eq!(metadata.capture_time, Some(time));
+    assert_eq!(metadata.session.as_deref(), Some("2023-10-12"));
+
+    // Files without a header or a time in their name were captured when they were written
+    let entry = entry(&dir, "light_0001.fits", b"SIMPLE");
+    let modified = std::fs::metadata(dir.path().join("light_0001.fits"))
+        .and_then(|m| m.modified())
+        .unwrap();
+    assert_eq!(metadata::capture_time(&entry), Some(modified));
+}
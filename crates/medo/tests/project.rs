@@ -0,0 +1,126 @@
+use medo::core::cv;
+use medo::core::cv::core::{Mat, Scalar};
+use medo::core::entry::Entry;
+use medo::core::util::{self, WorkDir};
+use medo::pipeline::{stacking, Context, Pipeline, Registry};
+use medo::project::{GroupBy, Project, ProjectFile, DEFAULT_GROUP};
+use medo::reference::Selection;
+
+/// Write images of a constant value, and get entries for them.
+fn write_inputs(dir: &WorkDir) -> Vec<Entry> {
+    (1..=3)
+        .map(|i| {
+            let path = dir.path().join(format!("light_{}.tif", i));
+            let image =
+                Mat::new_rows_cols_with_default(8, 8, cv::core::CV_8UC3, Scalar::all(i as f64))
+                    .unwrap();
+            util::write_image(&path, &image).unwrap();
+            Entry::new_path_owned(path).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn saved_project_skips_unchanged_groups() {
+    let dir = WorkDir::new().unwrap();
+    let pipeline = Pipeline {
+        stages: vec![stacking::Opts::default().into()],
+        ..Default::default()
+    };
+    let mut project = Project::group(
+        write_inputs(&dir),
+        GroupBy::default(),
+        &pipeline,
+        &Selection::First,
+    )
+    .unwrap();
+    let summary = project.process().unwrap();
+    assert!(summary.failed.is_empty());
+
+    // Write the master, and record the project as `stack --save-project` does
+    let output = dir.path().join("master.tif");
+    let master = project.groups[0].pipeline_output.as_ref().unwrap();
+    util::write_image(&output, master.reference.read_image().unwrap().as_ref()).unwrap();
+    let path = dir.path().join("project.toml");
+    ProjectFile::from_project(&project, |_| output.clone(), None, None)
+        .unwrap()
+        .write(&path)
+        .unwrap();
+
+    let mut file = ProjectFile::read(&path).unwrap();
+    let summary = file
+        .run(&Registry::default(), false, &Context::new())
+        .unwrap();
+    assert!(summary.processed.is_empty());
+    assert!(summary.failed.is_empty());
+    assert_eq!(summary.skipped, vec![project.groups[0].name.clone()]);
+
+    // Forced runs process unchanged groups anyway
+    let summary = file
+        .run(&Registry::default(), true, &Context::new())
+        .unwrap();
+    assert_eq!(summary.processed, vec![project.groups[0].name.clone()]);
+}
+
+#[test]
+fn settings_group_by_known_metadata() {
+    let dir = WorkDir::new().unwrap();
+    let entries = [
+        "light_Ha_300s_1.tif",
+        "light_Ha_300s_2.tif",
+        "light_OIII_300s_1.tif",
+    ]
+    .into_iter()
+    .map(|name| {
+        let path = dir.path().join(name);
+        std::fs::write(&path, b"").unwrap();
+        Entry::new_path_owned(path).unwrap()
+    })
+    .collect();
+    let groups = GroupBy::SETTINGS.split(entries);
+    assert_eq!(
+        groups.keys().collect::<Vec<_>>(),
+        vec!["Ha_300s", "OIII_300s"]
+    );
+    assert_eq!(groups["Ha_300s"].len(), 2);
+
+    // Entries with no known metadata are not split
+    let groups = GroupBy::SETTINGS.split(write_inputs(&dir));
+    assert_eq!(groups.keys().collect::<Vec<_>>(), vec![DEFAULT_GROUP]);
+}
+
+#[test]
+fn reference_file_is_used_by_its_group() {
+    let dir = WorkDir::new().unwrap();
+    let paths = ["Ha_1.tif", "Ha_2.tif", "OIII_1.tif", "OIII_2.tif"].map(|name| {
+        let path = dir.path().join(name);
+        let image =
+            Mat::new_rows_cols_with_default(8, 8, cv::core::CV_8UC3, Scalar::all(1.0)).unwrap();
+        util::write_image(&path, &image).unwrap();
+        path
+    });
+    let entries = || {
+        paths
+            .iter()
+            .map(|p| Entry::new_path_owned(p.clone()).unwrap())
+            .collect::<Vec<_>>()
+    };
+    let by = GroupBy {
+        filter: true,
+        ..Default::default()
+    };
+
+    let selection = Selection::File(paths[3].clone());
+    let project = Project::group(entries(), by, &Pipeline::default(), &selection).unwrap();
+    assert_eq!(project.groups.len(), 2);
+    let reference = |group: &str| {
+        let group = project.group_by_name(group).unwrap();
+        group.entries.reference.name().to_string()
+    };
+    assert_eq!(reference("OIII"), "OIII_2.tif");
+    assert!(reference("Ha").starts_with("Ha_"));
+
+    // References that are not among the inputs are still reported
+    let selection = Selection::File(dir.path().join("L_1.tif"));
+    assert!(Project::group(entries(), by, &Pipeline::default(), &selection).is_err());
+}
@@ -0,0 +1,465 @@
+//! Modelling of the sky background, to remove gradients.
+//!
+//! Light pollution and moonlight add smooth gradients to images, which are often brighter than
+//! the target itself. The background is sampled on a grid, leaving out stars and bright
+//! structures such as nebulae, and a smooth surface is fitted to the samples. The surface is a
+//! model of the background alone, which can then be subtracted from or divided out of the image.
+//!
+//! Images are expected to be 32-bit floating point, normalized from 0 to 1 as
+//! [`medo_core::format::to_float`] converts them.
+
+use std::cmp::Ordering;
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar, Size};
+use medo_core::cv::imgproc;
+use medo_core::{Error, Result};
+
+use crate::noise::{self, MAD_TO_SIGMA};
+use crate::star::{self, Circle, ContourDetectionOpts};
+
+/// Length of the longest side of the grid that models are evaluated on, before being scaled to
+/// the size of the image.
+const MAX_EVALUATION_SIZE: i32 = 256;
+
+/// The background around a point of an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Horizontal position of the sample, in pixels.
+    pub x: f32,
+    /// Vertical position of the sample, in pixels.
+    pub y: f32,
+    /// Median of each channel around the sample.
+    pub values: Vec<f32>,
+}
+
+impl Sample {
+    /// Mean of the sample's channels.
+    #[inline]
+    fn luminance(&self) -> f32 {
+        self.values.iter().sum::<f32>() / self.values.len() as f32
+    }
+}
+
+/// Surfaces that a background can be modelled by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    /// Polynomial of two variables, of a total degree. Low degrees can't follow structures, so
+    /// they are robust to samples that aren't background, but can only model simple gradients.
+    Polynomial { degree: u32 },
+    /// Thin plate spline through the samples, which follows complex gradients. Smoothing
+    /// trades how closely samples are followed for how smooth the surface is.
+    Rbf { smoothing: f64 },
+}
+
+/// Create a mask of the stars of an image.
+///
+/// Stars are found by [`star::find_stretched`]. Masked pixels are non-zero.
+///
+/// # Parameters
+/// - `image`: The image to find stars in.
+/// - `opts`: Options used to detect stars, with brightnesses from 0 to 255.
+/// - `margin`: Scale of the radius of stars, so that their halos are masked too.
+pub fn star_mask(image: &Mat, opts: ContourDetectionOpts, margin: f32) -> Result<Mat> {
+    let stars = star::find_stretched(image, opts)?
+        .into_iter()
+        .map(|s| Circle {
+            radius: s.radius * margin,
+            ..s
+        });
+    star::create_mask(image.size()?, cv::core::CV_8U, stars)
+}
+
+/// Sample the background of a normalized image on a grid.
+///
+/// Each sample is the median of a square around a point of the grid. Masked and invalid pixels
+/// are left out, and squares that are mostly masked or invalid are not sampled.
+///
+/// # Parameters
+/// - `image`: The image to sample.
+/// - `mask`: 8-bit mask of pixels that are not background, such as stars. Masked pixels are
+///   non-zero.
+/// - `grid_size`: Number of samples along each side of the image.
+/// - `sample_size`: Length of the side of the square each sample is taken from, in pixels.
+pub fn sample(image: &Mat, mask: &Mat, grid_size: u32, sample_size: u32) -> Result<Vec<Sample>> {
+    let size = image.size()?;
+    let found = mask.size()?;
+    if size != found {
+        return Err(Error::DimensionMismatch {
+            expected: size,
+            found,
+        });
+    }
+    let channels = image.channels() as usize;
+    let image = image.try_clone()?.reshape(1, 0)?;
+    let data = image.data_typed::<f32>()?;
+    let mask = mask.try_clone()?;
+    let mask_data = mask.data_typed::<u8>()?;
+
+    let half = (sample_size / 2) as i32;
+    let mut samples = vec![];
+    let mut values = vec![Vec::new(); channels];
+    for gy in 0..grid_size {
+        for gx in 0..grid_size {
+            let cx = ((gx as f32 + 0.5) * size.width as f32 / grid_size as f32) as i32;
+            let cy = ((gy as f32 + 0.5) * size.height as f32 / grid_size as f32) as i32;
+            values.iter_mut().for_each(Vec::clear);
+
+            let mut total = 0;
+            for y in (cy - half).max(0)..=(cy + half).min(size.height - 1) {
+                for x in (cx - half).max(0)..=(cx + half).min(size.width - 1) {
+                    total += 1;
+                    let i = (y * size.width + x) as usize;
+                    let pixel = &data[i * channels..][..channels];
+                    if mask_data[i] != 0 || pixel.iter().any(|v| v.is_nan()) {
+                        continue;
+                    }
+                    for (values, v) in values.iter_mut().zip(pixel) {
+                        values.push(*v);
+                    }
+                }
+            }
+            // Squares covered by stars or out-of-frame pixels are not background
+            if values[0].len() * 2 < total {
+                continue;
+            }
+            samples.push(Sample {
+                x: cx as f32,
+                y: cy as f32,
+                values: values.iter_mut().filter_map(noise::median).collect(),
+            });
+        }
+    }
+    Ok(samples)
+}
+
+/// Solve a square system of linear equations by Gaussian elimination.
+///
+/// `a` is the matrix of coefficients, by row. Returns `None` if the system is singular.
+fn solve(mut a: Vec<f64>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        // Partial pivoting keeps the elimination stable
+        let pivot = (col..n).max_by(|&i, &j| {
+            a[i * n + col]
+                .abs()
+                .partial_cmp(&a[j * n + col].abs())
+                .unwrap_or(Ordering::Equal)
+        })?;
+        if a[pivot * n + col].abs() < 1e-12 {
+            return None;
+        }
+        if pivot != col {
+            for k in 0..n {
+                a.swap(pivot * n + k, col * n + k);
+            }
+            b.swap(pivot, col);
+        }
+        for row in col + 1..n {
+            let factor = a[row * n + col] / a[col * n + col];
+            if factor == 0.0 {
+                continue;
+            }
+            for k in col..n {
+                a[row * n + k] -= factor * a[col * n + k];
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum = (row + 1..n).map(|k| a[row * n + k] * x[k]).sum::<f64>();
+        x[row] = (b[row] - sum) / a[row * n + row];
+    }
+    Some(x)
+}
+
+/// Radial basis function of a thin plate spline.
+#[inline]
+fn thin_plate(r2: f64) -> f64 {
+    if r2 > 0.0 {
+        0.5 * r2 * r2.ln()
+    } else {
+        0.0
+    }
+}
+
+/// A surface fitted to the samples of each channel.
+#[derive(Debug, Clone)]
+enum Surface {
+    /// Coefficients of each channel, for each term.
+    Polynomial {
+        degree: u32,
+        coefficients: Vec<Vec<f64>>,
+    },
+    /// Weights of each channel, for each center, followed by the affine terms.
+    Rbf {
+        centers: Vec<(f64, f64)>,
+        weights: Vec<Vec<f64>>,
+    },
+}
+
+/// Powers of the terms of a polynomial of two variables, of a total degree.
+fn terms(degree: u32) -> impl Iterator<Item = (i32, i32)> {
+    let degree = degree as i32;
+    (0..=degree).flat_map(move |i| (0..=degree - i).map(move |j| (i, j)))
+}
+
+impl Surface {
+    /// Fit a surface to samples of some channels, at normalized positions.
+    fn fit(
+        points: &[(f64, f64)],
+        samples: &[Sample],
+        channels: usize,
+        model: Model,
+    ) -> Result<Self> {
+        match model {
+            Model::Polynomial { degree } => {
+                let terms = terms(degree).collect::<Vec<_>>();
+                let m = terms.len();
+                if points.len() < m {
+                    return Err(Error::Other(format!(
+                        "{} background samples are too few for a polynomial of degree {}",
+                        points.len(),
+                        degree
+                    )));
+                }
+                let rows = points
+                    .iter()
+                    .map(|(u, v)| {
+                        terms
+                            .iter()
+                            .map(|&(i, j)| u.powi(i) * v.powi(j))
+                            .collect::<Vec<_>>()
+                    })
+                    .collect::<Vec<_>>();
+
+                // Least squares by the normal equations
+                let mut ata = vec![0.0; m * m];
+                for row in &rows {
+                    for (i, ri) in row.iter().enumerate() {
+                        for (j, rj) in row.iter().enumerate() {
+                            ata[i * m + j] += ri * rj;
+                        }
+                    }
+                }
+                let coefficients = (0..channels)
+                    .map(|c| {
+                        let mut atb = vec![0.0; m];
+                        for (row, sample) in rows.iter().zip(samples) {
+                            for (a, r) in atb.iter_mut().zip(row) {
+                                *a += r * sample.values[c] as f64;
+                            }
+                        }
+                        solve(ata.clone(), atb)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(Error::OtherStatic("failed to fit background polynomial"))?;
+                Ok(Self::Polynomial {
+                    degree,
+                    coefficients,
+                })
+            }
+            Model::Rbf { smoothing } => {
+                let n = points.len();
+                if n < 3 {
+                    return Err(Error::Other(format!(
+                        "{} background samples are too few for a spline",
+                        n
+                    )));
+                }
+                // Kernel, smoothed along the diagonal, bordered by the affine terms
+                let size = n + 3;
+                let mut a = vec![0.0; size * size];
+                for (i, (ui, vi)) in points.iter().enumerate() {
+                    for (j, (uj, vj)) in points.iter().enumerate() {
+                        a[i * size + j] = thin_plate((ui - uj).powi(2) + (vi - vj).powi(2));
+                    }
+                    a[i * size + i] += smoothing;
+                    for (k, p) in [1.0, *ui, *vi].into_iter().enumerate() {
+                        a[i * size + n + k] = p;
+                        a[(n + k) * size + i] = p;
+                    }
+                }
+                let weights = (0..channels)
+                    .map(|c| {
+                        let mut b = samples
+                            .iter()
+                            .map(|s| s.values[c] as f64)
+                            .collect::<Vec<_>>();
+                        b.extend([0.0; 3]);
+                        solve(a.clone(), b)
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(Error::OtherStatic("failed to fit background spline"))?;
+                Ok(Self::Rbf {
+                    centers: points.to_vec(),
+                    weights,
+                })
+            }
+        }
+    }
+
+    /// Get the value of a channel of the surface at a normalized position.
+    fn eval(&self, c: usize, u: f64, v: f64) -> f64 {
+        match self {
+            Self::Polynomial {
+                degree,
+                coefficients,
+            } => terms(*degree)
+                .zip(&coefficients[c])
+                .map(|((i, j), k)| k * u.powi(i) * v.powi(j))
+                .sum(),
+            Self::Rbf { centers, weights } => {
+                let weights = &weights[c];
+                let n = centers.len();
+                let kernel = centers
+                    .iter()
+                    .zip(weights)
+                    .map(|((cu, cv), w)| w * thin_plate((u - cu).powi(2) + (v - cv).powi(2)))
+                    .sum::<f64>();
+                kernel + weights[n] + weights[n + 1] * u + weights[n + 2] * v
+            }
+        }
+    }
+}
+
+/// Map a position of an image to a normalized position.
+///
+/// Positions are centered and scaled by the longest side, so that distances are the same along
+/// both axes.
+#[inline]
+fn normalize_position(size: Size, x: f64, y: f64) -> (f64, f64) {
+    let scale = size.width.max(size.height) as f64 / 2.0;
+    (
+        (x - size.width as f64 / 2.0) / scale,
+        (y - size.height as f64 / 2.0) / scale,
+    )
+}
+
+/// Render a surface at the size of an image, as 32-bit floating point.
+fn render(surface: &Surface, size: Size, channels: i32) -> Result<Mat> {
+    // Surfaces are smooth, so they are evaluated on a coarse grid and scaled up
+    let scale = (MAX_EVALUATION_SIZE as f64 / size.width.max(size.height) as f64).min(1.0);
+    let width = ((size.width as f64 * scale).ceil() as i32).max(1);
+    let height = ((size.height as f64 * scale).ceil() as i32).max(1);
+
+    let mut coarse = Mat::new_rows_cols_with_default(
+        height,
+        width * channels,
+        cv::core::CV_32F,
+        Scalar::all(0.0),
+    )?;
+    let data = coarse.data_typed_mut::<f32>()?;
+    for y in 0..height {
+        for x in 0..width {
+            // Pixel centers of the coarse grid, in pixels of the image
+            let (u, v) = normalize_position(
+                size,
+                (x as f64 + 0.5) * size.width as f64 / width as f64 - 0.5,
+                (y as f64 + 0.5) * size.height as f64 / height as f64 - 0.5,
+            );
+            let out =
+                &mut data[(y * width + x) as usize * channels as usize..][..channels as usize];
+            for (c, out) in out.iter_mut().enumerate() {
+                *out = surface.eval(c, u, v) as f32;
+            }
+        }
+    }
+    let coarse = coarse.reshape(channels, height)?;
+    if (width, height) == (size.width, size.height) {
+        return Ok(coarse);
+    }
+    let mut model = Mat::default();
+    imgproc::resize(&coarse, &mut model, size, 0.0, 0.0, imgproc::INTER_CUBIC)?;
+    Ok(model)
+}
+
+/// Fit a model of the background to samples, and render it at the size of an image.
+///
+/// Samples that are brighter than the fitted background by more than `tolerance` standard
+/// deviations of the residuals are taken to be structures rather than background, and are left
+/// out of a second fit.
+///
+/// Returns the model as a 32-bit floating point image, and the number of samples it was fitted
+/// to.
+///
+/// # Parameters
+/// - `samples`: Samples of the background, such as from [`sample`].
+/// - `size`: Size of the image that the background is modelled for.
+/// - `model`: Surface that the background is modelled by.
+/// - `tolerance`: How far above the background samples are rejected, in standard deviations.
+pub fn fit(samples: &[Sample], size: Size, model: Model, tolerance: f32) -> Result<(Mat, usize)> {
+    let channels = match samples.first() {
+        Some(s) => s.values.len(),
+        None => return Err(Error::OtherStatic("no background samples")),
+    };
+    let points = samples
+        .iter()
+        .map(|s| normalize_position(size, s.x as f64, s.y as f64))
+        .collect::<Vec<_>>();
+    let surface = Surface::fit(&points, samples, channels, model)?;
+
+    // Reject samples of bright structures by how far they are above the surface
+    let residuals = samples
+        .iter()
+        .zip(&points)
+        .map(|(s, (u, v))| {
+            let fitted = (0..channels).map(|c| surface.eval(c, *u, *v)).sum::<f64>();
+            s.luminance() - (fitted / channels as f64) as f32
+        })
+        .collect::<Vec<_>>();
+    let limit = match noise::median_mad(&mut residuals.clone()) {
+        Some((median, mad)) => median + tolerance * mad * MAD_TO_SIGMA as f32,
+        None => f32::INFINITY,
+    };
+    let (kept, kept_points): (Vec<_>, Vec<_>) = samples
+        .iter()
+        .zip(points)
+        .zip(residuals)
+        .filter(|(_, r)| *r <= limit)
+        .map(|((s, p), _)| (s.clone(), p))
+        .unzip();
+
+    let surface = if kept.len() < samples.len() {
+        Surface::fit(&kept_points, &kept, channels, model)?
+    } else {
+        surface
+    };
+    Ok((render(&surface, size, channels as i32)?, kept.len()))
+}
+
+/// Subtract a background from an image.
+///
+/// The mean of the background is added back, so that the image keeps its brightness and is not
+/// clipped at black.
+pub fn subtract(image: &Mat, background: &Mat) -> Result<Mat> {
+    let mean = cv::core::mean(background, &cv::core::no_array())?;
+    let mut difference = Mat::default();
+    cv::core::subtract(
+        image,
+        background,
+        &mut difference,
+        &cv::core::no_array(),
+        -1,
+    )?;
+    let mut out = Mat::default();
+    cv::core::add(&difference, &mean, &mut out, &cv::core::no_array(), -1)?;
+    Ok(out)
+}
+
+/// Divide an image by a background, which removes vignetting as well as gradients.
+///
+/// The image is scaled by the mean of the background, so that it keeps its brightness.
+pub fn divide(image: &Mat, background: &Mat) -> Result<Mat> {
+    let mean = cv::core::mean(background, &cv::core::no_array())?;
+    // The background can't be modelled as black
+    let mut floor = Mat::default();
+    cv::core::max(background, &Scalar::all(1e-6), &mut floor)?;
+    let mut quotient = Mat::default();
+    cv::core::divide2(image, &floor, &mut quotient, 1.0, -1)?;
+    let mut out = Mat::default();
+    cv::core::multiply(&quotient, &mean, &mut out, 1.0, -1)?;
+    Ok(out)
+}
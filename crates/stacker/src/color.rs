@@ -0,0 +1,169 @@
+//! Colour calibration of images.
+//!
+//! Light pollution and the differing sensitivity of a sensor's channels tint images: the sky
+//! background is rarely grey, and white objects are rarely white. The background is first
+//! neutralized, shifting channels so that it is grey, then channels are scaled around the
+//! background so that a white reference, such as the average of many stars, is white.
+//!
+//! Green noise left over afterwards, which is rare in deep sky objects, can be removed by
+//! subtractive chromatic noise reduction.
+//!
+//! Images are expected to be 32-bit floating point and linear.
+
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Rect};
+use medo_core::{Error, Result};
+
+use crate::noise;
+use crate::star::Circle;
+use crate::stretch;
+
+/// Get the values of each channel of an image's region, leaving out invalid pixels.
+fn channel_values(image: &Mat, region: Rect) -> Result<Vec<Vec<f32>>> {
+    let channels = image.channels() as usize;
+    let roi = Mat::roi(image, region)?.try_clone()?.reshape(1, 0)?;
+    let mut values = vec![Vec::with_capacity(region.area() as usize); channels];
+    for pixel in roi.data_typed::<f32>()?.chunks_exact(channels) {
+        if pixel.iter().any(|v| v.is_nan()) {
+            continue;
+        }
+        for (values, v) in values.iter_mut().zip(pixel) {
+            values.push(*v);
+        }
+    }
+    if values[0].is_empty() {
+        return Err(Error::OtherStatic("region has no valid pixels"));
+    }
+    Ok(values)
+}
+
+/// Get the background level of each channel of an image.
+///
+/// The level is the median of a region of background, or of the whole image, which is mostly
+/// background in deep sky images.
+pub fn background_levels(image: &Mat, region: Option<Rect>) -> Result<Vec<f32>> {
+    let levels = match region {
+        Some(region) => channel_values(image, region)?
+            .iter_mut()
+            .filter_map(noise::median)
+            .collect::<Vec<_>>(),
+        None => stretch::median_mad(image, false)?
+            .into_iter()
+            .map(|stats| stats.map(|(median, _)| median))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(Error::OtherStatic("image has no valid pixels"))?,
+    };
+    Ok(levels)
+}
+
+/// Neutralize the background of an image, shifting each channel so that its background level
+/// is the mean of the levels of all channels.
+pub fn neutralize(image: &Mat, levels: &[f32]) -> Result<Mat> {
+    let target = levels.iter().sum::<f32>() / levels.len() as f32;
+    stretch::map_channels(image, |c, v| v + target - levels[c])
+}
+
+/// Get the average colour of stars, relative to a background.
+///
+/// The flux of each star is summed over its circle, and the colour of each channel is the median
+/// of the stars' fluxes in the channel relative to their mean flux. Stars with any value at or
+/// above `saturation`, or that are not brighter than the background in every channel, are left
+/// out. Returns `None` if every star is left out.
+pub fn star_color(
+    image: &Mat,
+    stars: &[Circle],
+    levels: &[f32],
+    saturation: f32,
+) -> Result<Option<Vec<f32>>> {
+    let (width, height, channels) = (image.cols(), image.rows(), levels.len());
+    let data_mat = image.try_clone()?.reshape(1, 0)?;
+    let data = data_mat.data_typed::<f32>()?;
+
+    let mut ratios = vec![Vec::with_capacity(stars.len()); channels];
+    'stars: for star in stars {
+        let (cx, cy, r) = (star.center.x, star.center.y, star.radius);
+        let mut flux = vec![0.0; channels];
+        for y in ((cy - r).floor() as i32).max(0)..=((cy + r).ceil() as i32).min(height - 1) {
+            for x in ((cx - r).floor() as i32).max(0)..=((cx + r).ceil() as i32).min(width - 1) {
+                if (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2) > r * r {
+                    continue;
+                }
+                let pixel = &data[(y * width + x) as usize * channels..][..channels];
+                for ((flux, v), level) in flux.iter_mut().zip(pixel).zip(levels) {
+                    if v.is_nan() || *v >= saturation {
+                        continue 'stars;
+                    }
+                    *flux += v - level;
+                }
+            }
+        }
+        if flux.iter().any(|f| *f <= 0.0) {
+            continue;
+        }
+        let mean = flux.iter().sum::<f32>() / channels as f32;
+        for (ratios, f) in ratios.iter_mut().zip(flux) {
+            ratios.push(f / mean);
+        }
+    }
+    Ok(ratios.iter_mut().map(noise::median).collect())
+}
+
+/// Get the mean colour of a region, relative to a background.
+pub fn region_color(image: &Mat, region: Rect, levels: &[f32]) -> Result<Vec<f32>> {
+    let color = channel_values(image, region)?
+        .iter()
+        .zip(levels)
+        .map(|(values, level)| values.iter().sum::<f32>() / values.len() as f32 - level)
+        .collect::<Vec<_>>();
+    if color.iter().any(|c| *c <= 0.0) {
+        return Err(Error::OtherStatic(
+            "region is not brighter than the background in every channel",
+        ));
+    }
+    Ok(color)
+}
+
+/// Scale each channel of an image around its background level, so that a colour becomes white.
+///
+/// Channels are scaled relative to each other, keeping the mean brightness of the colour.
+pub fn white_balance(image: &Mat, color: &[f32], levels: &[f32]) -> Result<Mat> {
+    let mean = color.iter().sum::<f32>() / color.len() as f32;
+    let factors = color.iter().map(|c| mean / c).collect::<Vec<_>>();
+    stretch::map_channels(image, |c, v| (v - levels[c]) * factors[c] + levels[c])
+}
+
+/// Neutral levels that green is limited to by subtractive chromatic noise reduction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Neutral {
+    /// The mean of red and blue, which removes green strongly.
+    Average,
+    /// The brighter of red and blue, which removes green gently.
+    Maximum,
+}
+
+/// Remove green noise from a BGR image by subtractive chromatic noise reduction.
+///
+/// Green is limited to a neutral level of red and blue, so that no pixel is greener than
+/// neutral. The result is blended with the image by `amount`, from 0 for the image to 1 for the
+/// fully limited result.
+pub fn scnr(image: &Mat, neutral: Neutral, amount: f32) -> Result<Mat> {
+    let (channels, rows) = (image.channels(), image.rows());
+    if channels < 3 {
+        return Err(Error::OtherStatic("image is not in colour"));
+    }
+    let mut out = image.try_clone()?.reshape(1, 0)?;
+    for pixel in out
+        .data_typed_mut::<f32>()?
+        .chunks_exact_mut(channels as usize)
+    {
+        let (blue, red) = (pixel[0], pixel[2]);
+        let level = match neutral {
+            Neutral::Average => (blue + red) / 2.0,
+            Neutral::Maximum => blue.max(red),
+        };
+        // NaN levels leave green unchanged
+        if pixel[1] > level {
+            pixel[1] = pixel[1] * (1.0 - amount) + level * amount;
+        }
+    }
+    Ok(out.reshape(channels, rows)?)
+}
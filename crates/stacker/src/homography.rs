@@ -8,11 +8,13 @@ use medo_core::cv;
 use medo_core::cv::core::{Mat, TermCriteria};
 use medo_core::cv::imgproc;
 use medo_core::cv::video;
-use medo_core::util;
-use medo_core::Result;
+use medo_core::{util, warp};
+use medo_core::{Error, Result};
 
 /// Homography calculation options.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct CalculateOpts {
     /// Number of iterations of the algorithm.
     pub iterations: usize,
@@ -74,7 +76,17 @@ impl Calculator {
             criteria,
             &util::DEFAULT_MAT.0,
             5,
-        )?;
+        )
+        .map_err(|e| match e.code {
+            cv::core::StsNoConv => Error::AlignmentDiverged(e.message),
+            _ => e.into(),
+        })?;
+        if !warp::transform_to_array(&homography)?
+            .iter()
+            .all(|v| v.is_finite())
+        {
+            return Err(Error::AlignmentDiverged("transform is not finite".to_owned()));
+        }
 
         Ok(homography)
     }
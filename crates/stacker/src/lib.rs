@@ -1,5 +1,9 @@
 //! Image stacking library focused on astronomical images.
 
+pub mod background;
+pub mod color;
 pub mod homography;
+pub mod noise;
 pub mod stacker;
 pub mod star;
+pub mod stretch;
@@ -0,0 +1,82 @@
+//! Tools to estimate the noise in an image.
+
+use std::cmp::Ordering;
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, Size};
+use medo_core::cv::imgproc;
+use medo_core::{Error, Result};
+
+/// Scale of the median absolute deviation to a standard deviation of normally distributed values.
+pub const MAD_TO_SIGMA: f64 = 1.4826;
+
+/// Get the median of some values, reordering them.
+///
+/// Values that are not a number are ignored. Returns `None` if there are no other values.
+pub fn median(values: &mut Vec<f32>) -> Option<f32> {
+    values.retain(|v| !v.is_nan());
+    if values.is_empty() {
+        return None;
+    }
+    let mid = values.len() / 2;
+    let (_, median, _) =
+        values.select_nth_unstable_by(mid, |a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    Some(*median)
+}
+
+/// Get the median and median absolute deviation of some values, reordering them.
+///
+/// Values that are not a number are ignored. Returns `None` if there are no other values.
+pub fn median_mad(values: &mut Vec<f32>) -> Option<(f32, f32)> {
+    let median = median(values)?;
+    let mut deviations = values.iter().map(|v| (v - median).abs()).collect();
+    Some((median, self::median(&mut deviations)?))
+}
+
+/// Estimate the standard deviation of the noise in an image.
+///
+/// The image is converted to grayscale, and its structure is removed by subtracting a blurred
+/// copy. The noise is the scaled median absolute deviation of what remains, which is robust to
+/// stars and to pixels that are not a number. Estimates are in the units of the image, so only
+/// estimates of images of the same type are comparable.
+pub fn estimate(image: &Mat) -> Result<f64> {
+    let mut gray = Mat::default();
+    match image.channels() {
+        1 => image.convert_to(&mut gray, cv::core::CV_32F, 1.0, 0.0)?,
+        _ => {
+            let mut image_f = Mat::default();
+            image.convert_to(&mut image_f, cv::core::CV_32F, 1.0, 0.0)?;
+            imgproc::cvt_color(&image_f, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+        }
+    }
+
+    // Invalid pixels are blurred as zero, then left out
+    let mut valid = Mat::default();
+    cv::core::compare(&gray, &gray, &mut valid, cv::core::CMP_EQ)?;
+    let mut patched = gray.try_clone()?;
+    cv::core::patch_na_ns(&mut patched, 0.0)?;
+    let mut blurred = Mat::default();
+    imgproc::gaussian_blur(
+        &patched,
+        &mut blurred,
+        Size::new(5, 5),
+        0.0,
+        0.0,
+        cv::core::BORDER_REFLECT,
+    )?;
+    let mut residual = Mat::default();
+    cv::core::subtract(&gray, &blurred, &mut residual, &Mat::default(), -1)?;
+
+    let residual = residual.try_clone()?; // continuous
+    let valid = valid.try_clone()?;
+    let mut values = residual
+        .data_typed::<f32>()?
+        .iter()
+        .zip(valid.data_typed::<u8>()?)
+        .filter(|(_, valid)| **valid != 0)
+        .map(|(v, _)| *v)
+        .collect();
+    let (_, mad) =
+        median_mad(&mut values).ok_or(Error::OtherStatic("image has no valid pixels"))?;
+    Ok(f64::from(mad) * MAD_TO_SIGMA)
+}
@@ -1,15 +1,20 @@
 //! Method of stacking by averaging.
+//!
+//! Pixels that are not a number are considered invalid, and are left out of the average. This
+//! lets out-of-frame pixels of aligned images be ignored instead of darkening the edges of the
+//! stack. Images of different depths are scaled alike, so they can be stacked together.
 
 use std::borrow::Cow;
 
 use medo_core::cv;
-use medo_core::cv::core::Mat;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, Scalar};
 use medo_core::entry::{self, Entry};
-use medo_core::Result;
+use medo_core::{format, Error, Result};
 
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub struct Stacker<'iter, T: Iterator<Item = Cow<'iter, Entry>>> {
     out: entry::Image,
+    accumulator: Accumulator,
     iter: T,
     prog: usize,
 }
@@ -18,7 +23,32 @@ impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Stacker<'iter, T> {
     pub fn new<F: IntoIterator<Item = T::Item, IntoIter = T>>(iter: F) -> Result<Self> {
         let mut iter = iter.into_iter();
         let out = iter.next().unwrap().into_owned().into_image()?;
-        Ok(Self { out, iter, prog: 0 })
+
+        let mut stacker = Self {
+            accumulator: Accumulator::new(out.image())?,
+            out,
+            iter,
+            prog: 0,
+        };
+        let first = stacker.out.image().clone();
+        stacker.add(&first)?;
+        Ok(stacker)
+    }
+
+    /// Add an image to the stack.
+    ///
+    /// Images can be added as they become available, instead of through the stacker's
+    /// iterator.
+    pub fn add(&mut self, image: &Mat) -> Result<()> {
+        self.accumulator.add(image, 1.0)?;
+        let new = self.accumulator.average(self.out.image().depth())?;
+        self.out.replace_image(new)
+    }
+
+    /// Get the current stacked image.
+    #[inline]
+    pub fn image(&self) -> &Mat {
+        self.out.image()
     }
 
     /// Leak the underlying data store.
@@ -33,24 +63,92 @@ impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Iterator for Stacker<'iter, T
 
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(|next| {
-            // Calculate weight
-            let alpha = 1.0 / (self.prog + 2) as f64;
-            let beta = 1.0 - alpha;
-            // Add
-            let mut new = Mat::default();
-            cv::core::add_weighted(
-                next.read_image()?.as_ref(),
-                alpha,
-                self.out.image(),
-                beta,
-                0.0,
-                &mut new,
-                -1,
-            )?;
+            next.read_image()
+                .and_then(|image| self.add(image.as_ref()))
+                .map_err(|e| e.with_entry(next.name()))?;
             // Update progress
-            self.out.replace_image(new)?;
             self.prog += 1;
             Ok(())
         })
     }
 }
+
+/// Average images, weighting each image's contribution.
+///
+/// The average has the type of the first image. Fails if there are no images, or if an image
+/// has no positive weight.
+///
+/// # Parameters
+/// - `images`: The images to average, and their weights.
+pub fn weighted<'a, I: IntoIterator<Item = (&'a Mat, f64)>>(images: I) -> Result<Mat> {
+    let mut images = images.into_iter();
+    let (first, weight) = images
+        .next()
+        .ok_or(Error::OtherStatic("no images to average"))?;
+    let mut accumulator = Accumulator::new(first)?;
+    accumulator.add(first, weight)?;
+    for (image, weight) in images {
+        accumulator.add(image, weight)?;
+    }
+    accumulator.average(first.depth())
+}
+
+/// Per-pixel weighted sums of valid values.
+struct Accumulator {
+    /// Per-pixel weighted sum of valid values.
+    sum: Mat,
+    /// Per-pixel sum of the weights of valid values.
+    count: Mat,
+}
+
+impl Accumulator {
+    /// Create an empty accumulator for images like `like`.
+    fn new(like: &Mat) -> Result<Self> {
+        let size = like.size()?;
+        let typ = cv::core::CV_MAKETYPE(cv::core::CV_64F, like.channels());
+        Ok(Self {
+            sum: Mat::new_size_with_default(size, typ, Scalar::all(0.0))?,
+            count: Mat::new_size_with_default(size, typ, Scalar::all(0.0))?,
+        })
+    }
+
+    /// Add an image's valid values.
+    fn add(&mut self, image: &Mat, weight: f64) -> Result<()> {
+        let (expected, found) = (self.sum.size()?, image.size()?);
+        if expected != found {
+            return Err(Error::DimensionMismatch { expected, found });
+        }
+        if !(weight.is_finite() && weight > 0.0) {
+            return Err(Error::Other(format!("invalid weight: {}", weight)));
+        }
+
+        let mut image_f = format::to_float(image)?;
+
+        // `NaN` is the only value that is not equal to itself
+        let mut valid = Mat::default();
+        cv::core::compare(&image_f, &image_f, &mut valid, cv::core::CMP_EQ)?;
+        cv::core::patch_na_ns(&mut image_f, 0.0)?;
+
+        // Accumulate
+        let mut weighted = Mat::default();
+        image_f.convert_to(&mut weighted, cv::core::CV_64F, weight, 0.0)?;
+        let mut sum = Mat::default();
+        cv::core::add(&self.sum, &weighted, &mut sum, &Mat::default(), -1)?;
+        let mut valid_f = Mat::default();
+        valid.convert_to(&mut valid_f, cv::core::CV_64F, weight / 255.0, 0.0)?;
+        let mut count = Mat::default();
+        cv::core::add(&self.count, &valid_f, &mut count, &Mat::default(), -1)?;
+        self.sum = sum;
+        self.count = count;
+        Ok(())
+    }
+
+    /// Get the average of the added values, converted to `depth`.
+    ///
+    /// Pixels with no valid values are left invalid.
+    fn average(&self, depth: i32) -> Result<Mat> {
+        let mut average = Mat::default();
+        cv::core::divide2(&self.sum, &self.count, &mut average, 1.0, -1)?;
+        format::convert_cv_depth(&average, depth)
+    }
+}
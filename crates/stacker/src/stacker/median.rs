@@ -0,0 +1,111 @@
+//! Method of stacking by taking the median.
+//!
+//! The median rejects outliers such as satellite trails and hot pixels, at the cost of keeping
+//! every image in memory until the stack is done. Pixels that are not a number are considered
+//! invalid, and are left out of the median. Images of different depths are scaled alike, so
+//! they can be stacked together.
+
+use std::borrow::Cow;
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar};
+use medo_core::entry::{self, Entry};
+use medo_core::{format, Error, Result};
+
+use crate::noise;
+
+#[must_use = "iterators are lazy and do nothing unless consumed"]
+pub struct Stacker<'iter, T: Iterator<Item = Cow<'iter, Entry>>> {
+    out: entry::Image,
+    /// Images to take the median of, as single channel 32-bit floating point.
+    images: Vec<Mat>,
+    iter: T,
+    done: bool,
+}
+
+impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Stacker<'iter, T> {
+    pub fn new<F: IntoIterator<Item = T::Item, IntoIter = T>>(iter: F) -> Result<Self> {
+        let mut iter = iter.into_iter();
+        let out = iter.next().unwrap().into_owned().into_image()?;
+
+        let mut stacker = Self {
+            out,
+            images: vec![],
+            iter,
+            done: false,
+        };
+        let first = stacker.out.image().clone();
+        stacker.add(&first)?;
+        Ok(stacker)
+    }
+
+    /// Add an image to the stack.
+    fn add(&mut self, image: &Mat) -> Result<()> {
+        let (expected, found) = (self.out.image().size()?, image.size()?);
+        if expected != found {
+            return Err(Error::DimensionMismatch { expected, found });
+        }
+        let image_f = format::to_float(image)?;
+        self.images.push(image_f.reshape(1, 0)?);
+        Ok(())
+    }
+
+    /// Take the median of the added images.
+    fn finish(&mut self) -> Result<()> {
+        let (rows, channels, depth) = (
+            self.out.image().rows(),
+            self.out.image().channels(),
+            self.out.image().depth(),
+        );
+        let data = self
+            .images
+            .iter()
+            .map(|i| i.data_typed::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let mut median =
+            Mat::new_size_with_default(self.images[0].size()?, cv::core::CV_32F, Scalar::all(0.0))?;
+        let mut values = Vec::with_capacity(data.len());
+        for (i, out) in median.data_typed_mut::<f32>()?.iter_mut().enumerate() {
+            values.clear();
+            values.extend(data.iter().map(|d| d[i]));
+            *out = noise::median(&mut values).unwrap_or(f32::NAN);
+        }
+
+        let out = format::convert_cv_depth(&median.reshape(channels, rows)?, depth)?;
+        self.images.clear();
+        self.out.replace_image(out)
+    }
+
+    /// Get the stacked image, which is the first image until all images are stacked.
+    #[inline]
+    pub fn image(&self) -> &Mat {
+        self.out.image()
+    }
+
+    /// Leak the underlying data store.
+    #[inline]
+    pub fn leak(self) -> entry::Image {
+        self.out
+    }
+}
+
+impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Iterator for Stacker<'iter, T> {
+    type Item = Result<()>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.iter.next() {
+            Some(next) => Some(
+                next.read_image()
+                    .and_then(|image| self.add(image.as_ref()))
+                    .map_err(|e| e.with_entry(next.name())),
+            ),
+            // The median is only known once every image is added
+            None if !self.done => {
+                self.done = true;
+                self.finish().err().map(Err)
+            }
+            None => None,
+        }
+    }
+}
@@ -2,14 +2,17 @@
 
 use std::borrow::Cow;
 
+use medo_core::cv::core::Mat;
 use medo_core::entry::Entry;
 use medo_core::Result;
 
 pub mod average;
+pub mod median;
 
 /// A wrapper around stacker types.
 pub enum Stacker<'iter, T: Iterator<Item = Cow<'iter, Entry>>> {
     Average(average::Stacker<'iter, T>),
+    Median(median::Stacker<'iter, T>),
 }
 
 impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Stacker<'iter, T> {
@@ -18,11 +21,28 @@ impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Stacker<'iter, T> {
         Ok(Self::Average(average::Stacker::new(iter)?))
     }
 
+    #[inline]
+    pub fn median<F: IntoIterator<Item = T::Item, IntoIter = T>>(iter: F) -> Result<Self> {
+        Ok(Self::Median(median::Stacker::new(iter)?))
+    }
+
+    /// Get the current stacked image.
+    ///
+    /// A median stack is the first image until all images are stacked.
+    #[inline]
+    pub fn image(&self) -> &Mat {
+        match self {
+            Self::Average(a) => a.image(),
+            Self::Median(m) => m.image(),
+        }
+    }
+
     /// Leak the underlying data store.
     #[inline]
     pub fn leak(self) -> Entry {
         match self {
             Self::Average(a) => Entry::Image(a.leak()),
+            Self::Median(m) => Entry::Image(m.leak()),
         }
     }
 }
@@ -34,6 +54,7 @@ impl<'iter, T: Iterator<Item = Cow<'iter, Entry>>> Iterator for Stacker<'iter, T
     fn next(&mut self) -> Option<Self::Item> {
         match self {
             Self::Average(a) => a.next(),
+            Self::Median(m) => m.next(),
         }
     }
 }
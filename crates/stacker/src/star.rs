@@ -1,10 +1,17 @@
 //! Tools to detect stars in an image and create a mask.
 
-use medo_core::cv::core::{Mat, Point, Point_, Scalar, Size, Vector};
+use std::borrow::Cow;
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, Point, Point_, Rect, Scalar, Size, Vector};
 use medo_core::cv::imgproc;
-use medo_core::Result;
+use medo_core::{format, Result};
+
+use crate::stretch;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct DetectionOpts {
     /// Maximum area that can be filled by a star.
     pub max_area: f32,
@@ -60,6 +67,8 @@ pub fn map_contour(cnt: &Contour, opts: DetectionOpts) -> Result<Option<Circle>>
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
 pub struct ContourDetectionOpts {
     /// Star detections options.
     pub star_detection: DetectionOpts,
@@ -83,13 +92,25 @@ impl Default for ContourDetectionOpts {
 }
 
 /// Find all star contours from an image.
+///
+/// Brightness thresholds are 8-bit values, that images of other depths are scaled to.
 pub fn find_contours(
     img: &Mat,
     opts: ContourDetectionOpts,
 ) -> Result<impl Iterator<Item = Circle>> {
+    let img = if img.depth() == cv::core::CV_8U {
+        Cow::Borrowed(img)
+    } else {
+        let mut patched = img.try_clone()?;
+        if patched.depth() == cv::core::CV_32F {
+            cv::core::patch_na_ns(&mut patched, 0.0)?;
+        }
+        Cow::Owned(format::convert_cv_depth(&patched, cv::core::CV_8U)?)
+    };
+
     // Convert image to grayscale, blur and threshold
     let mut img_gray = Mat::default();
-    imgproc::cvt_color(&img, &mut img_gray, imgproc::COLOR_BGR2GRAY, 0)?;
+    imgproc::cvt_color(img.as_ref(), &mut img_gray, imgproc::COLOR_BGR2GRAY, 0)?;
     let mut img_blur = Mat::default();
     imgproc::median_blur(&img_gray, &mut img_blur, opts.blur_amount)?;
     let mut img_thresh = Mat::default();
@@ -116,6 +137,25 @@ pub fn find_contours(
         .filter_map(move |s| map_contour(&s, opts.star_detection).ok().flatten()))
 }
 
+/// Find the stars of an image of any type and brightness.
+///
+/// Stars are found in an automatically stretched 8-bit copy of the image, so that detection
+/// thresholds work alike for linear stacks and for ordinary images.
+pub fn find_stretched(img: &Mat, opts: ContourDetectionOpts) -> Result<Vec<Circle>> {
+    let mut stretched = stretch::auto_stretch(img, true)?;
+    cv::core::patch_na_ns(&mut stretched, 0.0)?;
+    let mut stretched_8 = Mat::default();
+    stretched.convert_to(&mut stretched_8, cv::core::CV_8U, u8::MAX as f64, 0.0)?;
+    let bgr = if stretched_8.channels() == 1 {
+        let mut bgr = Mat::default();
+        imgproc::cvt_color(&stretched_8, &mut bgr, imgproc::COLOR_GRAY2BGR, 0)?;
+        bgr
+    } else {
+        stretched_8
+    };
+    Ok(find_contours(&bgr, opts)?.collect())
+}
+
 /// Create an image mask from the given stars.
 pub fn create_mask(size: Size, ty: i32, stars: impl Iterator<Item = Circle>) -> Result<Mat> {
     let mut mask = Mat::new_size_with_default(size, ty, Scalar::new(0.0, 0.0, 0.0, 0.0))?;
@@ -140,3 +180,87 @@ pub fn create_mask(size: Size, ty: i32, stars: impl Iterator<Item = Circle>) ->
 
     Ok(mask)
 }
+
+/// Summary of the stars found in an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metrics {
+    /// Number of detected stars.
+    pub stars: usize,
+    /// Median full width at half maximum of the detected stars, in pixels.
+    ///
+    /// This is `NaN` if no stars were detected.
+    pub fwhm: f32,
+}
+
+impl Metrics {
+    /// A quality score for the image these metrics describe.
+    ///
+    /// Images with more and tighter stars score higher.
+    pub fn score(&self) -> f32 {
+        if self.stars == 0 || !self.fwhm.is_finite() || self.fwhm <= 0.0 {
+            return 0.0;
+        }
+        self.stars as f32 / self.fwhm
+    }
+}
+
+/// Estimate the full width at half maximum of a star from a grayscale image.
+///
+/// The star profile is assumed to be gaussian, with its width estimated from the second order
+/// central moments of the background subtracted region around the star.
+pub fn fwhm(img_gray: &Mat, star: &Circle) -> Result<f32> {
+    // Region around the star, clipped to the image
+    let half = (star.radius * 2.0).ceil() as i32 + 1;
+    let x = (star.center.x as i32 - half).max(0);
+    let y = (star.center.y as i32 - half).max(0);
+    let width = (star.center.x as i32 + half + 1).min(img_gray.cols()) - x;
+    let height = (star.center.y as i32 + half + 1).min(img_gray.rows()) - y;
+    if width <= 0 || height <= 0 {
+        return Ok(f32::NAN);
+    }
+    let patch = Mat::roi(img_gray, Rect::new(x, y, width, height))?;
+
+    // Subtract local background
+    let mut background = 0.0;
+    cv::core::min_max_loc(
+        &patch,
+        Some(&mut background),
+        None,
+        None,
+        None,
+        &Mat::default(),
+    )?;
+    let mut profile = Mat::default();
+    cv::core::subtract(
+        &patch,
+        &Scalar::all(background),
+        &mut profile,
+        &Mat::default(),
+        cv::core::CV_32F,
+    )?;
+
+    // sigma^2 = (mu20 + mu02) / (2 * m00) for a circular gaussian
+    let moments = imgproc::moments(&profile, false)?;
+    if moments.m00 <= 0.0 {
+        return Ok(f32::NAN);
+    }
+    let sigma = ((moments.mu20 + moments.mu02) / (2.0 * moments.m00)).sqrt();
+    Ok((2.0 * (2.0 * std::f64::consts::LN_2).sqrt() * sigma) as f32)
+}
+
+/// Detect stars in an image and summarize them.
+pub fn measure(img: &Mat, opts: ContourDetectionOpts) -> Result<Metrics> {
+    let mut img_gray = Mat::default();
+    imgproc::cvt_color(&img, &mut img_gray, imgproc::COLOR_BGR2GRAY, 0)?;
+
+    let mut widths = find_contours(img, opts)?
+        .map(|s| fwhm(&img_gray, &s))
+        .collect::<Result<Vec<_>>>()?;
+    widths.retain(|w| w.is_finite());
+    widths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    Ok(Metrics {
+        stars: widths.len(),
+        fwhm: widths.get(widths.len() / 2).copied().unwrap_or(f32::NAN),
+    })
+}
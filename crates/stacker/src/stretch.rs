@@ -0,0 +1,369 @@
+//! Stretching of linear images, so that faint detail can be seen.
+//!
+//! Stacked images are linear: their values are proportional to the light collected, so the sky
+//! background and all but the brightest stars sit in the darkest few percent of the range, and
+//! the image looks black on screen. Stretching lifts the background and midtones while keeping
+//! highlights from clipping.
+//!
+//! Stretches work on normalized images, whose values are from 0 to 1.
+
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual};
+use medo_core::{format, Result};
+
+use crate::noise::{self, MAD_TO_SIGMA};
+
+/// Default clipping point of the shadows, in standard deviations from the median.
+pub const SHADOWS_CLIP: f32 = -2.8;
+/// Default brightness that the median is stretched to.
+pub const TARGET_BACKGROUND: f32 = 0.25;
+/// Most values that statistics are calculated from, to keep large images fast.
+const MAX_SAMPLES: usize = 1 << 20;
+
+/// Midtones transfer function.
+///
+/// Maps 0 to 0, 1 to 1, and the midtones balance `m` to 0.5. Values below `m` are brightened if
+/// `m` is less than 0.5.
+#[inline]
+pub fn mtf(m: f32, x: f32) -> f32 {
+    if x <= 0.0 {
+        0.0
+    } else if x >= 1.0 {
+        1.0
+    } else {
+        (m - 1.0) * x / ((2.0 * m - 1.0) * x - m)
+    }
+}
+
+/// Parameters of a midtones transfer, with clipping of shadows and highlights.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mtf {
+    /// Value that is mapped to 0, along with everything below it.
+    pub shadows: f32,
+    /// Value, after clipping, that is mapped to 0.5.
+    pub midtones: f32,
+    /// Value that is mapped to 1, along with everything above it.
+    pub highlights: f32,
+}
+
+impl Default for Mtf {
+    /// Parameters that leave values unchanged.
+    #[inline]
+    fn default() -> Self {
+        Self {
+            shadows: 0.0,
+            midtones: 0.5,
+            highlights: 1.0,
+        }
+    }
+}
+
+impl Mtf {
+    /// Find parameters that stretch the background to a brightness.
+    ///
+    /// Shadows are clipped at `shadows_clip` standard deviations from the median, estimated from
+    /// the median absolute deviation, and the median is stretched to `target_background`.
+    ///
+    /// # Parameters
+    /// - `median`: The median of the normalized values.
+    /// - `mad`: The median absolute deviation of the normalized values.
+    pub fn auto(median: f32, mad: f32, shadows_clip: f32, target_background: f32) -> Self {
+        let sigma = mad * MAD_TO_SIGMA as f32;
+        let shadows = (median + shadows_clip * sigma).clamp(0.0, 1.0);
+        let background = if shadows < 1.0 {
+            (median - shadows) / (1.0 - shadows)
+        } else {
+            0.0
+        };
+        // A flat image has no background to stretch
+        let midtones = if background > 0.0 {
+            mtf(target_background, background)
+        } else {
+            0.5
+        };
+        Self {
+            shadows,
+            midtones,
+            highlights: 1.0,
+        }
+    }
+
+    /// Stretch a normalized value.
+    #[inline]
+    pub fn apply(&self, x: f32) -> f32 {
+        if x.is_nan() {
+            return x;
+        }
+        let range = self.highlights - self.shadows;
+        let x = if range > 0.0 {
+            (x - self.shadows) / range
+        } else {
+            (x >= self.highlights) as u8 as f32
+        };
+        mtf(self.midtones, x)
+    }
+}
+
+/// Convert an image to 32-bit floating point, normalized to values from 0 to 1.
+///
+/// Integer images are divided by their type's largest value. Floating point images are divided
+/// by their largest value if it is more than 1, so that images that are not from 0 to 1 can
+/// still be stretched. Values that are not a number are kept.
+pub fn normalize(image: &Mat) -> Result<Mat> {
+    let scale = match image.depth() {
+        cv::core::CV_8U
+        | cv::core::CV_8S
+        | cv::core::CV_16U
+        | cv::core::CV_16S
+        | cv::core::CV_32S => 1.0 / format::white(image.depth()),
+        _ => {
+            let mut patched = image.try_clone()?;
+            if patched.depth() == cv::core::CV_32F {
+                cv::core::patch_na_ns(&mut patched, 0.0)?;
+            }
+            let mut max = 0.0;
+            cv::core::min_max_loc(
+                &patched.reshape(1, 0)?,
+                None,
+                Some(&mut max),
+                None,
+                None,
+                &cv::core::no_array(),
+            )?;
+            if max > 1.0 {
+                1.0 / max
+            } else {
+                1.0
+            }
+        }
+    };
+    let mut out = Mat::default();
+    image.convert_to(&mut out, cv::core::CV_32F, scale, 0.0)?;
+    Ok(out)
+}
+
+/// Convert a normalized image to a depth, scaling integer depths to their full range.
+///
+/// Floating point depths are left from 0 to 1.
+pub fn denormalize(image: &Mat, depth: i32) -> Result<Mat> {
+    format::convert_cv_depth(image, depth)
+}
+
+/// Get a sample of the values of each channel of an image.
+///
+/// With `linked`, the values of all channels are sampled together, and a single sample is
+/// returned.
+fn samples(image: &Mat, linked: bool) -> Result<Vec<Vec<f32>>> {
+    let channels = image.channels() as usize;
+    let image = image.try_clone()?.reshape(1, 0)?;
+    let data = image.data_typed::<f32>()?;
+    let pixels = data.len() / channels;
+    let step = (pixels / MAX_SAMPLES).max(1);
+
+    let mut samples = vec![Vec::with_capacity(pixels / step + 1); channels];
+    for pixel in data.chunks_exact(channels).step_by(step) {
+        for (sample, v) in samples.iter_mut().zip(pixel) {
+            sample.push(*v);
+        }
+    }
+    if linked {
+        samples = vec![samples.concat()];
+    }
+    Ok(samples)
+}
+
+/// Get the median and median absolute deviation of each channel of a normalized image.
+///
+/// One value is returned for each channel, or `None` if the channel has no valid values. With
+/// `linked`, all channels share the statistics of all of their values.
+pub fn median_mad(image: &Mat, linked: bool) -> Result<Vec<Option<(f32, f32)>>> {
+    let channels = image.channels() as usize;
+    let stats = samples(image, linked)?
+        .into_iter()
+        .map(|mut values| noise::median_mad(&mut values))
+        .collect::<Vec<_>>();
+    Ok(match stats[..] {
+        [s] => vec![s; channels],
+        _ => stats,
+    })
+}
+
+/// Find parameters that stretch each channel of a normalized image.
+///
+/// One set of parameters is returned for each channel. With `linked`, all channels share the
+/// parameters found from all of their values, which keeps the colour balance. Otherwise, each
+/// channel is stretched by its own statistics, which neutralizes a colour cast.
+pub fn auto_mtf(
+    image: &Mat,
+    linked: bool,
+    shadows_clip: f32,
+    target_background: f32,
+) -> Result<Vec<Mtf>> {
+    Ok(median_mad(image, linked)?
+        .into_iter()
+        .map(|stats| match stats {
+            Some((median, mad)) => Mtf::auto(median, mad, shadows_clip, target_background),
+            None => Mtf::default(),
+        })
+        .collect())
+}
+
+/// Apply a function to the values of each channel of an image.
+///
+/// The image must be 32-bit floating point, and the function is given the channel's index.
+pub fn map_channels<F: Fn(usize, f32) -> f32>(image: &Mat, f: F) -> Result<Mat> {
+    let (channels, rows) = (image.channels(), image.rows());
+    let mut out = image.try_clone()?.reshape(1, 0)?;
+    for pixel in out
+        .data_typed_mut::<f32>()?
+        .chunks_exact_mut(channels as usize)
+    {
+        for (c, v) in pixel.iter_mut().enumerate() {
+            *v = f(c, *v);
+        }
+    }
+    Ok(out.reshape(channels, rows)?)
+}
+
+/// Apply a function to the luminance of each pixel of an image, keeping its colour.
+///
+/// The luminance is the mean of a pixel's channels, and every channel is scaled by how much the
+/// function changes it. Channels are clipped at 1, so bright colours saturate towards white.
+/// The image must be 32-bit floating point.
+pub fn map_luminance<F: Fn(f32) -> f32>(image: &Mat, f: F) -> Result<Mat> {
+    let channels = image.channels();
+    if channels == 1 {
+        return map_channels(image, |_, v| f(v));
+    }
+    let rows = image.rows();
+    let mut out = image.try_clone()?.reshape(1, 0)?;
+    for pixel in out
+        .data_typed_mut::<f32>()?
+        .chunks_exact_mut(channels as usize)
+    {
+        let luminance = pixel.iter().sum::<f32>() / channels as f32;
+        if luminance.is_nan() {
+            continue;
+        }
+        let scale = if luminance > 0.0 {
+            f(luminance) / luminance
+        } else {
+            0.0
+        };
+        for v in pixel.iter_mut() {
+            *v = (*v * scale).min(1.0);
+        }
+    }
+    Ok(out.reshape(channels, rows)?)
+}
+
+/// Stretch a normalized image by a midtones transfer for each channel.
+#[inline]
+pub fn apply_mtf(image: &Mat, params: &[Mtf]) -> Result<Mat> {
+    map_channels(image, |c, v| params[c].apply(v))
+}
+
+/// Stretch an image automatically, for display.
+///
+/// The image is normalized, then stretched by [`auto_mtf`] with the default clipping point and
+/// target background. The stretched image is 32-bit floating point.
+pub fn auto_stretch(image: &Mat, linked: bool) -> Result<Mat> {
+    let normalized = normalize(image)?;
+    let params = auto_mtf(&normalized, linked, SHADOWS_CLIP, TARGET_BACKGROUND)?;
+    apply_mtf(&normalized, &params)
+}
+
+/// Inverse hyperbolic sine stretch of a normalized value.
+///
+/// Faint values are stretched almost linearly by `factor`, and bright values logarithmically,
+/// which keeps the cores of stars from saturating. A factor of 0 leaves values unchanged.
+#[inline]
+pub fn arcsinh(factor: f32, x: f32) -> f32 {
+    if factor <= 0.0 {
+        x
+    } else {
+        (factor * x).asinh() / factor.asinh()
+    }
+}
+
+/// Parameters of a generalized hyperbolic stretch.
+///
+/// The stretch adds the most contrast around the symmetry point, and its shape is chosen by the
+/// local intensity: 0 is exponential, negative values spread the stretch more widely, down to
+/// logarithmic at -1, and positive values focus it more tightly around the symmetry point.
+/// Below the shadow protection point and above the highlight protection point, the stretch is
+/// linear.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ghs {
+    /// Strength of the stretch, as `ln(D + 1)`. 0 leaves values unchanged.
+    pub stretch: f32,
+    /// Shape of the stretch, from -5 to 15.
+    pub local_intensity: f32,
+    /// Value around which contrast is added the most.
+    pub symmetry_point: f32,
+    /// Value below which the stretch is linear.
+    pub shadow_protection: f32,
+    /// Value above which the stretch is linear.
+    pub highlight_protection: f32,
+}
+
+impl Ghs {
+    /// Base transform of a distance from the symmetry point.
+    fn base(d: f64, b: f64, x: f64) -> f64 {
+        if b == -1.0 {
+            (d * x).ln_1p()
+        } else if b < 0.0 {
+            (1.0 - (1.0 - b * d * x).powf((b + 1.0) / b)) / (d * (b + 1.0))
+        } else if b == 0.0 {
+            1.0 - (-d * x).exp()
+        } else {
+            1.0 - (1.0 + b * d * x).powf(-1.0 / b)
+        }
+    }
+
+    /// Slope of the base transform.
+    fn slope(d: f64, b: f64, x: f64) -> f64 {
+        if b == -1.0 {
+            d / (1.0 + d * x)
+        } else if b < 0.0 {
+            (1.0 - b * d * x).powf(1.0 / b)
+        } else if b == 0.0 {
+            d * (-d * x).exp()
+        } else {
+            d * (1.0 + b * d * x).powf(-(1.0 + b) / b)
+        }
+    }
+
+    /// Get the transfer function of the stretch, which maps 0 to 0 and 1 to 1.
+    ///
+    /// The protection points are clamped so that the shadow protection point is no greater than
+    /// the symmetry point, and the highlight protection point is no less.
+    pub fn curve(&self) -> impl Fn(f32) -> f32 {
+        let d = (self.stretch as f64).exp_m1();
+        let b = self.local_intensity as f64;
+        let sp = (self.symmetry_point as f64).clamp(0.0, 1.0);
+        let lp = (self.shadow_protection as f64).clamp(0.0, sp);
+        let hp = (self.highlight_protection as f64).clamp(sp, 1.0);
+
+        let raw = move |x: f64| {
+            if x < lp {
+                -Self::base(d, b, sp - lp) - Self::slope(d, b, sp - lp) * (lp - x)
+            } else if x < sp {
+                -Self::base(d, b, sp - x)
+            } else if x <= hp {
+                Self::base(d, b, x - sp)
+            } else {
+                Self::base(d, b, hp - sp) + Self::slope(d, b, hp - sp) * (x - hp)
+            }
+        };
+        let (low, high) = (raw(0.0), raw(1.0));
+        let identity = !(d > 0.0 && high > low);
+        move |x: f32| {
+            if identity || x.is_nan() {
+                x
+            } else {
+                ((raw(x.clamp(0.0, 1.0) as f64) - low) / (high - low)) as f32
+            }
+        }
+    }
+}
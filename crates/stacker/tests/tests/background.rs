@@ -0,0 +1,49 @@
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar};
+use medo_stacker::background::{self, Model};
+
+/// Create a linear gradient from left to right, with a bright square in the middle.
+fn gradient() -> Mat {
+    let mut image =
+        Mat::new_rows_cols_with_default(128, 128, cv::core::CV_32FC1, Scalar::all(0.0)).unwrap();
+    for y in 0..128 {
+        let row = image.at_row_mut::<f32>(y).unwrap();
+        for (x, v) in row.iter_mut().enumerate() {
+            *v = 0.1 + 0.2 * x as f32 / 128.0;
+            if (56..72).contains(&x) && (56..72).contains(&y) {
+                *v += 0.5;
+            }
+        }
+    }
+    image
+}
+
+#[test]
+fn subtract_gradient() {
+    let image = gradient();
+    let mask = Mat::new_size_with_default(image.size().unwrap(), cv::core::CV_8U, Scalar::all(0.0))
+        .unwrap();
+
+    for model in [
+        Model::Polynomial { degree: 1 },
+        Model::Rbf { smoothing: 0.01 },
+    ] {
+        let samples = background::sample(&image, &mask, 8, 9).unwrap();
+        assert_eq!(samples.len(), 64);
+        let (model, used) = background::fit(&samples, image.size().unwrap(), model, 2.0).unwrap();
+        // The samples of the bright square are rejected
+        assert!(used < 64);
+
+        let flat = background::subtract(&image, &model).unwrap();
+        let row = flat.at_row::<f32>(8).unwrap();
+        let (min, max) = row.iter().fold((f32::MAX, f32::MIN), |(min, max), v| {
+            (min.min(*v), max.max(*v))
+        });
+        assert!(
+            max - min < 0.01,
+            "background is not flat: {} to {}",
+            min,
+            max
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Rect, Scalar};
+use medo_stacker::color;
+
+/// Create a BGR image with a reddish background and a greenish white square in the middle.
+fn tinted() -> Mat {
+    let mut image =
+        Mat::new_rows_cols_with_default(64, 64, cv::core::CV_32FC3, Scalar::all(0.0)).unwrap();
+    for y in 0..64 {
+        let row = image.at_row_mut::<cv::core::Vec3f>(y).unwrap();
+        for (x, v) in row.iter_mut().enumerate() {
+            v.0 = if (24..40).contains(&x) && (24..40).contains(&y) {
+                [0.5, 0.7, 0.7]
+            } else {
+                [0.1, 0.1, 0.2]
+            };
+        }
+    }
+    image
+}
+
+#[test]
+fn neutralize_and_white_balance() {
+    let image = tinted();
+    let background = Rect::new(0, 0, 16, 16);
+    let levels = color::background_levels(&image, Some(background)).unwrap();
+    assert_eq!(levels.len(), 3);
+
+    let neutral = color::neutralize(&image, &levels).unwrap();
+    let levels = color::background_levels(&neutral, Some(background)).unwrap();
+    for level in &levels {
+        assert!((level - levels[0]).abs() < 1e-6, "background is not grey");
+    }
+
+    let white = Rect::new(28, 28, 8, 8);
+    let reference = color::region_color(&neutral, white, &levels).unwrap();
+    let balanced = color::white_balance(&neutral, &reference, &levels).unwrap();
+    let balanced_white = color::region_color(&balanced, white, &levels).unwrap();
+    for c in &balanced_white {
+        assert!(
+            (c - 0.5).abs() < 1e-5,
+            "white is not white: {:?}",
+            balanced_white
+        );
+    }
+    // The background is unchanged by the white balance
+    let balanced_levels = color::background_levels(&balanced, Some(background)).unwrap();
+    for (a, b) in balanced_levels.iter().zip(&levels) {
+        assert!((a - b).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn scnr() {
+    let image = Mat::from_slice_2d(&[[0.2f32, 0.6, 0.4, 0.5, 0.1, 0.3]])
+        .unwrap()
+        .reshape(3, 1)
+        .unwrap();
+
+    let cases = [
+        (color::Neutral::Average, 1.0, [0.3, 0.1]),
+        (color::Neutral::Maximum, 1.0, [0.4, 0.1]),
+        (color::Neutral::Average, 0.5, [0.45, 0.1]),
+    ];
+    for (neutral, amount, green) in cases {
+        let reduced = color::scnr(&image, neutral, amount).unwrap();
+        let values = reduced.reshape(1, 0).unwrap();
+        let values = values.data_typed::<f32>().unwrap();
+        for (pixel, green) in values.chunks_exact(3).zip(green) {
+            assert!(
+                (pixel[1] - green).abs() < 1e-6,
+                "{:?} by {}: green is {}, not {}",
+                neutral,
+                amount,
+                pixel[1],
+                green
+            );
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+
+use medo_core::cv::core::{Mat, MatTraitConst};
+use medo_core::format::{self, Depth, Format};
+use medo_core::util::WorkDir;
+
+const BLOCK: usize = 2880;
+
+/// Write an image as FITS, and get the header's values and the data.
+fn write_fits(image: &Mat, depth: Depth) -> (HashMap<String, String>, Vec<u8>) {
+    let dir = WorkDir::new().unwrap();
+    let path = dir.path().join("image.fits");
+    format::write_image(&path, image, Format::Fits, Some(depth)).unwrap();
+    let file = std::fs::read(path).unwrap();
+    assert_eq!(file.len() % BLOCK, 0);
+
+    let mut header = HashMap::new();
+    let mut cards = file.chunks(80);
+    for card in &mut cards {
+        let card = std::str::from_utf8(card).unwrap();
+        if card.trim_end() == "END" {
+            break;
+        }
+        let (key, value) = card.split_once('=').unwrap();
+        header.insert(key.trim().to_owned(), value.trim().to_owned());
+    }
+    let header_len = (header.len() + 1) * 80;
+    let data_start = (header_len + BLOCK - 1) / BLOCK * BLOCK;
+    assert!(file[header_len..data_start].iter().all(|b| *b == b' '));
+    (header, file[data_start..].to_vec())
+}
+
+#[test]
+fn fits_u16_header_and_rows() {
+    let image = Mat::from_slice_2d(&[[0u16, 1000, 65535], [2, 3, 4]]).unwrap();
+    let (header, data) = write_fits(&image, Depth::U16);
+    assert_eq!(header["SIMPLE"], "T");
+    assert_eq!(header["BITPIX"], "16");
+    assert_eq!(header["NAXIS"], "2");
+    assert_eq!(header["NAXIS1"], "3");
+    assert_eq!(header["NAXIS2"], "2");
+    assert_eq!(header["BZERO"], "32768");
+    assert_eq!(header["BSCALE"], "1");
+    assert!(!header.contains_key("NAXIS3"));
+
+    // Rows are written from the bottom up, as signed values offset by BZERO
+    assert_eq!(data.len(), BLOCK);
+    let values = data[..12]
+        .chunks(2)
+        .map(|v| (i16::from_be_bytes([v[0], v[1]]) as i32 + 32768) as u16)
+        .collect::<Vec<_>>();
+    assert_eq!(values, vec![2, 3, 4, 0, 1000, 65535]);
+    assert!(data[12..].iter().all(|b| *b == 0));
+}
+
+#[test]
+fn fits_colour_planes_are_rgb() {
+    // Two BGR pixels
+    let image = Mat::from_slice_2d(&[[10u8, 20, 30, 40, 50, 60]])
+        .and_then(|m| m.reshape(3, 1))
+        .unwrap();
+    let (header, data) = write_fits(&image, Depth::U8);
+    assert_eq!(header["BITPIX"], "8");
+    assert_eq!(header["NAXIS"], "3");
+    assert_eq!(header["NAXIS1"], "2");
+    assert_eq!(header["NAXIS2"], "1");
+    assert_eq!(header["NAXIS3"], "3");
+    assert!(!header.contains_key("BZERO"));
+
+    // BGR pixels are written as red, green and blue planes
+    assert_eq!(data.len(), BLOCK);
+    assert_eq!(&data[..6], &[30, 60, 20, 50, 10, 40]);
+    assert!(data[6..].iter().all(|b| *b == 0));
+}
@@ -0,0 +1,25 @@
+use medo_core::cv;
+use medo_core::cv::core::{Mat, Scalar};
+use medo_stacker::noise;
+
+#[test]
+fn median_mad_ignores_invalid() {
+    let mut values = vec![1.0, f32::NAN, 2.0, 4.0, 3.0, 100.0];
+    assert_eq!(noise::median_mad(&mut values), Some((3.0, 1.0)));
+    assert_eq!(noise::median_mad(&mut vec![f32::NAN]), None);
+}
+
+#[test]
+fn estimate_noise() {
+    let flat =
+        Mat::new_rows_cols_with_default(32, 32, cv::core::CV_32FC3, Scalar::all(10.0)).unwrap();
+    assert_eq!(noise::estimate(&flat).unwrap(), 0.0);
+
+    let mut noisy =
+        Mat::new_rows_cols_with_default(32, 32, cv::core::CV_32FC1, Scalar::all(0.0)).unwrap();
+    cv::core::randn(&mut noisy, &Scalar::all(100.0), &Scalar::all(5.0)).unwrap();
+    let mut quiet =
+        Mat::new_rows_cols_with_default(32, 32, cv::core::CV_32FC1, Scalar::all(0.0)).unwrap();
+    cv::core::randn(&mut quiet, &Scalar::all(100.0), &Scalar::all(1.0)).unwrap();
+    assert!(noise::estimate(&noisy).unwrap() > 2.0 * noise::estimate(&quiet).unwrap());
+}
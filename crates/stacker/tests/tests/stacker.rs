@@ -1,8 +1,10 @@
 use std::borrow::Cow;
 
-use medo_core::cv::core::{MatTraitConst, Point3_};
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, Point3_, Scalar};
 use medo_core::entry::Entry;
-use medo_stacker::stacker::Stacker;
+use medo_core::{format, Error};
+use medo_stacker::stacker::{average, Stacker};
 use medo_stacker_tests::common;
 
 #[test]
@@ -31,3 +33,170 @@ fn stack_average_binary() {
         }
     }
 }
+
+#[test]
+fn stack_average_ignores_invalid() {
+    let valid =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(10.0)).unwrap();
+    let invalid =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(f64::NAN)).unwrap();
+    let valid = Entry::new_image("valid", valid).unwrap();
+    let invalid = Entry::new_image("invalid", invalid).unwrap();
+    let mut stacker = Stacker::average([Cow::Owned(valid), Cow::Owned(invalid)]).unwrap();
+    for i in stacker.by_ref() {
+        i.unwrap();
+    }
+    let last = stacker.leak();
+    let image = last.read_image().unwrap();
+
+    for i in 0..image.rows() {
+        for j in 0..image.cols() {
+            assert_eq!(
+                image.at_nd::<Point3_<f32>>(&[i, j]).unwrap(),
+                &Point3_ {
+                    x: 10.0,
+                    y: 10.0,
+                    z: 10.0
+                }
+            )
+        }
+    }
+}
+
+#[test]
+fn stack_average_dimension_mismatch() {
+    let small = Mat::new_rows_cols_with_default(4, 4, cv::core::CV_8UC3, Scalar::all(0.0)).unwrap();
+    let large = Mat::new_rows_cols_with_default(8, 8, cv::core::CV_8UC3, Scalar::all(0.0)).unwrap();
+    let small = Entry::new_image("small", small).unwrap();
+    let large = Entry::new_image("large", large).unwrap();
+    let mut stacker = Stacker::average([Cow::Owned(small), Cow::Owned(large)]).unwrap();
+
+    let error = stacker.next().unwrap().unwrap_err();
+    assert_eq!(error.entry(), Some("large"));
+    assert!(matches!(error.kind(), Error::DimensionMismatch { .. }));
+}
+
+#[test]
+fn stack_average_incremental() {
+    let first =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(10.0)).unwrap();
+    let first = Entry::new_image("first", first).unwrap();
+    let mut stacker = average::Stacker::new(std::iter::once(Cow::Owned(first))).unwrap();
+    for v in [20.0, 30.0] {
+        let image =
+            Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(v)).unwrap();
+        stacker.add(&image).unwrap();
+    }
+    let image = stacker.image();
+
+    for i in 0..image.rows() {
+        for j in 0..image.cols() {
+            assert_eq!(
+                image.at_nd::<Point3_<f32>>(&[i, j]).unwrap(),
+                &Point3_ {
+                    x: 20.0,
+                    y: 20.0,
+                    z: 20.0
+                }
+            )
+        }
+    }
+}
+
+#[test]
+fn stack_weighted_average() {
+    let low = Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(10.0)).unwrap();
+    let high =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(40.0)).unwrap();
+    let image = average::weighted([(&low, 2.0), (&high, 1.0)]).unwrap();
+
+    for i in 0..image.rows() {
+        for j in 0..image.cols() {
+            assert_eq!(
+                image.at_nd::<Point3_<f32>>(&[i, j]).unwrap(),
+                &Point3_ {
+                    x: 20.0,
+                    y: 20.0,
+                    z: 20.0
+                }
+            )
+        }
+    }
+}
+
+#[test]
+fn stack_median_rejects_outliers() {
+    let images = [10.0, 12.0, 200.0, f64::NAN]
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let image =
+                Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(*v)).unwrap();
+            Cow::Owned(Entry::new_image(i.to_string(), image).unwrap())
+        })
+        .collect::<Vec<_>>();
+    let mut stacker = Stacker::median(images).unwrap();
+    for i in stacker.by_ref() {
+        i.unwrap();
+    }
+    let last = stacker.leak();
+    let image = last.read_image().unwrap();
+
+    for i in 0..image.rows() {
+        for j in 0..image.cols() {
+            assert_eq!(
+                image.at_nd::<Point3_<f32>>(&[i, j]).unwrap(),
+                &Point3_ {
+                    x: 12.0,
+                    y: 12.0,
+                    z: 12.0
+                }
+            )
+        }
+    }
+}
+
+#[test]
+fn stack_average_mixed_depths() {
+    let integer =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_8UC3, Scalar::all(51.0)).unwrap();
+    let float =
+        Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(0.6)).unwrap();
+    let image = average::weighted([(&integer, 1.0), (&float, 1.0)]).unwrap();
+
+    assert_eq!(image.typ(), cv::core::CV_8UC3);
+    for i in 0..image.rows() {
+        for j in 0..image.cols() {
+            assert_eq!(
+                image.at_nd::<Point3_<u8>>(&[i, j]).unwrap(),
+                &Point3_ {
+                    x: 102,
+                    y: 102,
+                    z: 102
+                }
+            )
+        }
+    }
+}
+
+#[test]
+fn stack_float_to_16_bit() {
+    let low = Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(0.2)).unwrap();
+    let high = Mat::new_rows_cols_with_default(4, 4, cv::core::CV_32FC3, Scalar::all(0.6)).unwrap();
+    let stack = average::weighted([(&low, 1.0), (&high, 1.0)]).unwrap();
+    let image = format::convert_depth(&stack, format::Depth::U16).unwrap();
+
+    assert_eq!(image.typ(), cv::core::CV_16UC3);
+    for i in 0..image.rows() {
+        for j in 0..image.cols() {
+            assert_eq!(
+                image.at_nd::<Point3_<u16>>(&[i, j]).unwrap(),
+                &Point3_ {
+                    x: 26214,
+                    y: 26214,
+                    z: 26214
+                }
+            )
+        }
+    }
+}
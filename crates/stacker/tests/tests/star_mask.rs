@@ -13,3 +13,14 @@ fn create_star_mask() {
     // Write results
     common::write_image("star_mask.jpg", &mask).unwrap();
 }
+
+#[test]
+fn measure_stars() {
+    // Read test image
+    let image = common::read_image("template.jpg").unwrap();
+    // Measure
+    let metrics = star::measure(&image, Default::default()).unwrap();
+    assert!(metrics.stars > 0);
+    assert!(metrics.fwhm.is_finite() && metrics.fwhm > 0.0);
+    assert!(metrics.score() > 0.0);
+}
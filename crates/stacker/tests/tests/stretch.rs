@@ -0,0 +1,56 @@
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, Scalar};
+use medo_stacker::{noise, stretch};
+
+#[test]
+fn mtf_maps_midtones_to_half() {
+    assert_eq!(stretch::mtf(0.1, 0.0), 0.0);
+    assert_eq!(stretch::mtf(0.1, 1.0), 1.0);
+    assert!((stretch::mtf(0.1, 0.1) - 0.5).abs() < 1e-6);
+    assert!(stretch::mtf(0.1, 0.05) > 0.05);
+}
+
+#[test]
+fn auto_stretch_brightens_background() {
+    let mut image =
+        Mat::new_rows_cols_with_default(64, 64, cv::core::CV_16UC3, Scalar::all(0.0)).unwrap();
+    cv::core::randn(&mut image, &Scalar::all(1000.0), &Scalar::all(20.0)).unwrap();
+
+    for linked in [true, false] {
+        let stretched = stretch::auto_stretch(&image, linked).unwrap();
+        assert_eq!(stretched.typ(), cv::core::CV_32FC3);
+        let mut values = stretched
+            .reshape(1, 0)
+            .unwrap()
+            .data_typed::<f32>()
+            .unwrap()
+            .to_vec();
+        let median = noise::median(&mut values).unwrap();
+        assert!((median - stretch::TARGET_BACKGROUND).abs() < 0.02);
+    }
+}
+
+#[test]
+fn hyperbolic_stretches_keep_range() {
+    assert_eq!(stretch::arcsinh(0.0, 0.3), 0.3);
+    assert!((stretch::arcsinh(100.0, 1.0) - 1.0).abs() < 1e-6);
+    assert!(stretch::arcsinh(100.0, 0.01) > 0.1);
+
+    for local_intensity in [-1.0, -0.5, 0.0, 2.0] {
+        let curve = stretch::Ghs {
+            stretch: 3.0,
+            local_intensity,
+            symmetry_point: 0.1,
+            shadow_protection: 0.0,
+            highlight_protection: 1.0,
+        }
+        .curve();
+        assert!(curve(0.0).abs() < 1e-6);
+        assert!((curve(1.0) - 1.0).abs() < 1e-6);
+        assert!(curve(0.1) > 0.1);
+        let values = (0..=100)
+            .map(|i| curve(i as f32 / 100.0))
+            .collect::<Vec<_>>();
+        assert!(values.windows(2).all(|w| w[1] >= w[0]));
+    }
+}
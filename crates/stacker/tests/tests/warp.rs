@@ -0,0 +1,132 @@
+use medo_core::cv;
+use medo_core::cv::core::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual, Scalar};
+use medo_core::warp::{self, Interpolation};
+
+fn shift(x: f64) -> Mat {
+    Mat::from_slice_2d(&[[1.0, 0.0, x], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]).unwrap()
+}
+
+#[test]
+fn warp_identity_keeps_image() {
+    let mut image =
+        Mat::new_rows_cols_with_default(16, 16, cv::core::CV_8UC3, Scalar::all(0.0)).unwrap();
+    cv::core::randu(&mut image, &Scalar::all(0.0), &Scalar::all(255.0)).unwrap();
+
+    for interpolation in [
+        Interpolation::Nearest,
+        Interpolation::Bilinear,
+        Interpolation::Bicubic,
+        Interpolation::Lanczos3,
+        Interpolation::Lanczos4,
+    ] {
+        let opts = warp::Opts {
+            interpolation,
+            ..Default::default()
+        };
+        let warped =
+            warp::warp_perspective(&image, &shift(0.0), image.size().unwrap(), opts).unwrap();
+        assert_eq!(warped.typ(), image.typ());
+        let error = cv::core::norm2(&image, &warped, cv::core::NORM_INF, &Mat::default()).unwrap();
+        assert!(
+            error <= 1.0,
+            "{:?} changed the image by {}",
+            interpolation,
+            error
+        );
+    }
+}
+
+#[test]
+fn warp_invalid_border_is_normalized_float() {
+    let image =
+        Mat::new_rows_cols_with_default(8, 8, cv::core::CV_8UC3, Scalar::all(51.0)).unwrap();
+
+    for interpolation in [Interpolation::Bicubic, Interpolation::Lanczos3] {
+        let opts = warp::Opts {
+            interpolation,
+            invalid_border: true,
+            ..Default::default()
+        };
+        let warped =
+            warp::warp_perspective(&image, &shift(4.0), image.size().unwrap(), opts).unwrap();
+        assert_eq!(warped.typ(), cv::core::CV_32FC3);
+        let row = warped.reshape(1, 0).unwrap();
+        let row = row.at_row::<f32>(4).unwrap();
+        assert!(row[0].is_nan());
+        assert!((row[row.len() - 1] - 0.2).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn warp_clamps_to_the_same_neighbourhood() {
+    // A sharp edge and a star, which ring when interpolated
+    let mut image =
+        Mat::new_rows_cols_with_default(16, 16, cv::core::CV_32FC1, Scalar::all(0.0)).unwrap();
+    for y in 0..16 {
+        for x in 8..16 {
+            *image.at_2d_mut::<f32>(y, x).unwrap() = 0.5;
+        }
+    }
+    *image.at_2d_mut::<f32>(4, 4).unwrap() = 1.0;
+
+    // Resampled pixels are nearest to the source pixel at the same position
+    let range = |op: fn(&Mat, &mut Mat) -> cv::Result<()>| {
+        let mut out = Mat::default();
+        op(&image, &mut out).unwrap();
+        out
+    };
+    let min = range(|src, dst| {
+        cv::imgproc::erode(
+            src,
+            dst,
+            &Mat::default(),
+            cv::core::Point::new(-1, -1),
+            1,
+            cv::core::BORDER_REPLICATE,
+            cv::imgproc::morphology_default_border_value()?,
+        )
+    });
+    let max = range(|src, dst| {
+        cv::imgproc::dilate(
+            src,
+            dst,
+            &Mat::default(),
+            cv::core::Point::new(-1, -1),
+            1,
+            cv::core::BORDER_REPLICATE,
+            cv::imgproc::morphology_default_border_value()?,
+        )
+    });
+
+    for interpolation in [
+        Interpolation::Bicubic,
+        Interpolation::Lanczos3,
+        Interpolation::Lanczos4,
+    ] {
+        let opts = warp::Opts {
+            interpolation,
+            ..Default::default()
+        };
+        let warped =
+            warp::warp_perspective(&image, &shift(0.3), image.size().unwrap(), opts).unwrap();
+        for y in 0..16 {
+            for x in 0..16 {
+                let v = *warped.at_2d::<f32>(y, x).unwrap();
+                let (min, max) = (
+                    *min.at_2d::<f32>(y, x).unwrap(),
+                    *max.at_2d::<f32>(y, x).unwrap(),
+                );
+                assert!(
+                    (min - 1e-6..=max + 1e-6).contains(&v),
+                    "{:?} resampled ({}, {}) to {}, outside {}..{}",
+                    interpolation,
+                    x,
+                    y,
+                    v,
+                    min,
+                    max
+                );
+            }
+        }
+    }
+}
@@ -1,19 +1,646 @@
 //! Command line argument parser.
 
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+use medo::core::format;
+use medo::{calibration, input, pipeline, project, reference};
 
 /// Command line options.
 #[derive(Debug, Parser)]
 #[clap(name = "medo", version)]
 pub struct Opts {
-    /// Input directory.
+    /// Maximum threads for each unit of work.
+    #[clap(short, long, default_value = "4", global = true)]
+    pub max_threads: usize,
+    /// Print a JSON report of the outputs, and of the frames that were accepted or rejected,
+    /// once the command finishes.
+    ///
+    /// Logs are always written to standard error.
+    #[clap(long, global = true)]
+    pub json: bool,
+    /// Also print each pipeline event as it happens, as a line of JSON.
+    #[clap(long, global = true, requires = "json")]
+    pub json_events: bool,
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Calibrate, align and stack images into a master.
+    Stack(StackOpts),
+    /// Calibrate and align images, writing each registered image.
+    Align(AlignOpts),
+    /// Build a bias, dark or flat master from calibration frames.
+    Calibrate(CalibrateOpts),
+    /// Print the quality metrics of each image.
+    Analyze(AnalyzeOpts),
+    /// Print the dimensions, depth and metadata of an image.
+    Info(InfoOpts),
+    /// Stack images as they are captured into a directory, until interrupted.
+    Live(LiveOpts),
+    /// Convert an image to another format, given by the output's extension or `--output-format`.
+    Convert(ConvertOpts),
+    /// Work with project files.
+    #[clap(subcommand)]
+    Project(ProjectCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ProjectCommand {
+    /// Process the groups of a project file whose images or pipeline changed.
+    Run(ProjectRunOpts),
+}
+
+/// Options of the `project run` command.
+#[derive(Debug, Args)]
+pub struct ProjectRunOpts {
+    /// Project file, as written by `stack --save-project`.
     #[clap(parse(from_os_str))]
-    pub input: PathBuf,
+    pub file: PathBuf,
+    /// Process every group, even if it did not change.
+    #[clap(long)]
+    pub force: bool,
+}
+
+/// Options of the `stack` command.
+#[derive(Debug, Args)]
+pub struct StackOpts {
+    /// Input images.
+    ///
+    /// Each is a directory, a file, a glob pattern such as `lights/*.tif`, or a file listing one
+    /// input per line prefixed by `@`, such as `@lights.txt`.
+    #[clap(required = true)]
+    pub input: Vec<String>,
     /// Output file.
     #[clap(parse(from_os_str))]
     pub output: PathBuf,
-    /// Maximum threads for each unit of work.
-    #[clap(short, long, default_value = "4")]
-    pub max_threads: usize,
+    #[clap(flatten)]
+    pub inputs: InputArgs,
+    /// Pipeline configuration file, in TOML or YAML.
+    ///
+    /// Defaults to aligning, sharpening and stacking images.
+    #[clap(short, long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    #[clap(flatten)]
+    pub output_args: OutputArgs,
+    #[clap(flatten)]
+    pub calibration: CalibrationArgs,
+    #[clap(flatten)]
+    pub alignment: AlignmentArgs,
+    /// Strength of the sharpening stage.
+    #[clap(long)]
+    pub sharpen_amount: Option<f64>,
+    /// Method the stacking stage combines images with.
+    #[clap(long, value_enum)]
+    pub stacking_method: Option<StackingMethod>,
+    /// Write a stretched preview of the stack to this PNG or JPEG file as images are stacked.
+    #[clap(long, parse(from_os_str))]
+    pub preview: Option<PathBuf>,
+    /// Length of the preview's longest side, in pixels.
+    #[clap(long, default_value = "1024")]
+    pub preview_size: u32,
+    /// Remove gradients from the stack by modelling its background with this surface.
+    #[clap(long, value_enum)]
+    pub background: Option<BackgroundModel>,
+    /// How the background model is removed.
+    #[clap(long, value_enum, default_value = "subtract")]
+    pub background_correction: BackgroundCorrection,
+    /// Write the background model of each stack to this directory, for inspection.
+    #[clap(long, parse(from_os_str), requires = "background")]
+    pub background_model_dir: Option<PathBuf>,
+    /// Neutralize the background of colour stacks and white balance them by their stars.
+    #[clap(long)]
+    pub color_calibration: bool,
+    /// Region of background to neutralize by, as x,y,width,height in pixels.
+    ///
+    /// Defaults to the whole stack.
+    #[clap(long, requires = "color_calibration")]
+    pub neutral_region: Option<pipeline::color::Region>,
+    /// Region to white balance by instead of stars, as x,y,width,height in pixels.
+    #[clap(long, requires = "color_calibration")]
+    pub white_region: Option<pipeline::color::Region>,
+    /// Remove green noise from colour stacks, limiting green to this neutral level.
+    #[clap(long, value_enum)]
+    pub scnr: Option<ScnrMode>,
+    /// How much green noise is removed, from 0 to 1.
+    #[clap(long, default_value = "1")]
+    pub scnr_amount: f32,
+    /// Stretch the stack for display with this function, after every other stage.
+    ///
+    /// The stack is linear otherwise, and looks black in most viewers.
+    #[clap(long, value_enum)]
+    pub stretch: Option<StretchFunction>,
+    /// Stretch colour channels separately, which neutralizes a colour cast.
+    #[clap(long, requires = "stretch")]
+    pub stretch_unlinked: bool,
+    /// Save the output of every stage to this directory, and resume from it on later runs.
+    #[clap(long, parse(from_os_str))]
+    pub checkpoint_dir: Option<PathBuf>,
+    /// Stop as soon as any entry fails to be processed, instead of leaving it out.
+    #[clap(long)]
+    pub abort_on_error: bool,
+    /// Stop if more than this percentage of entries fail to be processed in any stage.
+    #[clap(long, conflicts_with = "abort_on_error")]
+    pub abort_above: Option<f32>,
+    /// Metadata to split images into groups by, each stacked into its own master.
+    ///
+    /// Metadata is read from image headers, and file and directory names. Defaults to grouping
+    /// by filter, exposure and binning, wherever they are known; `none` stacks every image
+    /// together. With several groups, masters are written next to the output file, named after
+    /// their group.
+    #[clap(long, value_enum, use_value_delimiter = true)]
+    pub group_by: Vec<GroupByKey>,
+    /// Register every group's master to a common reference, and integrate them into this file.
+    ///
+    /// Masters are weighted by their noise. This combines nights grouped by session.
+    #[clap(long, parse(from_os_str))]
+    pub integrate: Option<PathBuf>,
+    /// Group whose master the others are registered to when integrating.
+    ///
+    /// Defaults to the group whose master has the least noise.
+    #[clap(long, requires = "integrate")]
+    pub integration_reference: Option<String>,
+    /// Record the groups, their images, pipelines and masters in this project file.
+    ///
+    /// The project can then be processed again with `project run`.
+    #[clap(long, parse(from_os_str))]
+    pub save_project: Option<PathBuf>,
+}
+
+/// Options of the `align` command.
+#[derive(Debug, Args)]
+pub struct AlignOpts {
+    /// Input images.
+    ///
+    /// Each is a directory, a file, a glob pattern such as `lights/*.tif`, or a file listing one
+    /// input per line prefixed by `@`, such as `@lights.txt`.
+    #[clap(required = true)]
+    pub input: Vec<String>,
+    /// Directory registered images are written to.
+    #[clap(parse(from_os_str))]
+    pub output: PathBuf,
+    #[clap(flatten)]
+    pub inputs: InputArgs,
+    #[clap(flatten)]
+    pub calibration: CalibrationArgs,
+    #[clap(flatten)]
+    pub alignment: AlignmentArgs,
+}
+
+/// Options of the `calibrate` command.
+#[derive(Debug, Args)]
+pub struct CalibrateOpts {
+    /// Input images.
+    ///
+    /// Each is a directory, a file, a glob pattern such as `lights/*.tif`, or a file listing one
+    /// input per line prefixed by `@`, such as `@lights.txt`.
+    #[clap(required = true)]
+    pub input: Vec<String>,
+    /// Master file.
+    #[clap(parse(from_os_str))]
+    pub output: PathBuf,
+    #[clap(flatten)]
+    pub output_args: OutputArgs,
+    #[clap(flatten)]
+    pub inputs: InputArgs,
+    /// Kind of master to build.
+    #[clap(long, value_enum)]
+    pub kind: CalibrationKind,
+    /// Bias master that flat frames are calibrated by.
+    #[clap(long, parse(from_os_str))]
+    pub bias: Option<PathBuf>,
+    /// Dark master that flat frames are calibrated by, instead of the bias master.
+    #[clap(long, parse(from_os_str))]
+    pub dark: Option<PathBuf>,
+}
+
+/// Options of the `analyze` command.
+#[derive(Debug, Args)]
+pub struct AnalyzeOpts {
+    /// Input images.
+    ///
+    /// Each is a directory, a file, a glob pattern such as `lights/*.tif`, or a file listing one
+    /// input per line prefixed by `@`, such as `@lights.txt`.
+    #[clap(required = true)]
+    pub input: Vec<String>,
+    #[clap(flatten)]
+    pub inputs: InputArgs,
+}
+
+/// Options of the `live` command.
+#[derive(Debug, Args)]
+pub struct LiveOpts {
+    /// Directory that images are captured into.
+    #[clap(parse(from_os_str))]
+    pub dir: PathBuf,
+    /// File the stack is written to after every image.
+    ///
+    /// The stack is written at the default depth of its format, e.g. 16 bits for TIFF, unless
+    /// `--output-depth` is given.
+    #[clap(short, long, parse(from_os_str), default_value = "live.tif")]
+    pub output: PathBuf,
+    #[clap(flatten)]
+    pub output_args: OutputArgs,
+    /// File the stack's statistics are written to after every image, as JSON.
+    #[clap(long, parse(from_os_str), default_value = "live.json")]
+    pub stats: PathBuf,
+    /// PNG or JPEG file a stretched preview of the stack is written to after every image.
+    #[clap(long, parse(from_os_str), default_value = "live_preview.jpg")]
+    pub preview: PathBuf,
+    /// Length of the preview's longest side, in pixels.
+    #[clap(long, default_value = "1024")]
+    pub preview_size: u32,
+    /// Image that all other images are aligned to. Defaults to the first image captured.
+    #[clap(long, parse(from_os_str))]
+    pub reference: Option<PathBuf>,
+    /// Seconds between checks for new images.
+    #[clap(long, default_value = "2")]
+    pub interval: f64,
+    #[clap(flatten)]
+    pub inputs: InputArgs,
+    #[clap(flatten)]
+    pub calibration: CalibrationArgs,
+}
+
+/// Options of the `info` command.
+#[derive(Debug, Args)]
+pub struct InfoOpts {
+    /// Image file.
+    #[clap(parse(from_os_str))]
+    pub file: PathBuf,
+}
+
+/// Options of the `convert` command.
+#[derive(Debug, Args)]
+pub struct ConvertOpts {
+    /// Image file.
+    #[clap(parse(from_os_str))]
+    pub input: PathBuf,
+    /// Converted file.
+    #[clap(parse(from_os_str))]
+    pub output: PathBuf,
+    #[clap(flatten)]
+    pub output_args: OutputArgs,
+}
+
+/// How output images are written.
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// Format of output images, replacing the output's extension.
+    ///
+    /// Defaults to the format of the output's extension.
+    #[clap(long, value_enum)]
+    pub output_format: Option<OutputFormat>,
+    /// Bit depth of output images. Defaults to the format's, or to the image's own depth.
+    #[clap(long, value_enum)]
+    pub output_depth: Option<OutputDepth>,
+}
+
+impl OutputArgs {
+    /// Get the path an output image is written to, with the extension of the output format.
+    pub fn path(&self, path: &Path) -> PathBuf {
+        match self.output_format {
+            Some(f) => path.with_extension(format::Format::from(f).extension()),
+            None => path.to_owned(),
+        }
+    }
+}
+
+/// How input images are selected.
+#[derive(Debug, Args)]
+pub struct InputArgs {
+    /// Search the subdirectories of input directories.
+    #[clap(short, long)]
+    pub recursive: bool,
+    /// Only use files with these extensions. Defaults to image formats.
+    #[clap(long, use_value_delimiter = true)]
+    pub include: Vec<String>,
+    /// Leave out files with these extensions.
+    #[clap(long, use_value_delimiter = true)]
+    pub exclude: Vec<String>,
+    /// Order of the input images.
+    #[clap(long, value_enum, default_value = "name")]
+    pub order: InputOrder,
+}
+
+impl InputArgs {
+    /// Get the input selection options.
+    pub fn opts(&self) -> input::Opts {
+        input::Opts {
+            recursive: self.recursive,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            order: match self.order {
+                InputOrder::Name => input::Order::Name,
+                InputOrder::CaptureTime => input::Order::CaptureTime,
+            },
+        }
+    }
+}
+
+/// Masters that images are calibrated by.
+#[derive(Debug, Args)]
+pub struct CalibrationArgs {
+    /// Bias master, subtracted from each image if there is no dark master.
+    #[clap(long, parse(from_os_str))]
+    pub bias: Option<PathBuf>,
+    /// Dark master, subtracted from each image.
+    #[clap(long, parse(from_os_str))]
+    pub dark: Option<PathBuf>,
+    /// Flat master, that each image is divided by.
+    #[clap(long, parse(from_os_str))]
+    pub flat: Option<PathBuf>,
+}
+
+impl CalibrationArgs {
+    /// Get the options of the calibration stage, if any masters are given.
+    pub fn stage(&self) -> Option<pipeline::calibration::Opts> {
+        if self.bias.is_none() && self.dark.is_none() && self.flat.is_none() {
+            return None;
+        }
+        Some(pipeline::calibration::Opts {
+            bias: self.bias.clone(),
+            dark: self.dark.clone(),
+            flat: self.flat.clone(),
+        })
+    }
+}
+
+/// How images are aligned.
+#[derive(Debug, Args)]
+pub struct AlignmentArgs {
+    /// How to choose the reference that all other images are aligned to.
+    #[clap(long, value_enum, default_value = "quality")]
+    pub reference_selection: ReferenceSelection,
+    /// Input image that all other images are aligned to, instead of selecting one.
+    ///
+    /// With several groups, only the group of this image is aligned to it, and the others
+    /// select their reference by quality.
+    #[clap(long, parse(from_os_str), conflicts_with = "reference_selection")]
+    pub reference: Option<PathBuf>,
+    /// Brightness above which pixels are part of stars, from 0 to 255.
+    #[clap(long)]
+    pub star_threshold: Option<f32>,
+    /// Number of iterations of the ECC algorithm that images are aligned by.
+    #[clap(long)]
+    pub ecc_iterations: Option<usize>,
+    /// Accuracy at which the ECC algorithm stops iterating.
+    #[clap(long)]
+    pub ecc_epsilon: Option<f64>,
+    /// Invalidate previously cached alignment results.
+    #[clap(long)]
+    pub clear_cache: bool,
+    /// Write aligned images to disk instead of keeping their transforms in memory.
+    #[clap(long)]
+    pub align_to_disk: bool,
+    /// Directory in which temporary working directories are created.
+    #[clap(long, parse(from_os_str))]
+    pub work_dir: Option<PathBuf>,
+    /// Write an alignment report to this file, as JSON or CSV depending on its extension.
+    #[clap(long, parse(from_os_str))]
+    pub alignment_report: Option<PathBuf>,
+}
+
+impl AlignmentArgs {
+    /// Get how the reference is chosen.
+    pub fn selection(&self) -> reference::Selection {
+        match &self.reference {
+            Some(path) => reference::Selection::File(path.clone()),
+            None => self.reference_selection.into(),
+        }
+    }
+}
+
+impl StackOpts {
+    /// Get the metadata to group images by.
+    pub fn group_by(&self) -> project::GroupBy {
+        if self.group_by.is_empty() {
+            return project::GroupBy::SETTINGS;
+        }
+        let mut by = project::GroupBy::default();
+        for key in &self.group_by {
+            match key {
+                GroupByKey::None => {}
+                GroupByKey::Filter => by.filter = true,
+                GroupByKey::Exposure => by.exposure = true,
+                GroupByKey::Binning => by.binning = true,
+                GroupByKey::Session => by.session = true,
+            }
+        }
+        by
+    }
+}
+
+/// Metadata that images can be grouped by.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum GroupByKey {
+    /// Don't group images.
+    None,
+    Filter,
+    Exposure,
+    Binning,
+    Session,
+}
+
+/// Orders of input images.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum InputOrder {
+    /// By path.
+    Name,
+    /// By capture time, then path.
+    CaptureTime,
+}
+
+/// Kinds of calibration masters.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CalibrationKind {
+    Bias,
+    Dark,
+    Flat,
+}
+
+impl From<CalibrationKind> for calibration::Kind {
+    #[inline]
+    fn from(k: CalibrationKind) -> Self {
+        match k {
+            CalibrationKind::Bias => Self::Bias,
+            CalibrationKind::Dark => Self::Dark,
+            CalibrationKind::Flat => Self::Flat,
+        }
+    }
+}
+
+/// Reference selection strategies.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ReferenceSelection {
+    /// The first image found.
+    First,
+    /// The image with the most and tightest stars.
+    Quality,
+    /// The chronologically middle image.
+    Middle,
+}
+
+impl From<ReferenceSelection> for reference::Selection {
+    #[inline]
+    fn from(s: ReferenceSelection) -> Self {
+        match s {
+            ReferenceSelection::First => Self::First,
+            ReferenceSelection::Quality => Self::Quality,
+            ReferenceSelection::Middle => Self::Middle,
+        }
+    }
+}
+
+/// Methods of combining images into a stack.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum StackingMethod {
+    /// Per-pixel average.
+    Average,
+    /// Per-pixel median, which rejects outliers but keeps every image in memory.
+    Median,
+}
+
+impl From<StackingMethod> for pipeline::stacking::Method {
+    #[inline]
+    fn from(m: StackingMethod) -> Self {
+        match m {
+            StackingMethod::Average => Self::Average,
+            StackingMethod::Median => Self::Median,
+        }
+    }
+}
+
+/// Surfaces that backgrounds can be modelled by.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum BackgroundModel {
+    /// Polynomial of degree 2, for simple gradients.
+    Polynomial,
+    /// Thin plate spline, for complex gradients.
+    Rbf,
+}
+
+impl From<BackgroundModel> for pipeline::background::Model {
+    #[inline]
+    fn from(m: BackgroundModel) -> Self {
+        match m {
+            BackgroundModel::Polynomial => Self::Polynomial,
+            BackgroundModel::Rbf => Self::Rbf,
+        }
+    }
+}
+
+/// Ways background models are removed.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum BackgroundCorrection {
+    /// Subtract the model, which removes light added by the sky.
+    Subtract,
+    /// Divide by the model, which removes vignetting.
+    Divide,
+}
+
+impl From<BackgroundCorrection> for pipeline::background::Correction {
+    #[inline]
+    fn from(c: BackgroundCorrection) -> Self {
+        match c {
+            BackgroundCorrection::Subtract => Self::Subtract,
+            BackgroundCorrection::Divide => Self::Divide,
+        }
+    }
+}
+
+/// Neutral levels that green is limited to by chromatic noise reduction.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ScnrMode {
+    /// The mean of red and blue, which removes green strongly.
+    AverageNeutral,
+    /// The brighter of red and blue, which removes green gently.
+    MaximumNeutral,
+}
+
+impl From<ScnrMode> for pipeline::scnr::Mode {
+    #[inline]
+    fn from(m: ScnrMode) -> Self {
+        match m {
+            ScnrMode::AverageNeutral => Self::AverageNeutral,
+            ScnrMode::MaximumNeutral => Self::MaximumNeutral,
+        }
+    }
+}
+
+/// Functions that stacks can be stretched by.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum StretchFunction {
+    /// Midtones transfer function, with shadows and midtones found from the background.
+    Mtf,
+    /// Inverse hyperbolic sine, which keeps stars from saturating.
+    Arcsinh,
+    /// Generalized hyperbolic stretch around the background.
+    Ghs,
+}
+
+impl From<StretchFunction> for pipeline::stretch::Function {
+    #[inline]
+    fn from(f: StretchFunction) -> Self {
+        match f {
+            StretchFunction::Mtf => Self::Mtf,
+            StretchFunction::Arcsinh => Self::Arcsinh,
+            StretchFunction::Ghs => Self::Ghs,
+        }
+    }
+}
+
+/// Formats of output images.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    /// TIFF, 16-bit by default.
+    Tif16,
+    /// TIFF, 32-bit floating point by default.
+    Tif32f,
+    /// FITS, 16-bit by default.
+    Fits,
+    /// PNG, 16-bit by default.
+    Png16,
+    /// JPEG, 8-bit.
+    Jpg,
+}
+
+impl From<OutputFormat> for format::Format {
+    #[inline]
+    fn from(f: OutputFormat) -> Self {
+        match f {
+            OutputFormat::Tif16 => Self::Tif16,
+            OutputFormat::Tif32f => Self::Tif32f,
+            OutputFormat::Fits => Self::Fits,
+            OutputFormat::Png16 => Self::Png16,
+            OutputFormat::Jpg => Self::Jpg,
+        }
+    }
+}
+
+/// Bit depths of output images.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputDepth {
+    /// 8-bit integers.
+    #[clap(name = "8")]
+    U8,
+    /// 16-bit integers.
+    #[clap(name = "16")]
+    U16,
+    /// 32-bit floating point.
+    #[clap(name = "32")]
+    F32,
+}
+
+impl From<OutputDepth> for format::Depth {
+    #[inline]
+    fn from(d: OutputDepth) -> Self {
+        match d {
+            OutputDepth::U8 => Self::U8,
+            OutputDepth::U16 => Self::U16,
+            OutputDepth::F32 => Self::F32,
+        }
+    }
 }
@@ -0,0 +1,115 @@
+//! The `align` command.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use medo::core::entry::Entry;
+use medo::core::util;
+use medo::group::Group;
+use medo::pipeline;
+use medo::project::DEFAULT_GROUP;
+use medo::reference;
+
+use crate::cli;
+use crate::report::Report;
+
+/// Align the images of a directory, and write each registered image.
+///
+/// Returns whether every image was aligned and written.
+pub fn run(opts: &cli::AlignOpts, report: &Arc<Report>) -> super::Result<bool> {
+    // Create pipeline
+    let mut config = pipeline::Pipeline {
+        stages: vec![pipeline::alignment::Opts::default().into()],
+        on_error: Default::default(),
+        checkpoints: None,
+    }
+    .to_config()
+    .unwrap();
+    super::apply_calibration_args(&mut config, &opts.calibration);
+    super::apply_alignment_args(&mut config, &opts.alignment)?;
+    let pipeline = super::create_pipeline(&config)?;
+
+    // Run
+    let entries = super::read_entries(&opts.input, &opts.inputs)?;
+    let paths = output_paths(&opts.output, &entries)?;
+    let entries = reference::select(entries, &opts.alignment.selection())
+        .map_err(|e| super::Error::new("failed to select reference", e))?;
+    let mut group = Group {
+        name: DEFAULT_GROUP.to_owned(),
+        pipeline,
+        entries,
+        pipeline_output: None,
+        failures: vec![],
+    };
+    let output = group
+        .process_with(&super::context(report))
+        .map_err(|e| super::Error::new("failed to align images", e))?
+        .clone();
+    report.reference(&group.name, &group.entries.reference.name());
+    for f in &group.failures {
+        tracing::warn!(stage = %f.stage, name = %f.name, error = %f.error);
+    }
+
+    // Write registered images
+    let failed = std::iter::once(output.reference)
+        .chain(output.entries)
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .filter(|entry| {
+            let name = entry.name();
+            let path = &paths[name.as_ref()];
+            match entry.read_image().and_then(|i| util::write_image(path, &i)) {
+                Ok(_) => {
+                    report.output(Some(DEFAULT_GROUP), path);
+                    false
+                }
+                Err(e) => {
+                    tracing::error!(%name, error = %e, "failed to write registered image");
+                    report.reject(DEFAULT_GROUP, &name, "output", &e.to_string());
+                    true
+                }
+            }
+        })
+        .count();
+    tracing::info!(output = %opts.output.display(), "wrote registered images");
+    Ok(failed == 0 && group.failures.is_empty())
+}
+
+/// Get the files that registered images are written to, by the names of their originals.
+///
+/// Images are named after their originals' stems, e.g. `light_1.tif`, or after their whole names
+/// if another image shares their stem, e.g. `light_1.fits.tif`. Images that would still be
+/// written to the same file, such as those of different directories that share a name, are
+/// refused rather than overwritten.
+fn output_paths(output: &Path, entries: &[Entry]) -> super::Result<HashMap<String, PathBuf>> {
+    let names = entries.iter().map(|e| e.name()).collect::<Vec<_>>();
+    let mut stems = HashMap::<_, usize>::new();
+    for name in &names {
+        *stems
+            .entry(Path::new(name.as_ref()).file_stem())
+            .or_default() += 1;
+    }
+
+    let mut paths = HashMap::new();
+    let mut written = HashSet::new();
+    for name in &names {
+        let stem = Path::new(name.as_ref()).file_stem();
+        let file = if stems[&stem] > 1 {
+            name.to_string()
+        } else {
+            stem.unwrap_or_default().to_string_lossy().into_owned()
+        };
+        let path = output.join(format!("{}.tif", file));
+        if !written.insert(path.clone()) {
+            return Err(super::Error::msg(format!(
+                "several images would be written to `{}`",
+                path.display()
+            )));
+        }
+        paths.insert(name.to_string(), path);
+    }
+    Ok(paths)
+}
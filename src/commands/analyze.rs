@@ -0,0 +1,81 @@
+//! The `analyze` command.
+
+use std::sync::Arc;
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+use medo::core::Result;
+use medo::project::DEFAULT_GROUP;
+use medo::stacker::{noise, star};
+
+use crate::cli;
+use crate::report::Report;
+
+/// Quality metrics of an image.
+struct Analysis {
+    metrics: star::Metrics,
+    noise: f64,
+}
+
+/// Print the quality metrics of each image of a directory.
+///
+/// Returns whether every image was analyzed.
+pub fn run(opts: &cli::AnalyzeOpts, report: &Arc<Report>) -> super::Result<bool> {
+    let entries = super::read_entries(&opts.input, &opts.inputs)?;
+
+    let results = entries
+        .par_iter()
+        .map(|e| {
+            let span = tracing::info_span!("analyze");
+            let _enter = span.enter();
+
+            let result: Result<_> = e.read_image().and_then(|image| {
+                Ok(Analysis {
+                    metrics: star::measure(&image, Default::default())?,
+                    noise: noise::estimate(&image)?,
+                })
+            });
+            tracing::debug!(name = %e.name(), "analyzed entry");
+            result
+        })
+        .collect::<Vec<_>>();
+
+    if !report.is_json() {
+        println!(
+            "{:<40} {:>6} {:>8} {:>8} {:>10}",
+            "name", "stars", "fwhm", "score", "noise"
+        );
+    }
+    let mut failed = false;
+    for (entry, result) in entries.iter().zip(results) {
+        match result {
+            Ok(a) if report.is_json() => report.measure(
+                DEFAULT_GROUP,
+                &entry.name(),
+                [
+                    ("stars", a.metrics.stars as f64),
+                    ("fwhm", a.metrics.fwhm as f64),
+                    ("score", a.metrics.score() as f64),
+                    ("noise", a.noise),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v))
+                .collect(),
+            ),
+            Ok(a) => println!(
+                "{:<40} {:>6} {:>8.3} {:>8.3} {:>10.4}",
+                entry.name(),
+                a.metrics.stars,
+                a.metrics.fwhm,
+                a.metrics.score(),
+                a.noise
+            ),
+            Err(e) => {
+                tracing::error!(name = %entry.name(), error = %e, "failed to analyze entry");
+                report.reject(DEFAULT_GROUP, &entry.name(), "analyze", &e.to_string());
+                failed = true;
+            }
+        }
+    }
+    Ok(!failed)
+}
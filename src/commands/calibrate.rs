@@ -0,0 +1,27 @@
+//! The `calibrate` command.
+
+use std::sync::Arc;
+
+use medo::calibration::{self, Masters};
+
+use crate::cli;
+use crate::report::Report;
+
+/// Build a calibration master from a directory of frames.
+pub fn run(opts: &cli::CalibrateOpts, report: &Arc<Report>) -> super::Result<bool> {
+    let masters = Masters::read(opts.bias.as_deref(), opts.dark.as_deref(), None)
+        .map_err(|e| super::Error::new("failed to read masters", e))?;
+    let frames = super::read_entries(&opts.input, &opts.inputs)?;
+    let frame_count = frames.len();
+    let output = calibration::build_master(frames, opts.kind.into(), &masters)
+        .and_then(|master| super::write_output(&opts.output, &master, &opts.output_args))
+        .map_err(|e| super::Error::new("failed to build master", e))?;
+    report.output(None, &output);
+    report.insert("frames", frame_count);
+    tracing::info!(
+        frames = frame_count,
+        output = %output.display(),
+        "wrote master"
+    );
+    Ok(true)
+}
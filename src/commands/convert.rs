@@ -0,0 +1,18 @@
+//! The `convert` command.
+
+use std::sync::Arc;
+
+use medo::core::util;
+
+use crate::cli;
+use crate::report::Report;
+
+/// Convert an image to the output format, or to the format of the output's extension.
+pub fn run(opts: &cli::ConvertOpts, report: &Arc<Report>) -> super::Result<bool> {
+    let output = util::read_image_any_depth(&opts.input)
+        .and_then(|image| super::write_output(&opts.output, &image, &opts.output_args))
+        .map_err(|e| super::Error::new("failed to convert image", e))?;
+    report.output(None, &output);
+    tracing::info!(output = %output.display(), "wrote image");
+    Ok(true)
+}
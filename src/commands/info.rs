@@ -0,0 +1,84 @@
+//! The `info` command.
+
+use std::sync::Arc;
+
+use medo::core::cv;
+use medo::core::cv::core::{MatTraitConst, MatTraitConstManual};
+use medo::core::entry::Entry;
+use medo::core::util;
+use medo::metadata::{self, Metadata};
+
+use crate::cli;
+use crate::report::Report;
+
+/// Describe the depth of an image's values.
+fn depth_name(depth: i32) -> &'static str {
+    match depth {
+        cv::core::CV_8U => "8-bit unsigned",
+        cv::core::CV_8S => "8-bit signed",
+        cv::core::CV_16U => "16-bit unsigned",
+        cv::core::CV_16S => "16-bit signed",
+        cv::core::CV_32S => "32-bit signed",
+        cv::core::CV_32F => "32-bit floating point",
+        cv::core::CV_64F => "64-bit floating point",
+        _ => "unknown",
+    }
+}
+
+/// Print the dimensions, depth and metadata of an image.
+pub fn run(opts: &cli::InfoOpts, report: &Arc<Report>) -> super::Result<bool> {
+    let (entry, image) = Entry::new_path_owned(opts.file.clone())
+        .and_then(|e| Ok((e, util::read_image_any_depth(&opts.file)?)))
+        .map_err(|e| super::Error::new("failed to read image", e))?;
+    let size = image
+        .size()
+        .map_err(|e| super::Error::new("failed to read image", e.into()))?;
+    let metadata = Metadata::read(&entry);
+
+    if report.is_json() {
+        report.insert(
+            "image",
+            serde_json::json!({
+                "file": opts.file,
+                "width": size.width,
+                "height": size.height,
+                "channels": image.channels(),
+                "depth": depth_name(image.depth()),
+                "filter": metadata.filter,
+                "exposure_secs": metadata.exposure.map(|e| e.as_secs_f64()),
+                "binning": metadata.binning,
+                "session": metadata.session,
+                "capture_time": metadata.capture_time.map(metadata::format_utc),
+            }),
+        );
+        return Ok(true);
+    }
+
+    println!("file:       {}", opts.file.display());
+    println!("dimensions: {}x{}", size.width, size.height);
+    println!("channels:   {}", image.channels());
+    println!("depth:      {}", depth_name(image.depth()));
+
+    let unknown = || "unknown".to_owned();
+    println!("filter:     {}", metadata.filter.unwrap_or_else(unknown));
+    println!(
+        "exposure:   {}",
+        metadata
+            .exposure
+            .map_or_else(unknown, |e| format!("{}s", e.as_secs_f64()))
+    );
+    println!(
+        "binning:    {}",
+        metadata
+            .binning
+            .map_or_else(unknown, |b| format!("{0}x{0}", b))
+    );
+    println!("session:    {}", metadata.session.unwrap_or_else(unknown));
+    println!(
+        "captured:   {}",
+        metadata
+            .capture_time
+            .map_or_else(unknown, metadata::format_utc)
+    );
+    Ok(true)
+}
@@ -0,0 +1,101 @@
+//! The `live` command.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use medo::core::{format, Result};
+use medo::project::DEFAULT_GROUP;
+use medo::{live, preview};
+
+use crate::cli;
+use crate::report::Report;
+
+/// Write a file through a hidden file next to it, so that it is never read half written.
+fn write_atomic<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<PathBuf>,
+{
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let written = write(&path.with_file_name(format!(".{}", name)))?;
+    std::fs::rename(written, path)?;
+    Ok(())
+}
+
+/// Stack the images of a directory as they are captured, until interrupted.
+pub fn run(opts: &cli::LiveOpts, report: &Arc<Report>) -> super::Result<bool> {
+    if !(opts.interval.is_finite() && opts.interval >= 0.0) {
+        return Err(super::Error::msg(format!(
+            "interval must be a non-negative number, not {}",
+            opts.interval
+        )));
+    }
+    if opts.preview_size == 0 {
+        return Err(super::Error::msg("preview size must be positive"));
+    }
+    let output = opts.output_args.path(&opts.output);
+    // The stack is floating point, which few viewers open, so it is written at the depth of its
+    // format unless one is given
+    let output_format = opts
+        .output_args
+        .output_format
+        .map(format::Format::from)
+        .or_else(|| format::Format::from_path(&output));
+    let output_depth = opts.output_args.output_depth.map(format::Depth::from);
+    let live_opts = live::Opts {
+        reference: opts.reference.clone(),
+        input: opts.inputs.opts(),
+        calibration: opts.calibration.stage().unwrap_or_default(),
+        interval: Duration::from_secs_f64(opts.interval),
+        // The stack may be written into the directory it is made of
+        ignore: vec![output.clone(), opts.stats.clone(), opts.preview.clone()],
+        ..Default::default()
+    };
+
+    let (mut stacked, mut rejected) = (0, 0);
+    let result = live::run(
+        &opts.dir,
+        &live_opts,
+        &super::context(report),
+        |image, stats| {
+            write_atomic(&output, |p| {
+                match output_format {
+                    Some(f) => format::write_image(p, image, f, output_depth)?,
+                    None => format::write_image_as(p, image, None, output_depth)?,
+                }
+                Ok(p.to_owned())
+            })?;
+            write_atomic(&opts.stats, |p| {
+                std::fs::write(p, serde_json::to_string_pretty(stats).unwrap())?;
+                Ok(p.to_owned())
+            })?;
+            if let Err(e) = preview::write(&opts.preview, image, opts.preview_size) {
+                tracing::warn!(error = %e, "failed to write preview");
+            }
+
+            // Record the frame that was just stacked or rejected
+            if stats.stacked > stacked {
+                if let Some(last) = &stats.last {
+                    report.measure(DEFAULT_GROUP, &last.name, last.metrics.clone());
+                }
+            }
+            if let Some(r) = stats.rejected.get(rejected) {
+                report.reject(DEFAULT_GROUP, &r.name, "live", &r.reason);
+            }
+            stacked = stats.stacked;
+            rejected = stats.rejected.len();
+            Ok(())
+        },
+    );
+    let stats = result.map_err(|e| super::Error::new("failed to stack live", e))?;
+
+    if !stats.reference.is_empty() {
+        report.reference(DEFAULT_GROUP, &stats.reference);
+        report.output(None, &output);
+        report.output(None, &opts.stats);
+        report.output(None, &opts.preview);
+    }
+    report.insert("live", &stats);
+    tracing::info!(output = %output.display(), "wrote stack");
+    Ok(true)
+}
@@ -0,0 +1,232 @@
+//! Implementations of the command line's commands.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use medo::core::cv::core::Mat;
+use medo::core::entry::Entry;
+use medo::core::format;
+use medo::input;
+use medo::pipeline::{self, StageConfig};
+
+use crate::cli::{AlignmentArgs, CalibrationArgs, InputArgs, OutputArgs};
+use crate::progress;
+use crate::report::Report;
+
+pub mod align;
+pub mod analyze;
+pub mod calibrate;
+pub mod convert;
+pub mod info;
+pub mod live;
+pub mod project;
+pub mod stack;
+
+/// Why a command stopped before it finished.
+#[derive(Debug)]
+pub struct Error {
+    message: String,
+    /// Whether the command was interrupted.
+    cancelled: bool,
+}
+
+impl Error {
+    /// Describe a failure that has no underlying error.
+    fn msg<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+            cancelled: false,
+        }
+    }
+
+    /// Describe a failure by what failed and the error that caused it.
+    ///
+    /// Errors caused by work being stopped are reported as interruptions.
+    fn new(context: &str, e: medo::core::Error) -> Self {
+        if e.is_cancelled() {
+            return Self::cancelled();
+        }
+        Self::msg(format!("{}: {}", context, e))
+    }
+
+    /// Describe the command being interrupted.
+    fn cancelled() -> Self {
+        Self {
+            message: "cancelled".to_owned(),
+            cancelled: true,
+        }
+    }
+
+    /// Get the code the process exits with, as shells do for interrupts.
+    pub fn exit_code(&self) -> i32 {
+        if self.cancelled {
+            130
+        } else {
+            1
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The result of a command: whether it did everything it was asked to, or why it stopped.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Create the context that work is run in.
+///
+/// Progress is rendered as progress bars and recorded in the report, and work stops at the next
+/// entry on interrupt, removing temporary files.
+fn context(report: &Arc<Report>) -> pipeline::Context {
+    let report = report.clone();
+    let progress = progress::Progress::new();
+    let cancellation = pipeline::CancellationToken::new();
+    {
+        let cancellation = cancellation.clone();
+        ctrlc::set_handler(move || {
+            tracing::warn!("interrupted, cancelling");
+            cancellation.cancel();
+        })
+        .unwrap();
+    }
+    pipeline::Context::new()
+        .with_listener(move |e| {
+            progress.handle(e);
+            report.handle(e);
+        })
+        .with_cancellation(cancellation)
+}
+
+/// Select input images, failing if there are none.
+fn read_entries(inputs: &[String], args: &InputArgs) -> Result<Vec<Entry>> {
+    let entries = input::select(inputs, &args.opts())
+        .map_err(|e| Error::new("failed to select inputs", e))?;
+    if entries.is_empty() {
+        return Err(Error::msg("no input images"));
+    }
+    Ok(entries)
+}
+
+/// Describe a stage by its name and options.
+fn stage_config<S: Serialize>(stage: &str, opts: &S) -> StageConfig {
+    let options = match pipeline::serialize_options(opts).unwrap() {
+        serde_json::Value::Object(o) => o,
+        _ => unreachable!(),
+    };
+    StageConfig {
+        stage: stage.to_owned(),
+        on_error: None,
+        options,
+    }
+}
+
+/// Calibrate images before any other stage, if any masters are given.
+fn apply_calibration_args(config: &mut pipeline::Config, args: &CalibrationArgs) {
+    if let Some(opts) = args.stage() {
+        config.stages.insert(0, stage_config("calibration", &opts));
+    }
+}
+
+/// Process stacks by a stage, replacing any stage of the same name in the configuration.
+///
+/// Stages are added at the end of the pipeline, except before stretching, which belongs last.
+fn apply_post_stage<S: Serialize>(config: &mut pipeline::Config, stage: &str, opts: &S) {
+    config.stages.retain(|s| s.stage != stage);
+    let index = match stage {
+        "stretch" => None,
+        _ => config.stages.iter().position(|s| s.stage == "stretch"),
+    };
+    let index = index.unwrap_or(config.stages.len());
+    config.stages.insert(index, stage_config(stage, opts));
+}
+
+/// Override the options of alignment stages, clearing their caches if asked to.
+fn apply_alignment_args(config: &mut pipeline::Config, args: &AlignmentArgs) -> Result<()> {
+    for stage in config.stages.iter_mut().filter(|s| s.stage == "alignment") {
+        if args.align_to_disk {
+            stage.options.insert("output".to_owned(), "disk".into());
+        }
+        if let Some(work_dir) = &args.work_dir {
+            stage
+                .options
+                .insert("work_dir".to_owned(), work_dir.display().to_string().into());
+        }
+        if let Some(report) = &args.alignment_report {
+            stage
+                .options
+                .insert("report".to_owned(), report.display().to_string().into());
+        }
+        if let Some(threshold) = args.star_threshold {
+            set_option(
+                stage,
+                &["star_detection", "threshold_brightness"],
+                threshold.into(),
+            );
+        }
+        if let Some(iterations) = args.ecc_iterations {
+            set_option(stage, &["homography", "iterations"], iterations.into());
+        }
+        if let Some(epsilon) = args.ecc_epsilon {
+            set_option(stage, &["homography", "epsilon"], epsilon.into());
+        }
+        if args.clear_cache {
+            let alignment: pipeline::alignment::Opts =
+                pipeline::deserialize_options(serde_json::Value::Object(stage.options.clone()))
+                    .map_err(|e| Error::new("invalid alignment options", e))?;
+            if let Some(cache) = alignment.open_cache() {
+                cache
+                    .clear()
+                    .map_err(|e| Error::new("failed to clear alignment cache", e))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set an option of a stage, creating the tables it is nested in.
+///
+/// # Parameters
+/// - `stage`: The stage whose option is set.
+/// - `path`: The keys of the tables the option is nested in, followed by its own key.
+/// - `value`: The option's value.
+fn set_option(stage: &mut StageConfig, path: &[&str], value: serde_json::Value) {
+    let (key, tables) = path.split_last().expect("option path is empty");
+    let mut options = &mut stage.options;
+    for table in tables {
+        let table = options
+            .entry(table.to_string())
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if !table.is_object() {
+            *table = serde_json::Value::Object(Default::default());
+        }
+        options = table.as_object_mut().unwrap();
+    }
+    options.insert(key.to_string(), value);
+}
+
+/// Write an output image in the format and depth given on the command line.
+///
+/// Without either, the image is written as it is, in the format of the output's extension.
+/// Returns the path written to, whose extension is the format's.
+fn write_output(path: &Path, image: &Mat, args: &OutputArgs) -> medo::core::Result<PathBuf> {
+    let path = args.path(path);
+    format::write_image_as(
+        &path,
+        image,
+        args.output_format.map(format::Format::from),
+        args.output_depth.map(format::Depth::from),
+    )?;
+    Ok(path)
+}
+
+/// Create a pipeline from its description.
+fn create_pipeline(config: &pipeline::Config) -> Result<pipeline::Pipeline> {
+    pipeline::Pipeline::from_config(config, &pipeline::Registry::default())
+        .map_err(|e| Error::new("invalid pipeline configuration", e))
+}
@@ -0,0 +1,52 @@
+//! The `project` commands.
+
+use std::sync::Arc;
+
+use medo::pipeline;
+use medo::project;
+
+use crate::cli;
+use crate::report::Report;
+
+/// Process the groups of a project file that changed.
+///
+/// Returns whether every group was processed, or was up to date.
+pub fn run(opts: &cli::ProjectRunOpts, report: &Arc<Report>) -> super::Result<bool> {
+    let mut file = project::ProjectFile::read(&opts.file)
+        .map_err(|e| super::Error::new("invalid project file", e))?;
+    let result = file.run(
+        &pipeline::Registry::default(),
+        opts.force,
+        &super::context(report),
+    );
+    // Groups that finished are recorded even if the run was cancelled
+    if let Err(e) = file.write(&opts.file) {
+        tracing::error!(error = %e, "failed to save project");
+    }
+    let summary = result.map_err(|_| super::Error::cancelled())?;
+    for group in &file.groups {
+        if summary.processed.contains(&group.name) {
+            report.output(Some(&group.name), &group.output);
+        }
+        // Frames are named after their files
+        if let Some(reference) = group.reference.as_ref().and_then(|r| r.file_name()) {
+            report.reference(&group.name, &reference.to_string_lossy());
+        }
+    }
+    report.insert("skipped_groups", &summary.skipped);
+    report.insert(
+        "failed_groups",
+        summary
+            .failed
+            .iter()
+            .map(|(group, e)| serde_json::json!({ "group": group, "reason": e.to_string() }))
+            .collect::<Vec<_>>(),
+    );
+    tracing::info!(
+        processed = summary.processed.len(),
+        skipped = summary.skipped.len(),
+        failed = summary.failed.len(),
+        "finished project"
+    );
+    Ok(summary.failed.is_empty())
+}
@@ -0,0 +1,234 @@
+//! The `stack` command.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use medo::integration;
+use medo::pipeline;
+use medo::project;
+
+use crate::report::Report;
+use crate::{cli, config};
+
+/// Stack the images of a directory.
+///
+/// Returns whether every group was stacked and integrated without failures.
+pub fn run(opts: &cli::StackOpts, report: &Arc<Report>) -> super::Result<bool> {
+    // Create pipeline
+    let mut config = match &opts.config {
+        Some(path) => config::read_pipeline(path),
+        None => pipeline::Pipeline::default().to_config(),
+    }
+    .map_err(|e| super::Error::new("invalid pipeline configuration", e))?;
+    super::apply_calibration_args(&mut config, &opts.calibration);
+    super::apply_alignment_args(&mut config, &opts.alignment)?;
+    for stage in config.stages.iter_mut() {
+        match stage.stage.as_str() {
+            "sharpen" => {
+                if let Some(amount) = opts.sharpen_amount {
+                    super::set_option(stage, &["amount"], amount.into());
+                }
+            }
+            "stacking" => {
+                if let Some(method) = opts.stacking_method {
+                    let method = pipeline::stacking::Method::from(method);
+                    super::set_option(stage, &["method"], serde_json::to_value(method).unwrap());
+                }
+                if let Some(path) = &opts.preview {
+                    super::set_option(stage, &["preview"], path.to_string_lossy().into());
+                    super::set_option(stage, &["preview_size"], opts.preview_size.into());
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some(model) = opts.background {
+        let background = pipeline::background::Opts {
+            model: model.into(),
+            correction: opts.background_correction.into(),
+            model_dir: opts.background_model_dir.clone(),
+            ..Default::default()
+        };
+        super::apply_post_stage(&mut config, "background", &background);
+    }
+    if opts.color_calibration {
+        let color = pipeline::color::Opts {
+            background_region: opts.neutral_region,
+            white_reference: match opts.white_region {
+                Some(_) => pipeline::color::WhiteReference::Region,
+                None => pipeline::color::WhiteReference::Stars,
+            },
+            white_region: opts.white_region,
+            ..Default::default()
+        };
+        super::apply_post_stage(&mut config, "color_calibration", &color);
+    }
+    if let Some(mode) = opts.scnr {
+        let scnr = pipeline::scnr::Opts {
+            mode: mode.into(),
+            amount: opts.scnr_amount,
+        };
+        super::apply_post_stage(&mut config, "scnr", &scnr);
+    }
+    if let Some(function) = opts.stretch {
+        let stretch = pipeline::stretch::Opts {
+            function: function.into(),
+            linked: !opts.stretch_unlinked,
+            ..Default::default()
+        };
+        super::apply_post_stage(&mut config, "stretch", &stretch);
+    }
+    if opts.abort_on_error {
+        config.on_error = pipeline::ErrorPolicy::Abort;
+    }
+    if let Some(percent) = opts.abort_above {
+        config.on_error = pipeline::ErrorPolicy::AbortAbove(percent);
+    }
+    let mut pipeline = super::create_pipeline(&config)?;
+    pipeline.checkpoints = opts
+        .checkpoint_dir
+        .as_ref()
+        .map(pipeline::checkpoint::Checkpoints::new);
+
+    // Run
+    let entries = super::read_entries(&opts.input, &opts.inputs)?;
+    // Create groups
+    let mut project = project::Project::group(
+        entries,
+        opts.group_by(),
+        &pipeline,
+        &opts.alignment.selection(),
+    )
+    .map_err(|e| super::Error::new("failed to create groups", e))?;
+    let summary = project
+        .process_with(&super::context(report))
+        .map_err(|_| super::Error::cancelled())?;
+    report.insert(
+        "failed_groups",
+        summary
+            .failed
+            .iter()
+            .map(|(group, e)| serde_json::json!({ "group": group, "reason": e.to_string() }))
+            .collect::<Vec<_>>(),
+    );
+
+    for group in &project.groups {
+        report.reference(&group.name, &group.entries.reference.name());
+        // Summarize failures
+        if !group.failures.is_empty() {
+            tracing::warn!(
+                group = %group.name,
+                "{} of {} entries failed",
+                group.failures.len(),
+                group.entries.entries.len()
+            );
+            for f in &group.failures {
+                tracing::warn!(stage = %f.stage, name = %f.name, error = %f.error);
+            }
+        }
+
+        // Write result
+        if let Some(out) = &group.pipeline_output {
+            let path = output_path(&opts.output, &group.name, project.groups.len());
+            let path = out
+                .reference
+                .read_image()
+                .and_then(|image| super::write_output(&path, image.as_ref(), &opts.output_args))
+                .map_err(|e| {
+                    super::Error::new(&format!("failed to write master of `{}`", group.name), e)
+                })?;
+            report.output(Some(&group.name), &path);
+            tracing::info!(group = %group.name, output = %path.display(), "wrote master");
+        }
+    }
+
+    // Combine masters
+    let mut integration_failed = false;
+    if let Some(path) = &opts.integrate {
+        let integration_opts = integration::Opts {
+            reference: opts.integration_reference.clone(),
+            ..Default::default()
+        };
+        match project.integrate(&integration_opts) {
+            Ok(integration) => {
+                integration_failed = !integration.failed.is_empty();
+                let path = integration
+                    .image
+                    .read_image()
+                    .and_then(|image| super::write_output(path, image.as_ref(), &opts.output_args))
+                    .map_err(|e| super::Error::new("failed to write integration", e))?;
+                report.output(None, &path);
+                report.insert(
+                    "integration",
+                    serde_json::json!({
+                        "output": path,
+                        "reference": integration.reference,
+                        "masters": integration
+                            .sessions
+                            .iter()
+                            .map(|s| serde_json::json!({
+                                "group": s.group,
+                                "noise": s.noise,
+                                "weight": s.weight,
+                            }))
+                            .collect::<Vec<_>>(),
+                        "failed": integration
+                            .failed
+                            .iter()
+                            .map(|(group, e)| serde_json::json!({
+                                "group": group,
+                                "reason": e.to_string(),
+                            }))
+                            .collect::<Vec<_>>(),
+                    }),
+                );
+                tracing::info!(
+                    reference = %integration.reference,
+                    masters = integration.sessions.len(),
+                    output = %path.display(),
+                    "wrote integration"
+                );
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "failed to integrate masters");
+                integration_failed = true;
+            }
+        }
+    }
+
+    // Record the project
+    if let Some(path) = &opts.save_project {
+        let groups = project.groups.len();
+        project::ProjectFile::from_project(
+            &project,
+            |name| {
+                opts.output_args
+                    .path(&output_path(&opts.output, name, groups))
+            },
+            opts.output_args.output_format.map(Into::into),
+            opts.output_args.output_depth.map(Into::into),
+        )
+        .and_then(|file| file.write(path))
+        .map_err(|e| super::Error::new("failed to save project", e))?;
+        report.output(None, path);
+        tracing::info!(project = %path.display(), "saved project");
+    }
+
+    Ok(summary.failed.is_empty() && !integration_failed)
+}
+
+/// Get the path a group's master is written to.
+///
+/// With several groups, masters are named after their group, e.g. `master_Ha.tif`.
+fn output_path(output: &Path, group: &str, groups: usize) -> PathBuf {
+    if groups <= 1 {
+        return output.to_owned();
+    }
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{}_{}", stem, group);
+    if let Some(ext) = output.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    output.with_file_name(name)
+}
@@ -0,0 +1,30 @@
+//! Pipeline configuration files.
+
+use std::fmt::Display;
+use std::path::Path;
+
+use medo::core::{Error, Result};
+use medo::pipeline::Config;
+
+/// Describe an error in a configuration file.
+fn invalid(path: &Path, e: impl Display) -> Error {
+    Error::Other(format!("{}: {}", path.display(), e))
+}
+
+/// Read a pipeline description from a TOML or YAML file, depending on its extension.
+///
+/// Errors point to the offending key.
+pub fn read_pipeline<P: AsRef<Path>>(path: P) -> Result<Config> {
+    let path = path.as_ref();
+    let contents = std::fs::read_to_string(path)?;
+
+    let config = match path.extension().and_then(|e| e.to_str()) {
+        Some("yaml" | "yml") => {
+            serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(&contents))
+                .map_err(|e| invalid(path, e))?
+        }
+        _ => serde_path_to_error::deserialize(&mut toml::Deserializer::new(&contents))
+            .map_err(|e| invalid(path, e))?,
+    };
+    Ok(config)
+}
@@ -1,12 +1,12 @@
-use std::borrow::Cow;
+use std::sync::Arc;
 
 use clap::Parser;
-use medo::core::entry::{Entries, Entry};
-use medo::core::util;
-use medo::group;
-use medo::pipeline;
 
 mod cli;
+mod commands;
+mod config;
+mod progress;
+mod report;
 
 fn init_log() {
     #[cfg(debug_assertions)]
@@ -21,6 +21,8 @@ fn init_log() {
     tracing_subscriber::fmt()
         .event_format(format)
         .with_max_level(level)
+        // Standard output is left for the command's output
+        .with_writer(std::io::stderr)
         .init();
 }
 
@@ -36,29 +38,31 @@ fn main() {
         .build_global()
         .unwrap();
 
-    // Run
-    let mut entries = std::fs::read_dir(&opts.input).unwrap().filter_map(|d| {
-        d.ok()
-            .and_then(|d| Entry::new_path_owned(d.path()).ok().map(Cow::Owned))
-    });
-    let reference = entries.next().unwrap();
-    let entries = Entries { reference, entries }.into_owned();
-    // Create default group
-    let pipeline = pipeline::Pipeline {
-        stages: vec![
-            pipeline::Stage::Alignment(Default::default()),
-            pipeline::Stage::Sharpen(Default::default()),
-            pipeline::Stage::Stacking(Default::default()),
-        ],
+    let report = Arc::new(report::Report::new(opts.json, opts.json_events));
+    let (command, result) = match &opts.command {
+        cli::Command::Stack(opts) => ("stack", commands::stack::run(opts, &report)),
+        cli::Command::Align(opts) => ("align", commands::align::run(opts, &report)),
+        cli::Command::Calibrate(opts) => ("calibrate", commands::calibrate::run(opts, &report)),
+        cli::Command::Analyze(opts) => ("analyze", commands::analyze::run(opts, &report)),
+        cli::Command::Info(opts) => ("info", commands::info::run(opts, &report)),
+        cli::Command::Live(opts) => ("live", commands::live::run(opts, &report)),
+        cli::Command::Convert(opts) => ("convert", commands::convert::run(opts, &report)),
+        cli::Command::Project(cli::ProjectCommand::Run(opts)) => {
+            ("project run", commands::project::run(opts, &report))
+        }
     };
-    let mut group = group::Group {
-        name: "default".to_owned(),
-        pipeline,
-        entries,
-        pipeline_output: None,
-    };
-    let out = group.process().unwrap();
-
-    // Write result
-    util::write_image(&opts.output, out.reference.read_image().unwrap().as_ref()).unwrap();
+    // The report is written however the command ended
+    if let Err(e) = &result {
+        tracing::error!("{}", e);
+    }
+    report.finish(
+        command,
+        matches!(result, Ok(true)),
+        result.as_ref().err().map(|e| e.to_string()).as_deref(),
+    );
+    match result {
+        Ok(true) => (),
+        Ok(false) => std::process::exit(1),
+        Err(e) => std::process::exit(e.exit_code()),
+    }
 }
@@ -0,0 +1,74 @@
+//! Progress bars for running pipelines.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use medo::pipeline::Event;
+
+/// Progress of the group being processed.
+#[derive(Default)]
+struct State {
+    /// Name of the group.
+    group: String,
+    /// Number of entries processed by each stage.
+    total: u64,
+    /// Progress bars of stages, by position in the pipeline.
+    bars: HashMap<usize, ProgressBar>,
+}
+
+/// Renders pipeline events as one progress bar per stage.
+#[derive(Default)]
+pub struct Progress {
+    multi: MultiProgress,
+    state: Mutex<State>,
+}
+
+impl Progress {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update progress bars with an event.
+    pub fn handle(&self, event: &Event) {
+        let mut state = self.state.lock().unwrap();
+        match event {
+            Event::GroupStarted { name, entries } => {
+                state.group = name.clone();
+                state.total = *entries as u64;
+                state.bars.clear();
+            }
+            Event::StageStarted { index, stage } => {
+                let bar = self.multi.add(ProgressBar::new(state.total));
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "{prefix:>20} [{bar:40}] {pos}/{len} {elapsed_precise} {msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("=> "),
+                );
+                bar.set_prefix(format!("{}/{}", state.group, stage));
+                state.bars.insert(*index, bar);
+            }
+            Event::EntryProcessed {
+                index, name, error, ..
+            } => {
+                if let Some(bar) = state.bars.get(index) {
+                    if error.is_some() {
+                        bar.set_message(format!("failed: {}", name));
+                    }
+                    bar.inc(1);
+                }
+            }
+            Event::StageFinished { index, .. } => {
+                if let Some(bar) = state.bars.get(index) {
+                    bar.finish();
+                }
+            }
+            Event::EntryMeasured { .. }
+            | Event::PipelineFinished { .. }
+            | Event::GroupFinished { .. } => {}
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Machine-readable reports of a command's run.
+//!
+//! With `--json`, a single JSON document describing what a command did is printed to standard
+//! output once it finishes: the files it wrote, and the frames of each group that were accepted
+//! or rejected, along with their metrics and timings. With `--json-events`, pipeline events are
+//! also printed as they happen, one JSON object per line.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use medo::pipeline::Event;
+use medo::project::DEFAULT_GROUP;
+
+/// A frame of a group, and what happened to it.
+#[derive(Debug, Default, Serialize)]
+struct Frame {
+    name: String,
+    /// Whether every stage processed the frame.
+    accepted: bool,
+    /// Stage that rejected the frame.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejected_by: Option<String>,
+    /// Why the frame was rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Measurements of the frame, by name.
+    metrics: BTreeMap<String, f64>,
+    /// Time taken by each stage to process the frame, in seconds.
+    timings: BTreeMap<String, f64>,
+}
+
+/// A frame that was rejected.
+#[derive(Debug, Serialize)]
+struct Rejection<'a> {
+    name: &'a str,
+    stage: &'a str,
+    reason: &'a str,
+}
+
+/// A group, and the frames it was made of.
+#[derive(Debug, Default)]
+struct GroupReport {
+    name: String,
+    reference: Option<String>,
+    outputs: Vec<PathBuf>,
+    time: Option<Duration>,
+    /// Frames, in the order they were first processed.
+    frames: Vec<Frame>,
+}
+
+impl GroupReport {
+    /// Get a frame by name, adding it if it is new.
+    fn frame_mut(&mut self, name: &str) -> &mut Frame {
+        match self.frames.iter().position(|f| f.name == name) {
+            Some(i) => &mut self.frames[i],
+            None => {
+                self.frames.push(Frame {
+                    name: name.to_owned(),
+                    accepted: true,
+                    ..Default::default()
+                });
+                self.frames.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let accepted = self
+            .frames
+            .iter()
+            .filter(|f| f.accepted)
+            .map(|f| f.name.as_str())
+            .collect::<Vec<_>>();
+        let rejected = self
+            .frames
+            .iter()
+            .filter_map(|f| {
+                Some(Rejection {
+                    name: &f.name,
+                    stage: f.rejected_by.as_deref()?,
+                    reason: f.reason.as_deref()?,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "name": self.name,
+            "reference": self.reference,
+            "outputs": self.outputs,
+            "time_secs": self.time.map(|t| t.as_secs_f64()),
+            "accepted": accepted,
+            "rejected": rejected,
+            "frames": self.frames,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    outputs: Vec<PathBuf>,
+    groups: Vec<GroupReport>,
+    /// Name of the group being processed.
+    current: Option<String>,
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+impl State {
+    /// Get a group by name, adding it if it is new.
+    fn group_mut(&mut self, name: &str) -> &mut GroupReport {
+        match self.groups.iter().position(|g| g.name == name) {
+            Some(i) => &mut self.groups[i],
+            None => {
+                self.groups.push(GroupReport {
+                    name: name.to_owned(),
+                    ..Default::default()
+                });
+                self.groups.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Get the group being processed.
+    fn current_mut(&mut self) -> &mut GroupReport {
+        let name = self
+            .current
+            .clone()
+            .unwrap_or_else(|| DEFAULT_GROUP.to_owned());
+        self.group_mut(&name)
+    }
+}
+
+/// Collects what a command did, and prints it as JSON.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Whether the report is printed.
+    json: bool,
+    /// Whether events are printed as they happen.
+    events: bool,
+    state: Mutex<State>,
+}
+
+impl Report {
+    #[inline]
+    pub fn new(json: bool, events: bool) -> Self {
+        Self {
+            json,
+            events,
+            ..Default::default()
+        }
+    }
+
+    /// Check if the report is printed, in which case nothing else should be printed to standard
+    /// output.
+    #[inline]
+    pub fn is_json(&self) -> bool {
+        self.json
+    }
+
+    /// Record a pipeline event, printing it if events are printed.
+    pub fn handle(&self, event: &Event) {
+        if self.events {
+            // Lines are written whole, so that events of different threads don't interleave
+            let line = serde_json::to_string(event).unwrap();
+            let _ = writeln!(std::io::stdout().lock(), "{}", line);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        match event {
+            Event::GroupStarted { name, .. } => {
+                state.current = Some(name.clone());
+                state.group_mut(name);
+            }
+            Event::EntryProcessed {
+                stage,
+                name,
+                time,
+                error,
+                ..
+            } => {
+                let frame = state.current_mut().frame_mut(name);
+                *frame.timings.entry(stage.clone()).or_default() += time.as_secs_f64();
+                if let (Some(error), true) = (error, frame.accepted) {
+                    frame.accepted = false;
+                    frame.rejected_by = Some(stage.clone());
+                    frame.reason = Some(error.clone());
+                }
+            }
+            Event::EntryMeasured { name, metrics, .. } => {
+                let frame = state.current_mut().frame_mut(name);
+                frame
+                    .metrics
+                    .extend(metrics.iter().map(|(k, v)| (k.clone(), *v)));
+            }
+            Event::GroupFinished { name, time, .. } => {
+                state.group_mut(name).time = Some(*time);
+                state.current = None;
+            }
+            Event::StageStarted { .. }
+            | Event::StageFinished { .. }
+            | Event::PipelineFinished { .. } => {}
+        }
+    }
+
+    /// Record the reference of a group.
+    pub fn reference(&self, group: &str, name: &str) {
+        self.state.lock().unwrap().group_mut(group).reference = Some(name.to_owned());
+    }
+
+    /// Record a file that was written, by a group if it belongs to one.
+    pub fn output(&self, group: Option<&str>, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+        state.outputs.push(path.to_owned());
+        if let Some(group) = group {
+            state.group_mut(group).outputs.push(path.to_owned());
+        }
+    }
+
+    /// Record measurements of a frame of a group.
+    pub fn measure(&self, group: &str, name: &str, metrics: BTreeMap<String, f64>) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .group_mut(group)
+            .frame_mut(name)
+            .metrics
+            .extend(metrics);
+    }
+
+    /// Record that a frame of a group was rejected, outside of any pipeline.
+    pub fn reject(&self, group: &str, name: &str, stage: &str, reason: &str) {
+        let mut state = self.state.lock().unwrap();
+        let frame = state.group_mut(group).frame_mut(name);
+        if frame.accepted {
+            frame.accepted = false;
+            frame.rejected_by = Some(stage.to_owned());
+            frame.reason = Some(reason.to_owned());
+        }
+    }
+
+    /// Record a value that is specific to the command.
+    pub fn insert<V: Serialize>(&self, key: &str, value: V) {
+        let value = serde_json::to_value(value).unwrap();
+        self.state
+            .lock()
+            .unwrap()
+            .extra
+            .insert(key.to_owned(), value);
+    }
+
+    /// Print the report, if it is printed.
+    ///
+    /// The report is printed on a single line if events are printed, so that every line of the
+    /// output is a JSON object.
+    ///
+    /// # Parameters
+    /// - `command`: Name of the command that was run.
+    /// - `success`: Whether the command did everything it was asked to.
+    /// - `error`: Why the command stopped before it finished, if it did.
+    pub fn finish(&self, command: &str, success: bool, error: Option<&str>) {
+        if !self.json {
+            return;
+        }
+        let state = self.state.lock().unwrap();
+        let mut document = serde_json::json!({
+            "command": command,
+            "success": success && error.is_none(),
+            "outputs": state.outputs,
+            "groups": state.groups.iter().map(GroupReport::to_json).collect::<Vec<_>>(),
+        });
+        for (key, value) in &state.extra {
+            document[key] = value.clone();
+        }
+        if let Some(error) = error {
+            document["error"] = error.into();
+        }
+        let document = if self.events {
+            serde_json::to_string(&document)
+        } else {
+            serde_json::to_string_pretty(&document)
+        };
+        let _ = writeln!(std::io::stdout().lock(), "{}", document.unwrap());
+    }
+}